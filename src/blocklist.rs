@@ -0,0 +1,213 @@
+use crate::targets::IpPrefix;
+use std::error::Error;
+use std::fs::read_to_string;
+use std::net::IpAddr;
+
+/// Special purpose IPv4 ranges from RFC 6890 and the IANA registry which are never scanned
+/// unless allowed explicitly or the default blocklist is disabled
+pub const DEFAULT_BLOCKLIST_V4: &[&str] = &[
+    "0.0.0.0/8",          // this network
+    "10.0.0.0/8",         // private use
+    "100.64.0.0/10",      // shared address space
+    "127.0.0.0/8",        // loopback
+    "169.254.0.0/16",     // link local
+    "172.16.0.0/12",      // private use
+    "192.0.0.0/24",       // IETF protocol assignments
+    "192.0.2.0/24",       // documentation
+    "192.88.99.0/24",     // 6to4 relay anycast
+    "192.168.0.0/16",     // private use
+    "198.18.0.0/15",      // benchmarking
+    "198.51.100.0/24",    // documentation
+    "203.0.113.0/24",     // documentation
+    "224.0.0.0/4",        // multicast
+    "240.0.0.0/4",        // reserved
+    "255.255.255.255/32", // limited broadcast
+];
+
+/// Special purpose IPv6 ranges from RFC 6890 and the IANA registry
+pub const DEFAULT_BLOCKLIST_V6: &[&str] = &[
+    "::/128",        // unspecified
+    "::1/128",       // loopback
+    "::ffff:0:0/96", // IPv4 mapped
+    "100::/64",      // discard only
+    "2001::/23",     // IETF protocol assignments
+    "2001:db8::/32", // documentation
+    "fc00::/7",      // unique local
+    "fe80::/10",     // link local
+    "ff00::/8",      // multicast
+];
+
+// Binary trie over the address bits, one trie per address family
+#[derive(Clone, Debug)]
+struct PrefixTrie {
+    // children of each node, 0 is the root so it also means no child
+    children: Vec<[u32; 2]>,
+    // whether a prefix ends at the node
+    terminal: Vec<bool>,
+}
+
+impl PrefixTrie {
+    fn new() -> Self {
+        PrefixTrie {
+            children: vec![[0, 0]],
+            terminal: vec![false],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.children.len() == 1 && !self.terminal[0]
+    }
+
+    fn insert(&mut self, bits: u128, len: u8) {
+        let mut node = 0;
+        for i in 0..len {
+            if self.terminal[node] {
+                // a shorter prefix already covers this one
+                return;
+            }
+            let bit = (bits >> (127 - i) & 1) as usize;
+            if self.children[node][bit] == 0 {
+                self.children.push([0, 0]);
+                self.terminal.push(false);
+                self.children[node][bit] = (self.children.len() - 1) as u32;
+            }
+            node = self.children[node][bit] as usize;
+        }
+        self.terminal[node] = true;
+    }
+
+    fn contains(&self, bits: u128) -> bool {
+        let mut node = 0;
+        for i in 0..128 {
+            if self.terminal[node] {
+                return true;
+            }
+            let bit = (bits >> (127 - i) & 1) as usize;
+            node = match self.children[node][bit] {
+                0 => return false,
+                child => child as usize,
+            };
+        }
+        self.terminal[node]
+    }
+}
+
+/// A set of IPv4 and IPv6 prefixes
+#[derive(Clone, Debug)]
+pub struct PrefixSet {
+    v4: PrefixTrie,
+    v6: PrefixTrie,
+}
+
+impl Default for PrefixSet {
+    fn default() -> Self {
+        PrefixSet {
+            v4: PrefixTrie::new(),
+            v6: PrefixTrie::new(),
+        }
+    }
+}
+
+impl PrefixSet {
+    pub fn insert(&mut self, prefix: IpPrefix) {
+        // addresses are left aligned in a u128 so both families share the trie code
+        match prefix.addr() {
+            IpAddr::V4(ip) => self
+                .v4
+                .insert(u128::from(u32::from(ip)) << 96, prefix.prefix_len()),
+            IpAddr::V6(ip) => self.v6.insert(u128::from(ip), prefix.prefix_len()),
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => self.v4.contains(u128::from(u32::from(ip)) << 96),
+            IpAddr::V6(ip) => self.v6.contains(u128::from(ip)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+
+    /// Add the prefixes of a file in ZMap blocklist format: one address or prefix per line,
+    /// comments start with #
+    pub fn load_file(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let s = read_to_string(path)?;
+        for (i, line) in s.lines().enumerate() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let prefix: IpPrefix = entry
+                .parse()
+                .map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+            self.insert(prefix);
+        }
+        Ok(())
+    }
+}
+
+/// Decides which addresses may be scanned.
+///
+/// An address in the blocklist is never scanned. If the allowlist is not empty, only addresses
+/// in it are scanned. Otherwise every address outside the default blocklist of special purpose
+/// ranges is scanned. An allowlist entry overrides the default blocklist, so private ranges can
+/// be scanned by allowing them explicitly.
+#[derive(Clone, Debug, Default)]
+pub struct Blocklist {
+    blocked: PrefixSet,
+    allowed: PrefixSet,
+    defaults: PrefixSet,
+}
+
+impl Blocklist {
+    /// A blocklist containing only the default special purpose ranges
+    pub fn with_defaults() -> Self {
+        let mut blocklist = Blocklist::default();
+        for prefix in DEFAULT_BLOCKLIST_V4.iter().chain(DEFAULT_BLOCKLIST_V6) {
+            blocklist
+                .defaults
+                .insert(prefix.parse().expect("invalid default blocklist prefix"));
+        }
+        blocklist
+    }
+
+    /// Build the blocklist from files in ZMap blocklist format
+    pub fn load(
+        blocklist_files: &[String],
+        allowlist_files: &[String],
+        default_blocklist: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut blocklist = if default_blocklist {
+            Blocklist::with_defaults()
+        } else {
+            Blocklist::default()
+        };
+        for path in blocklist_files {
+            blocklist.blocked.load_file(path)?;
+        }
+        for path in allowlist_files {
+            blocklist.allowed.load_file(path)?;
+        }
+        Ok(blocklist)
+    }
+
+    pub fn block(&mut self, prefix: IpPrefix) {
+        self.blocked.insert(prefix);
+    }
+
+    pub fn allow(&mut self, prefix: IpPrefix) {
+        self.allowed.insert(prefix);
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.blocked.contains(ip) {
+            return false;
+        }
+        if self.allowed.contains(ip) {
+            return true;
+        }
+        self.allowed.is_empty() && !self.defaults.contains(ip)
+    }
+}
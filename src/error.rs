@@ -0,0 +1,63 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// What can go wrong in a scan. Errors of the tx and rx threads arrive on
+/// `Scanner::error_receiver`, the thread stops after reporting one.
+#[derive(Debug)]
+pub enum ScanError {
+    /// the handshakes file could not be loaded
+    Handshakes(String),
+    /// the nmap-service-probes file could not be loaded
+    ServiceProbes(String),
+    /// a blocklist or allowlist file could not be loaded
+    Blocklist(String),
+    /// the target is an IPv4 address, but there is no source IPv4 address
+    MissingIpv4,
+    /// the target is an IPv6 address, but there is no source IPv6 address
+    MissingIpv6,
+    /// the ip number of the target is not one we can probe
+    UnsupportedProtocol(u8),
+    /// the payload of the target does not fit in a packet
+    PayloadTooLarge,
+    /// reading or writing packets failed
+    Io(io::Error),
+    /// a scanner thread panicked
+    Panicked(&'static str),
+    /// a scanner thread is gone, so nothing can be sent to it anymore
+    Stopped,
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScanError::Handshakes(e) => write!(f, "failed to load handshakes: {}", e),
+            ScanError::ServiceProbes(e) => write!(f, "failed to load service probes: {}", e),
+            ScanError::Blocklist(e) => write!(f, "failed to load blocklist: {}", e),
+            ScanError::MissingIpv4 => write!(f, "Missing source Ipv4 address"),
+            ScanError::MissingIpv6 => write!(f, "Missing source Ipv6 address"),
+            ScanError::UnsupportedProtocol(ip_number) => {
+                write!(f, "Unsupported ip number {}", ip_number)
+            }
+            ScanError::PayloadTooLarge => write!(f, "Payload does not fit in a packet"),
+            ScanError::Io(e) => write!(f, "packet io failed: {}", e),
+            ScanError::Panicked(thread) => write!(f, "the {} thread panicked", thread),
+            ScanError::Stopped => write!(f, "the scanner threads stopped"),
+        }
+    }
+}
+
+impl Error for ScanError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ScanError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ScanError {
+    fn from(e: io::Error) -> Self {
+        ScanError::Io(e)
+    }
+}
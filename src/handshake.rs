@@ -1,43 +1,338 @@
+use crate::tls::{self, TlsConfig};
+use memchr::memmem;
+use regex::bytes::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fs::read_to_string;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
 
 use base64::{engine::general_purpose, Engine as _};
+use rand::Rng;
+
+// Length of the random hex string a {nonce} placeholder is replaced with
+const NONCE_LEN: usize = 16;
+
+/// A port or an inclusive range of ports such as "8000-8100"
+#[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum HandshakePort {
+    Port(u16),
+    Range(String),
+}
+
+impl HandshakePort {
+    fn to_range(&self) -> Result<RangeInclusive<u16>, Box<dyn Error>> {
+        match self {
+            HandshakePort::Port(port) => Ok(*port..=*port),
+            HandshakePort::Range(range) => {
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (start.trim().parse()?, end.trim().parse()?),
+                    None => {
+                        let port = range.trim().parse()?;
+                        (port, port)
+                    }
+                };
+                if start > end {
+                    return Err(format!("invalid port range {}", range).into());
+                }
+                Ok(start..=end)
+            }
+        }
+    }
+}
+
+/// One way of recognising the response of a service. Exactly one of regex, prefix and contains
+/// must be set. prefix and contains are base64, regex is matched against the raw bytes of the
+/// response.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct MatcherDefinition {
+    #[serde(default)]
+    pub regex: Option<String>,
+    #[serde(default)]
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub contains: Option<String>,
+    /// the service recognised by this matcher, if it differs from the service of the handshake
+    #[serde(default)]
+    pub service: Option<String>,
+    /// fields reported with a match, such as product or version. Values may refer to capture
+    /// groups of the regex like $1 or ${name}. Named groups are reported as fields themselves.
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+}
+
+impl MatcherDefinition {
+    fn into_matcher(self) -> Result<Matcher, Box<dyn Error>> {
+        let pattern = match (self.regex, self.prefix, self.contains) {
+            (Some(regex), None, None) => {
+                Pattern::Regex(RegexBuilder::new(&regex).unicode(false).build()?)
+            }
+            (None, Some(prefix), None) => {
+                Pattern::Prefix(general_purpose::STANDARD.decode(prefix)?)
+            }
+            (None, None, Some(contains)) => {
+                Pattern::Contains(general_purpose::STANDARD.decode(contains)?)
+            }
+            _ => return Err("a matcher needs exactly one of regex, prefix and contains".into()),
+        };
+        Ok(Matcher {
+            pattern,
+            service: self.service,
+            fields: self.fields,
+        })
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct HandshakeDefinition {
     pub service: String,
+    /// base64 of the request, not needed for tls
+    #[serde(default)]
     pub request: String,
-    pub response: String,
+    /// base64 of a string the response contains, shorthand for a contains matcher
+    #[serde(default)]
+    pub response: Option<String>,
+    /// alternative ways of recognising the response, tried in order after response
+    #[serde(default)]
+    pub matchers: Vec<MatcherDefinition>,
+    /// ports the service usually runs on, the handshake is tried first on these
+    #[serde(default)]
+    pub ports: Vec<HandshakePort>,
+    /// handshakes with a higher priority are tried earlier
+    #[serde(default)]
+    pub priority: i32,
+    /// send a ClientHello instead of the request, and parse the reply of the server
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
 }
 
 impl HandshakeDefinition {
-    fn to_handshake(self) -> Handshake {
-        Handshake {
+    fn into_handshake(self) -> Result<Handshake, Box<dyn Error>> {
+        let response = self.response.map(|contains| MatcherDefinition {
+            contains: Some(contains),
+            ..Default::default()
+        });
+        let matchers = response
+            .into_iter()
+            .chain(self.matchers)
+            .map(|m| m.into_matcher())
+            .collect::<Result<Vec<_>, _>>()?;
+        if matchers.is_empty() && self.tls.is_none() {
+            return Err("no response or matchers".into());
+        }
+        Ok(Handshake {
+            request: general_purpose::STANDARD.decode(self.request)?,
+            matchers: vec![matchers.into()],
+            ports: self
+                .ports
+                .iter()
+                .map(|port| port.to_range())
+                .collect::<Result<_, _>>()?,
+            priority: self.priority,
+            tls: self.tls,
             service: self.service,
-            request: general_purpose::STANDARD
-                .decode(self.request)
-                .expect("failed to decode handshake"),
-            response: general_purpose::STANDARD
-                .decode(self.response)
-                .expect("failed to decode handshake"),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Regex(Regex),
+    Prefix(Vec<u8>),
+    Contains(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    pub pattern: Pattern,
+    pub service: Option<String>,
+    pub fields: BTreeMap<String, String>,
+}
+
+impl Matcher {
+    /// The fields of the match, if the response matches
+    pub fn find(&self, response: &[u8]) -> Option<BTreeMap<String, String>> {
+        let captures = match &self.pattern {
+            Pattern::Prefix(prefix) if response.starts_with(prefix) => None,
+            Pattern::Contains(contains) if memmem::find(response, contains).is_some() => None,
+            Pattern::Regex(regex) => Some(regex.captures(response)?),
+            _ => return None,
+        };
+
+        let mut fields = BTreeMap::new();
+        if let (Some(captures), Pattern::Regex(regex)) = (&captures, &self.pattern) {
+            for name in regex.capture_names().flatten() {
+                if let Some(value) = captures.name(name) {
+                    fields.insert(
+                        name.to_string(),
+                        String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                    );
+                }
+            }
+        }
+        for (name, template) in &self.fields {
+            let value = match &captures {
+                Some(captures) => {
+                    let mut value = vec![];
+                    captures.expand(template.as_bytes(), &mut value);
+                    String::from_utf8_lossy(&value).into_owned()
+                }
+                None => template.clone(),
+            };
+            fields.insert(name.clone(), value);
         }
+        Some(fields)
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone)]
 pub struct Handshake {
     pub service: String,
     pub request: Vec<u8>,
-    pub response: Vec<u8>,
+    /// groups of matchers tried in order. Handshakes share a group rather than copy it, like the
+    /// matches of nmap's NULL probe which apply to every probe
+    pub matchers: Vec<Arc<[Matcher]>>,
+    pub ports: Vec<RangeInclusive<u16>>,
+    pub priority: i32,
+    pub tls: Option<TlsConfig>,
+}
+
+impl Handshake {
+    pub fn matches_port(&self, port: u16) -> bool {
+        self.ports.iter().any(|ports| ports.contains(&port))
+    }
+
+    /// The request for a target, with the placeholders {ip}, {port}, {hostname} and {nonce}
+    /// replaced. Targets without a hostname get their address as {hostname}, in brackets for
+    /// IPv6 as in a Host header. {nonce} is a new random hex string every time. Anything else in
+    /// braces is left alone. tls handshakes get a ClientHello with the hostname as SNI.
+    pub fn render_request(&self, ip: IpAddr, port: u16, hostname: Option<&str>) -> Vec<u8> {
+        if let Some(tls) = &self.tls {
+            return tls::client_hello(tls, hostname);
+        }
+        let mut request = Vec::with_capacity(self.request.len());
+        let mut rest = &self.request[..];
+        while let Some(start) = memchr::memchr(b'{', rest) {
+            request.extend_from_slice(&rest[..start]);
+            rest = &rest[start..];
+            let end = match memchr::memchr(b'}', rest) {
+                Some(end) => end,
+                None => break,
+            };
+            let value = match &rest[..=end] {
+                b"{ip}" => ip.to_string(),
+                b"{port}" => port.to_string(),
+                b"{hostname}" => match (hostname, ip) {
+                    (Some(hostname), _) => hostname.to_string(),
+                    (None, IpAddr::V4(ip)) => ip.to_string(),
+                    (None, IpAddr::V6(ip)) => format!("[{}]", ip),
+                },
+                b"{nonce}" => nonce(),
+                _ => {
+                    request.push(b'{');
+                    rest = &rest[1..];
+                    continue;
+                }
+            };
+            request.extend_from_slice(value.as_bytes());
+            rest = &rest[end + 1..];
+        }
+        request.extend_from_slice(rest);
+        request
+    }
+
+    /// Match a response against the matchers in order, returning the service and the fields of
+    /// the first match
+    pub fn find(&self, response: &[u8]) -> Option<(&str, BTreeMap<String, String>)> {
+        self.matchers
+            .iter()
+            .flat_map(|group| group.iter())
+            .find_map(|m| {
+                let fields = m.find(response)?;
+                Some((m.service.as_deref().unwrap_or(&self.service), fields))
+            })
+    }
+}
+
+fn nonce() -> String {
+    let mut rng = rand::thread_rng();
+    (0..NONCE_LEN)
+        .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap_or('0'))
+        .collect()
 }
 
 pub fn get_service_handshakes(handshakes_file: &str) -> Result<Vec<Handshake>, Box<dyn Error>> {
     let s = read_to_string(handshakes_file)?;
     let handshake_defs: Vec<HandshakeDefinition> = serde_yaml::from_str(&s)?;
-    let handshakes = handshake_defs
+    handshake_defs
         .into_iter()
-        .map(|h| h.to_handshake())
-        .collect();
-    Ok(handshakes)
+        .map(|h| {
+            let service = h.service.clone();
+            h.into_handshake()
+                .map_err(|e| format!("handshake {}: {}", service, e).into())
+        })
+        .collect()
+}
+
+// Handshakes listing the port go first, then those with a higher priority. The sort is stable,
+// so the file order is kept otherwise.
+fn order_key(handshake: &Handshake, port: u16) -> (bool, i64) {
+    (
+        !handshake.matches_port(port),
+        -i64::from(handshake.priority),
+    )
+}
+
+/// The order to try the handshakes in on a port: the handshakes listing the port, then all
+/// others, each by descending priority and otherwise in file order
+pub fn handshakes_for_port(handshakes: &[Handshake], port: u16) -> Vec<&Handshake> {
+    let mut ordered: Vec<&Handshake> = handshakes.iter().collect();
+    ordered.sort_by_key(|h| order_key(h, port));
+    ordered
+}
+
+/// The handshakes in the order of `handshakes_for_port` for every port, worked out once when
+/// they are loaded instead of for every packet. The order only changes where the port range of
+/// a handshake starts or ends, so ports are grouped into ranges sharing an order.
+pub struct Handshakes {
+    // the first port of every range, sorted, with the index of its order
+    ranges: Vec<(u16, usize)>,
+    orders: Vec<Vec<Arc<Handshake>>>,
+}
+
+impl Handshakes {
+    pub fn new(handshakes: Vec<Handshake>) -> Self {
+        let handshakes: Vec<Arc<Handshake>> = handshakes.into_iter().map(Arc::new).collect();
+        let mut starts = vec![0];
+        for ports in handshakes.iter().flat_map(|h| &h.ports) {
+            starts.push(*ports.start());
+            starts.extend(ports.end().checked_add(1));
+        }
+        starts.sort_unstable();
+        starts.dedup();
+
+        let mut ranges = Vec::with_capacity(starts.len());
+        let mut orders = vec![];
+        let mut known_orders = HashMap::new();
+        for start in starts {
+            let mut order: Vec<usize> = (0..handshakes.len()).collect();
+            order.sort_by_key(|&i| order_key(&handshakes[i], start));
+            let index = *known_orders.entry(order).or_insert_with_key(|order| {
+                orders.push(order.iter().map(|&i| handshakes[i].clone()).collect());
+                orders.len() - 1
+            });
+            ranges.push((start, index));
+        }
+        Handshakes { ranges, orders }
+    }
+
+    /// The handshakes in the order to try them in on a port
+    pub fn for_port(&self, port: u16) -> &[Arc<Handshake>] {
+        // the first range starts at port 0, so there is always one
+        let range = self.ranges.partition_point(|(start, _)| *start <= port) - 1;
+        &self.orders[self.ranges[range].1]
+    }
 }
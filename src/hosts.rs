@@ -0,0 +1,232 @@
+use crate::reassembly::Stream;
+use crate::TcpFlags;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+// A connection, keyed on the full 4-tuple. ip and port are the remote end.
+#[derive(Clone, Debug, Serialize, Deserialize, Hash, Eq, PartialEq)]
+pub(crate) struct Host {
+    pub(crate) ip: IpAddr,
+    pub(crate) port: u16,
+    pub(crate) local_ip: IpAddr,
+    pub(crate) local_port: u16,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Hash)]
+pub(crate) struct State {
+    pub(crate) handshakes_attempted: usize,
+    pub(crate) tcp_flags: TcpFlags,
+    /// the final result of the connection was reported
+    pub(crate) finished: bool,
+    /// the packet with the first request, held back while waiting for the service to send a
+    /// banner, and the length of the request
+    pub(crate) pending_request: Option<(Vec<u8>, u32)>,
+    /// name of the target, for the placeholders of the requests
+    pub(crate) hostname: Option<String>,
+    /// reply of the target, put together until it is complete
+    pub(crate) stream: Stream,
+    /// sequence number of the next byte we send
+    pub(crate) local_seq: u32,
+}
+
+impl State {
+    /// The state of a connection the target just accepted
+    pub(crate) fn new(hostname: Option<String>) -> Self {
+        State {
+            handshakes_attempted: 0,
+            tcp_flags: TcpFlags::Synack,
+            finished: false,
+            pending_request: None,
+            hostname,
+            stream: Stream::default(),
+            local_seq: 0,
+        }
+    }
+}
+
+struct Entry {
+    state: State,
+    last_seen: Instant,
+}
+
+/// Connection state of the hosts we are talking to, bounded in time and size. Entries expire
+/// once they have been idle for the timeout, and the least recently seen entry is evicted when
+/// the table is full.
+pub(crate) struct HostTable {
+    entries: HashMap<Host, Entry>,
+    // every time an entry is seen, it is queued with the time it was seen. As all entries have
+    // the same timeout the queue is ordered by expiry. Queued times older than the last_seen of
+    // their entry are stale and skipped.
+    expiry: VecDeque<(Instant, Host)>,
+    evicted: Vec<(Host, State)>,
+    // hosts waiting for a banner, by the end of their wait
+    banner_waits: VecDeque<(Instant, Host)>,
+    hostnames: Arc<Hostnames>,
+    hostnames_expired: Instant,
+    timeout: Duration,
+    max_hosts: usize,
+}
+
+impl HostTable {
+    pub(crate) fn new(timeout: Duration, max_hosts: usize, hostnames: Arc<Hostnames>) -> Self {
+        HostTable {
+            entries: HashMap::new(),
+            expiry: VecDeque::new(),
+            evicted: vec![],
+            banner_waits: VecDeque::new(),
+            hostnames,
+            hostnames_expired: Instant::now(),
+            timeout,
+            max_hosts,
+        }
+    }
+
+    /// Look up a host and mark it as seen
+    pub(crate) fn get_mut(&mut self, host: &Host) -> Option<&mut State> {
+        let entry = self.entries.get_mut(host)?;
+        let now = Instant::now();
+        entry.last_seen = now;
+        self.expiry.push_back((now, host.clone()));
+        self.compact();
+        self.entries.get_mut(host).map(|entry| &mut entry.state)
+    }
+
+    // Drop stale queue entries once they far outnumber the live ones, so that hosts which keep
+    // talking to us don't grow the queue without bound
+    fn compact(&mut self) {
+        if self.expiry.len() <= 2 * self.entries.len().max(1024) {
+            return;
+        }
+        let entries = &self.entries;
+        self.expiry.retain(
+            |(seen, host)| matches!(entries.get(host), Some(entry) if entry.last_seen == *seen),
+        );
+    }
+
+    /// Track a new host, evicting the least recently seen host if the table is full
+    pub(crate) fn insert(&mut self, host: Host, state: State) {
+        if self.max_hosts == 0 {
+            return;
+        }
+        if !self.entries.contains_key(&host) && self.entries.len() >= self.max_hosts {
+            if let Some(evicted) = self.pop_oldest(None) {
+                self.evicted.push(evicted);
+            }
+        }
+        let now = Instant::now();
+        self.expiry.push_back((now, host.clone()));
+        self.entries.insert(
+            host,
+            Entry {
+                state,
+                last_seen: now,
+            },
+        );
+    }
+
+    // Remove the least recently seen entry, if it was last seen before `before`
+    fn pop_oldest(&mut self, before: Option<Instant>) -> Option<(Host, State)> {
+        while let Some((seen, _)) = self.expiry.front() {
+            if let Some(before) = before {
+                if *seen > before {
+                    return None;
+                }
+            }
+            let (seen, host) = self.expiry.pop_front()?;
+            let current = match self.entries.get(&host) {
+                Some(entry) => entry.last_seen == seen,
+                None => false,
+            };
+            if current {
+                let entry = self.entries.remove(&host)?;
+                return Some((host, entry.state));
+            }
+        }
+        None
+    }
+
+    /// Remove and return the hosts which have been idle for longer than the timeout
+    pub(crate) fn expire(&mut self) -> Vec<(Host, State)> {
+        if self.hostnames_expired.elapsed() >= self.timeout {
+            self.hostnames.expire(self.timeout);
+            self.hostnames_expired = Instant::now();
+        }
+        let deadline = match Instant::now().checked_sub(self.timeout) {
+            Some(deadline) => deadline,
+            None => return vec![],
+        };
+        let mut expired = vec![];
+        while let Some(entry) = self.pop_oldest(Some(deadline)) {
+            expired.push(entry);
+        }
+        expired
+    }
+
+    /// The hostname of the target a new connection was opened to, if it has one
+    pub(crate) fn take_hostname(&self, host: &Host) -> Option<String> {
+        self.hostnames.take(host.ip, host.port)
+    }
+
+    /// Wait for a host to send a banner for the given time
+    pub(crate) fn wait_for_banner(&mut self, host: Host, wait: Duration) {
+        self.banner_waits.push_back((Instant::now() + wait, host));
+    }
+
+    /// Hosts whose banner wait ended since the last call. They may have sent their banner or
+    /// been removed in the meantime.
+    pub(crate) fn banner_waits_over(&mut self) -> Vec<Host> {
+        let now = Instant::now();
+        let mut hosts = vec![];
+        while let Some((end, _)) = self.banner_waits.front() {
+            if *end > now {
+                break;
+            }
+            if let Some((_, host)) = self.banner_waits.pop_front() {
+                hosts.push(host);
+            }
+        }
+        hosts
+    }
+
+    /// Hosts evicted to make room for new ones since the last call
+    pub(crate) fn take_evicted(&mut self) -> Vec<(Host, State)> {
+        std::mem::take(&mut self.evicted)
+    }
+}
+
+/// Hostnames of the targets which have one, from sending the SYN until the target answers.
+/// Targets are keyed on address and port, so of two targets with the same address and port but
+/// different hostnames only the last one scanned keeps its hostname.
+#[derive(Debug, Default)]
+pub(crate) struct Hostnames {
+    entries: Mutex<HashMap<(IpAddr, u16), (Instant, String)>>,
+}
+
+impl Hostnames {
+    pub(crate) fn insert(&self, ip: IpAddr, port: u16, hostname: String) {
+        self.entries()
+            .insert((ip, port), (Instant::now(), hostname));
+    }
+
+    /// Remove and return the hostname of a target
+    pub(crate) fn take(&self, ip: IpAddr, port: u16) -> Option<String> {
+        self.entries()
+            .remove(&(ip, port))
+            .map(|(_, hostname)| hostname)
+    }
+
+    /// Forget the hostnames of targets which did not answer within the timeout
+    pub(crate) fn expire(&self, timeout: Duration) {
+        self.entries()
+            .retain(|_, (scanned, _)| scanned.elapsed() < timeout);
+    }
+
+    // Every change to the map is a single insert or removal, so it is intact even if a thread
+    // panicked while holding the lock, and the scan carries on with it
+    fn entries(&self) -> MutexGuard<'_, HashMap<(IpAddr, u16), (Instant, String)>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
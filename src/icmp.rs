@@ -0,0 +1,124 @@
+use crate::PortState;
+use etherparse::ip_number;
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const ICMPV4_ECHO_REPLY: u8 = 0;
+pub const ICMPV4_DEST_UNREACHABLE: u8 = 3;
+pub const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+pub const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Length of the echo request payload, which carries the send time
+pub const ECHO_PAYLOAD_LEN: usize = 8;
+
+const ICMPV4_PORT_UNREACHABLE: u8 = 3;
+const ICMPV6_PORT_UNREACHABLE: u8 = 4;
+
+/// The headers of one of our probes, as quoted back to us in an ICMP error message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuotedProbe {
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub ip_number: u8,
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// sequence number of a quoted tcp header. ICMPv4 only guarantees the first 8 bytes of the
+    /// transport header are quoted, which is just enough for the ports and the sequence number.
+    pub tcp_seq: Option<u32>,
+}
+
+/// Parse the ip header and the start of the transport header quoted in the payload of an
+/// ICMP or ICMPv6 error message. Only tcp and udp probes are of interest.
+pub fn parse_quoted_probe(payload: &[u8]) -> Option<QuotedProbe> {
+    let (src_ip, dst_ip, ip_number, header_len) = match payload.first()? >> 4 {
+        4 => {
+            if payload.len() < 20 {
+                return None;
+            }
+            let header_len = usize::from(payload[0] & 0xf) * 4;
+            let src: [u8; 4] = payload[12..16].try_into().ok()?;
+            let dst: [u8; 4] = payload[16..20].try_into().ok()?;
+            (
+                IpAddr::V4(Ipv4Addr::from(src)),
+                IpAddr::V4(Ipv4Addr::from(dst)),
+                payload[9],
+                header_len,
+            )
+        }
+        6 => {
+            if payload.len() < 40 {
+                return None;
+            }
+            let src: [u8; 16] = payload[8..24].try_into().ok()?;
+            let dst: [u8; 16] = payload[24..40].try_into().ok()?;
+            (
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                payload[6],
+                40,
+            )
+        }
+        _ => return None,
+    };
+
+    if ip_number != u8::from(ip_number::TCP) && ip_number != u8::from(ip_number::UDP) {
+        return None;
+    }
+    let transport = payload.get(header_len..)?;
+    if transport.len() < 4 {
+        return None;
+    }
+    let tcp_seq = if ip_number == u8::from(ip_number::TCP) && transport.len() >= 8 {
+        Some(u32::from_be_bytes(transport[4..8].try_into().ok()?))
+    } else {
+        None
+    };
+
+    Some(QuotedProbe {
+        src_ip,
+        dst_ip,
+        ip_number,
+        src_port: u16::from_be_bytes([transport[0], transport[1]]),
+        dst_port: u16::from_be_bytes([transport[2], transport[3]]),
+        tcp_seq,
+    })
+}
+
+/// Port unreachable means the target itself answered that nothing listens on the port. Every
+/// other code (protocol unreachable, host unreachable, administratively prohibited, ...) means
+/// something on the way stopped the probe, as nmap reports it.
+pub fn icmpv4_unreachable_port_state(code: u8) -> PortState {
+    match code {
+        ICMPV4_PORT_UNREACHABLE => PortState::Closed,
+        _ => PortState::Filtered,
+    }
+}
+
+pub fn icmpv6_unreachable_port_state(code: u8) -> PortState {
+    match code {
+        ICMPV6_PORT_UNREACHABLE => PortState::Closed,
+        _ => PortState::Filtered,
+    }
+}
+
+/// Split the validation cookie of a target into the identifier and sequence number of an echo
+/// request, so the reply can be validated without remembering the request
+pub fn echo_id_seq(cookie: u32) -> (u16, u16) {
+    ((cookie >> 16) as u16, cookie as u16)
+}
+
+/// Echo request payload holding the send time in nanoseconds since the unix epoch. The target
+/// sends it back in the reply, which gives the round trip time without keeping state.
+pub fn echo_payload() -> [u8; ECHO_PAYLOAD_LEN] {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_nanos() as u64).to_be_bytes()
+}
+
+pub fn echo_rtt(payload: &[u8]) -> Option<Duration> {
+    let sent: [u8; ECHO_PAYLOAD_LEN] = payload.get(..ECHO_PAYLOAD_LEN)?.try_into().ok()?;
+    let sent = UNIX_EPOCH + Duration::from_nanos(u64::from_be_bytes(sent));
+    SystemTime::now().duration_since(sent).ok()
+}
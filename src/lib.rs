@@ -1,21 +1,47 @@
-use afpacket::sync::RawPacketStream;
+use blocklist::Blocklist;
 use crossbeam_channel::{unbounded, Receiver, Sender};
-use etherparse::PacketBuilder;
-use rand::prelude::*;
+pub use error::ScanError;
+use etherparse::{ip_number, PacketBuilder};
+use handshake::Handshakes;
+use hosts::Hostnames;
+use packet_io::{Backend, Interface, PacketIo};
+use ratelimit::RateLimit;
+use send::Retransmit;
 use serde::{Deserialize, Serialize};
 use serde_with::base64::Base64;
 use serde_with::serde_as;
-use std::error::Error;
-use std::fmt;
+use stats::ScanStats;
+use std::collections::BTreeMap;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tls::TlsInfo;
+use validate::Validator;
 
+pub mod blocklist;
+pub mod error;
 pub mod handshake;
+mod hosts;
+pub mod icmp;
+pub mod nmap;
+pub mod output;
 pub mod packet;
+pub mod packet_io;
+pub mod permutation;
+pub mod ratelimit;
+pub mod reassembly;
 pub mod recv;
+pub mod ring;
 pub mod send;
+pub mod stats;
+pub mod targets;
+pub mod tls;
+pub mod udp;
+pub mod validate;
+pub mod xdp;
 
 pub const MAX_PACKET_SIZE: usize = 1500;
 
@@ -25,6 +51,9 @@ pub struct Target {
     pub port: u16,
     pub ip_number: u8,
     pub data: Option<Vec<u8>>,
+    /// name of the target, filled into the {hostname} placeholder of handshake requests
+    #[serde(default)]
+    pub hostname: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
@@ -35,6 +64,26 @@ pub enum TcpFlags {
     Rst,
 }
 
+/// How a connection is closed once the reply to a handshake was captured, or the connection timed
+/// out
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, Eq, PartialEq, Hash)]
+pub enum Teardown {
+    /// reset the connection
+    #[default]
+    Rst,
+    /// send a FIN and acknowledge the FIN of the target
+    Fin,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
+pub enum PortState {
+    Open,
+    /// the target answered that nothing listens on the port
+    Closed,
+    /// the probe was stopped before reaching the service, e.g. by a firewall
+    Filtered,
+}
+
 #[serde_as]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ScanResult {
@@ -42,7 +91,20 @@ pub struct ScanResult {
     pub port: u16,
     pub transport_protocol: u8,
     pub service: Option<String>,
+    /// services of the handshakes sent to the target, in order
+    pub probes: Vec<String>,
+    /// fields filled in by the matcher which recognised the service, e.g. product and version
+    pub captures: BTreeMap<String, String>,
+    /// what the server sent in reply to a tls handshake
+    pub tls: Option<TlsInfo>,
     pub tcp_flags: Option<TcpFlags>,
+    pub port_state: Option<PortState>,
+    /// type of the ICMP message the result was built from
+    pub icmp_type: Option<u8>,
+    /// round trip time of an ICMP echo
+    pub rtt: Option<Duration>,
+    /// the connection state expired before the target answered our handshake
+    pub timed_out: bool,
     #[serde_as(as = "Base64")]
     pub data: Vec<u8>,
 }
@@ -53,60 +115,133 @@ pub struct ScanConfig {
     pub dst_mac: [u8; 6],
     pub src_ipv4: Option<Ipv4Addr>,
     pub src_ipv6: Option<Ipv6Addr>,
-    pub src_port: u16,
-    pub handshakes_file: String,
+    /// source ports to send probes from, the port for a target is picked by hashing the target
+    pub src_ports: RangeInclusive<u16>,
+    /// handshakes in the yaml format of handshakes.yaml
+    pub handshakes_file: Option<String>,
+    /// handshakes in the nmap-service-probes format, tried along with the yaml handshakes
+    pub service_probes_file: Option<String>,
+    pub rate_limit: RateLimit,
+    /// key for the sequence number hash used to validate responses, should be random per scan
+    pub secret: [u8; 16],
+    /// files of prefixes which are never scanned, in ZMap blocklist format
+    pub blocklist_files: Vec<String>,
+    /// files of prefixes to scan, if given nothing outside of them is scanned
+    pub allowlist_files: Vec<String>,
+    /// don't scan private, reserved and multicast ranges unless they are allowlisted
+    pub default_blocklist: bool,
+    /// log every target which is dropped by the blocklist
+    pub log_blocked: bool,
+    /// forget a connection after not hearing from the host for this long
+    pub host_timeout: Duration,
+    /// maximum number of connections tracked at once, the least recently seen is evicted
+    pub max_hosts: usize,
+    /// after the SYN-ACK, wait this long for the service to speak first before sending a request
+    pub banner_wait: Option<Duration>,
+    /// bytes of a reply kept at most, the rest of it is not acknowledged
+    pub max_capture: usize,
+    /// how connections are closed once we are done with them
+    pub teardown: Teardown,
+    /// how often the SYN of a tcp target is sent
+    pub retransmit: Retransmit,
+    /// how `Scanner::open` sends and receives frames
+    pub backend: Backend,
 }
 
-#[derive(Debug)]
-enum PacketGenError {
-    MissingIpv4,
-    MissingIpv6,
-}
-
-impl fmt::Display for PacketGenError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            PacketGenError::MissingIpv4 => write!(f, "Missing source Ipv4 address"),
-            PacketGenError::MissingIpv6 => write!(f, "Missing source Ipv6 address"),
-        }
-    }
-}
-
-impl Error for PacketGenError {
-    fn description(&self) -> &str {
-        match *self {
-            PacketGenError::MissingIpv4 => "Missing source Ipv4 address",
-            PacketGenError::MissingIpv6 => "Missing source Ipv6 address",
-        }
+impl Target {
+    // The sequence number of the SYN to the target, which the answers to it acknowledge
+    pub(crate) fn tcp_cookie(
+        &self,
+        scan_config: &ScanConfig,
+        validator: &Validator,
+    ) -> Result<u32, ScanError> {
+        let src_ip = match self.ip {
+            IpAddr::V4(_) => IpAddr::V4(scan_config.src_ipv4.ok_or(ScanError::MissingIpv4)?),
+            IpAddr::V6(_) => IpAddr::V6(scan_config.src_ipv6.ok_or(ScanError::MissingIpv6)?),
+        };
+        let src_port = validator.src_port(self.ip, self.port, &scan_config.src_ports);
+        Ok(validator.tcp_seq(src_ip, self.ip, src_port, self.port))
     }
-}
 
-impl Target {
     fn to_pkt(
         &self,
         mut pkt: &mut [u8],
         scan_config: &ScanConfig,
-    ) -> Result<usize, PacketGenError> {
+        validator: &Validator,
+    ) -> Result<usize, ScanError> {
         let pkt_builder = PacketBuilder::ethernet2(scan_config.src_mac, scan_config.dst_mac);
 
-        let pkt_builder = match self.ip {
+        let (src_ip, pkt_builder) = match self.ip {
             IpAddr::V4(ipv4) => {
-                let src_ipv4 = scan_config.src_ipv4.ok_or(PacketGenError::MissingIpv4)?;
-                pkt_builder.ipv4(src_ipv4.octets(), ipv4.octets(), 20)
+                let src_ipv4 = scan_config.src_ipv4.ok_or(ScanError::MissingIpv4)?;
+                (
+                    IpAddr::V4(src_ipv4),
+                    pkt_builder.ipv4(src_ipv4.octets(), ipv4.octets(), 20),
+                )
             }
             IpAddr::V6(ipv6) => {
-                let src_ipv6 = scan_config.src_ipv6.ok_or(PacketGenError::MissingIpv6)?;
-                pkt_builder.ipv6(src_ipv6.octets(), ipv6.octets(), 20)
+                let src_ipv6 = scan_config.src_ipv6.ok_or(ScanError::MissingIpv6)?;
+                (
+                    IpAddr::V6(src_ipv6),
+                    pkt_builder.ipv6(src_ipv6.octets(), ipv6.octets(), 20),
+                )
             }
         };
 
-        let pkt_builder = pkt_builder
-            .tcp(scan_config.src_port, self.port, random(), 65535)
-            .syn();
+        let src_port = validator.src_port(self.ip, self.port, &scan_config.src_ports);
+        if self.ip_number == u8::from(ip_number::TCP) {
+            let seq = validator.tcp_seq(src_ip, self.ip, src_port, self.port);
+            let pkt_builder = pkt_builder.tcp(src_port, self.port, seq, 65535).syn();
+
+            let len = pkt_builder.size(0);
+            pkt_builder
+                .write(&mut pkt, &[])
+                .map_err(|_| ScanError::PayloadTooLarge)?;
+            Ok(len)
+        } else if self.ip_number == u8::from(ip_number::UDP) {
+            let payload = match (&self.data, udp::default_probe(self.port)) {
+                (Some(data), _) => data.as_slice(),
+                (None, Some(probe)) => probe.payload,
+                (None, None) => &[],
+            };
+            let pkt_builder = pkt_builder.udp(src_port, self.port);
 
-        let len = pkt_builder.size(0);
-        pkt_builder.write(&mut pkt, &[]).unwrap();
-        Ok(len)
+            let len = pkt_builder.size(payload.len());
+            if len > pkt.len() {
+                return Err(ScanError::PayloadTooLarge);
+            }
+            pkt_builder
+                .write(&mut pkt, payload)
+                .map_err(|_| ScanError::PayloadTooLarge)?;
+            Ok(len)
+        } else if self.ip_number == u8::from(ip_number::ICMP)
+            || self.ip_number == u8::from(ip_number::IPV6_ICMP)
+        {
+            // the echo request type follows the address family of the target
+            let (id, seq) = icmp::echo_id_seq(validator.echo_cookie(src_ip, self.ip));
+            let payload = icmp::echo_payload();
+            let len = match self.ip {
+                IpAddr::V4(_) => {
+                    let pkt_builder = pkt_builder.icmpv4_echo_request(id, seq);
+                    let len = pkt_builder.size(payload.len());
+                    pkt_builder
+                        .write(&mut pkt, &payload)
+                        .map_err(|_| ScanError::PayloadTooLarge)?;
+                    len
+                }
+                IpAddr::V6(_) => {
+                    let pkt_builder = pkt_builder.icmpv6_echo_request(id, seq);
+                    let len = pkt_builder.size(payload.len());
+                    pkt_builder
+                        .write(&mut pkt, &payload)
+                        .map_err(|_| ScanError::PayloadTooLarge)?;
+                    len
+                }
+            };
+            Ok(len)
+        } else {
+            Err(ScanError::UnsupportedProtocol(self.ip_number))
+        }
     }
 }
 
@@ -114,75 +249,179 @@ impl Target {
 pub struct Scanner {
     pub conf: ScanConfig,
     pub target_sender: Sender<Vec<u8>>,
+    probe_sender: Sender<(u32, Vec<u8>)>,
     pub result_receiver: Receiver<ScanResult>,
+    /// errors which stopped the tx or rx thread
+    pub error_receiver: Receiver<ScanError>,
+    rate_limit_sender: Sender<RateLimit>,
+    validator: Validator,
+    blocklist: Blocklist,
+    stats: Arc<ScanStats>,
+    hostnames: Arc<Hostnames>,
     tx_handle: JoinHandle<()>,
     rx_handle: JoinHandle<()>,
     shutdown: Arc<AtomicBool>,
 }
 
 impl Scanner {
-    pub fn new(packet_stream: RawPacketStream, conf: ScanConfig) -> Self {
-        let tx = packet_stream;
+    /// Load the handshakes and the blocklist, and start the tx and rx threads, which send and
+    /// receive through clones of packet_io
+    pub fn new<P: PacketIo>(packet_io: P, conf: ScanConfig) -> Result<Self, ScanError> {
+        let blocklist = Blocklist::load(
+            &conf.blocklist_files,
+            &conf.allowlist_files,
+            conf.default_blocklist,
+        )
+        .map_err(|e| ScanError::Blocklist(e.to_string()))?;
+        let mut handshakes = vec![];
+        if let Some(handshakes_file) = &conf.handshakes_file {
+            handshakes.extend(
+                handshake::get_service_handshakes(handshakes_file)
+                    .map_err(|e| ScanError::Handshakes(e.to_string()))?,
+            );
+        }
+        if let Some(service_probes_file) = &conf.service_probes_file {
+            handshakes.extend(
+                nmap::get_service_probes(service_probes_file)
+                    .map_err(|e| ScanError::ServiceProbes(e.to_string()))?,
+            );
+        }
+
+        let tx = packet_io;
         let rx = tx.clone();
 
         let (target_sender, target_receiver) = unbounded();
+        let (probe_sender, probe_receiver) = unbounded();
+        let (answered_sender, answered_receiver) = unbounded();
         let (result_sender, result_receiver) = unbounded();
+        let (error_sender, error_receiver) = unbounded();
+        let (rate_limit_sender, rate_limit_receiver) = unbounded();
         let shutdown = Arc::new(AtomicBool::new(false));
+        let validator = Validator::new(&conf.secret);
+        let stats = Arc::new(ScanStats::default());
+        let hostnames = Arc::new(Hostnames::default());
 
         let tx_shutdown = shutdown.clone();
-        let tx_handle = thread::Builder::new()
-            .name("tx".into())
-            .spawn(move || {
-                send::start_tx(tx, target_receiver, tx_shutdown);
-            })
-            .expect("failed to start tx thread");
-
-        let handshakes = handshake::get_service_handshakes(&conf.handshakes_file)
-            .expect("failed to get handshakes from file");
+        let tx_rate_limit = conf.rate_limit.clone();
+        let tx_retransmit = conf.retransmit.clone();
+        let tx_errors = error_sender.clone();
+        let tx_handle = thread::Builder::new().name("tx".into()).spawn(move || {
+            if let Err(e) = send::start_tx(
+                tx,
+                target_receiver,
+                probe_receiver,
+                answered_receiver,
+                tx_retransmit,
+                tx_rate_limit,
+                rate_limit_receiver,
+                tx_shutdown,
+            ) {
+                log::error!("tx thread stopped: {}", e);
+                let _ = tx_errors.send(e);
+            }
+        })?;
 
         let rx_target_sender = target_sender.clone();
         let rx_shutdown = shutdown.clone();
         let rx_conf = conf.clone();
-        let rx_handle = thread::Builder::new()
-            .name("rx".into())
-            .spawn(move || {
-                recv::start_rx(
-                    rx,
-                    rx_conf,
-                    handshakes,
-                    rx_target_sender,
-                    result_sender,
-                    rx_shutdown,
-                );
-            })
-            .expect("failed to start rx thread");
-
-        Scanner {
+        let rx_stats = stats.clone();
+        let rx_hostnames = hostnames.clone();
+        let rx_handle = thread::Builder::new().name("rx".into()).spawn(move || {
+            if let Err(e) = recv::start_rx(
+                rx,
+                rx_conf,
+                rx_stats,
+                rx_hostnames,
+                Handshakes::new(handshakes),
+                rx_target_sender,
+                answered_sender,
+                result_sender,
+                rx_shutdown,
+            ) {
+                log::error!("rx thread stopped: {}", e);
+                let _ = error_sender.send(e);
+            }
+        });
+        let rx_handle = match rx_handle {
+            Ok(rx_handle) => rx_handle,
+            Err(e) => {
+                shutdown.store(true, Ordering::Relaxed);
+                let _ = tx_handle.join();
+                return Err(e.into());
+            }
+        };
+
+        Ok(Scanner {
             conf,
             target_sender,
+            probe_sender,
             result_receiver,
+            error_receiver,
+            rate_limit_sender,
+            validator,
+            blocklist,
+            stats,
+            hostnames,
             tx_handle,
             rx_handle,
             shutdown,
-        }
+        })
+    }
+
+    /// Open the interface with the backend of the config and start scanning on it
+    pub fn open(if_name: &str, conf: ScanConfig) -> Result<Self, ScanError> {
+        let interface = Interface::open(if_name, &conf.backend, &conf.src_ports)?;
+        Scanner::new(interface, conf)
     }
 
-    pub fn scan_target(&self, target: &Target) {
+    /// Send a probe to the target, unless the blocklist forbids it. The SYN of a tcp target is sent
+    /// as often as `ScanConfig::retransmit` says, or until the target answers.
+    pub fn scan_target(&self, target: &Target) -> Result<(), ScanError> {
+        if !self.blocklist.is_allowed(target.ip) {
+            stats::increment(&self.stats.blocked);
+            if self.conf.log_blocked {
+                log::info!("blocked target {}:{}", target.ip, target.port);
+            }
+            return Ok(());
+        }
         let mut pkt = vec![0; MAX_PACKET_SIZE];
-        let len = target
-            .to_pkt(&mut pkt, &self.conf)
-            .expect("failed to convert target to packet");
+        let len = target.to_pkt(&mut pkt, &self.conf, &self.validator)?;
+        if let Some(hostname) = &target.hostname {
+            if target.ip_number == u8::from(ip_number::TCP) {
+                self.hostnames
+                    .insert(target.ip, target.port, hostname.clone());
+            }
+        }
+        // only the SYNs of tcp targets are retransmitted, until the target answers
+        if target.ip_number == u8::from(ip_number::TCP) && self.conf.retransmit.probes > 1 {
+            let cookie = target.tcp_cookie(&self.conf, &self.validator)?;
+            return self
+                .probe_sender
+                .send((cookie, pkt[..len].to_vec()))
+                .map_err(|_| ScanError::Stopped);
+        }
         self.target_sender
             .send(pkt[..len].to_vec())
-            .expect("failed to send target");
+            .map_err(|_| ScanError::Stopped)
     }
-    pub fn shutdown(self) {
+
+    /// Replace the rate limit of the running tx thread
+    pub fn set_rate_limit(&self, rate_limit: RateLimit) -> Result<(), ScanError> {
+        self.rate_limit_sender
+            .send(rate_limit)
+            .map_err(|_| ScanError::Stopped)
+    }
+
+    pub fn stats(&self) -> &ScanStats {
+        &self.stats
+    }
+
+    /// Stop the tx and rx threads and wait for them to finish
+    pub fn shutdown(self) -> Result<(), ScanError> {
         self.shutdown.swap(true, Ordering::Relaxed);
-        self.tx_handle
-            .join()
-            .expect("failed to wait for tx thread to stop");
-        self.rx_handle
-            .join()
-            .expect("failed to wait for rx thread to stop");
+        let tx = self.tx_handle.join();
+        let rx = self.rx_handle.join();
+        tx.map_err(|_| ScanError::Panicked("tx"))?;
+        rx.map_err(|_| ScanError::Panicked("rx"))
     }
 }
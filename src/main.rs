@@ -1,32 +1,86 @@
-use afpacket::sync::RawPacketStream;
 use clap::Parser;
-use crossbeam_channel::Receiver;
-use rscan::{ScanConfig, ScanResult, Scanner, Target};
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError};
+use etherparse::ip_number;
+use rscan::output::{self, OutputWriter, Rotation};
+use rscan::packet_io::{Backend, Interface};
+use rscan::permutation::Permutation;
+use rscan::ratelimit::{RateLimit, DEFAULT_BURST};
+use rscan::recv::{DEFAULT_HOST_TIMEOUT, DEFAULT_MAX_CAPTURE, DEFAULT_MAX_HOSTS};
+use rscan::ring::RingConfig;
+use rscan::send::{Retransmit, DEFAULT_PROBE_DELAY};
+use rscan::targets::TargetSpec;
+use rscan::xdp::{XdpConfig, XdpMode};
+use rscan::{ScanConfig, ScanError, ScanResult, Scanner, Target, Teardown};
 use std::error::Error;
-use std::io::{self, BufRead, BufReader};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor};
 use std::net::IpAddr;
+use std::num::NonZeroU64;
+use std::ops::RangeInclusive;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
+// How often buffered results are flushed to the output while results keep coming in
+const OUTPUT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Rscan
 #[derive(Debug, Clone, Parser)]
 #[command(version = "1.0", author = "Collins Huff")]
 struct Opts {
-    /// input file, if omitted defaults to stdin
+    /// targets such as 10.0.0.0/8:80,443,8000-8100, read from the input if omitted
+    targets: Vec<String>,
+
+    /// protocol used to probe targets given as prefixes and ports: tcp, udp or icmp
+    #[arg(long, default_value = "tcp", value_parser = parse_protocol)]
+    protocol: u8,
+
+    /// scan targets given as prefixes and ports in a pseudo random order
+    #[arg(long)]
+    shuffle: bool,
+
+    /// seed of the random order, the same seed gives the same order. Random if omitted
+    #[arg(long, requires = "shuffle")]
+    seed: Option<u64>,
+
+    /// only scan shard I of N of the random order, such as 0/4
+    #[arg(long, requires = "shuffle", value_parser = parse_shard)]
+    shard: Option<(u64, u64)>,
+
+    /// skip the first positions of the random order of the first target specification, to
+    /// resume an interrupted scan at the position it logged
+    #[arg(long, requires = "shuffle")]
+    resume: Option<u64>,
+
+    /// input file, if omitted defaults to stdin. .gz and .zst files are decompressed
     #[arg(short, long)]
     input: Option<String>,
 
-    /// output file, if omitted defaults to stdout
+    /// output file, if omitted defaults to stdout. .gz and .zst files are compressed
     #[arg(short, long)]
     output: Option<String>,
 
+    /// start a new output file once this many bytes were written, accepts K, M and G suffixes
+    #[arg(long, value_parser = parse_size)]
+    rotate_size: Option<u64>,
+
+    /// start a new output file after this many seconds
+    #[arg(long)]
+    rotate_secs: Option<u64>,
+
     /// log file, if omitted defaults to stderr
     #[arg(short, long)]
     log: Option<String>,
 
     /// service handshakes file
-    #[arg(short, long)]
-    handshakes_file: String,
+    #[arg(short, long, required_unless_present = "service_probes_file")]
+    handshakes_file: Option<String>,
+
+    /// nmap-service-probes file to load handshakes from, in addition to the handshakes file
+    #[arg(long)]
+    service_probes_file: Option<String>,
 
     /// interface name
     #[arg(short, long)]
@@ -48,9 +102,75 @@ struct Opts {
     #[arg(long)]
     src_ipv6: Option<String>,
 
-    /// source port
+    /// source port, or an inclusive range of source ports such as 40000-60000
+    #[arg(long, visible_alias = "src-port", value_parser = parse_port_range)]
+    src_ports: RangeInclusive<u16>,
+
+    /// maximum packets per second, unlimited if omitted
+    #[arg(short, long, value_parser = parse_rate)]
+    rate: Option<NonZeroU64>,
+
+    /// maximum bandwidth in bits per second, accepts K, M and G suffixes, unlimited if omitted
+    #[arg(short, long, value_parser = parse_bandwidth)]
+    bandwidth: Option<NonZeroU64>,
+
+    /// number of packets which may be sent back to back
+    #[arg(long, default_value_t = DEFAULT_BURST)]
+    burst: u64,
+
+    /// file of prefixes which must never be scanned, in ZMap blocklist format. Can be repeated
+    #[arg(long)]
+    blocklist_file: Vec<String>,
+
+    /// file of prefixes to scan, nothing outside of them is scanned. Can be repeated
+    #[arg(long)]
+    allowlist_file: Vec<String>,
+
+    /// also scan private, reserved and multicast ranges which are blocked by default
     #[arg(long)]
-    src_port: u16,
+    no_default_blocklist: bool,
+
+    /// log every target dropped by the blocklist
+    #[arg(long)]
+    log_blocked: bool,
+
+    /// seconds to keep the state of a connection without hearing from the host
+    #[arg(long, default_value_t = DEFAULT_HOST_TIMEOUT.as_secs())]
+    host_timeout: u64,
+
+    /// maximum number of connections to track at once
+    #[arg(long, default_value_t = DEFAULT_MAX_HOSTS)]
+    max_hosts: usize,
+
+    /// milliseconds to wait for a banner after connecting, before sending the first handshake
+    #[arg(long)]
+    banner_wait: Option<u64>,
+
+    /// bytes of a reply to keep at most
+    #[arg(long, default_value_t = DEFAULT_MAX_CAPTURE)]
+    max_capture: usize,
+
+    /// how to close connections once the reply was captured, rst or fin
+    #[arg(long, default_value = "rst", value_parser = parse_teardown)]
+    teardown: Teardown,
+
+    /// maximum number of times to send the SYN of each tcp target, it is not sent again once the
+    /// target answered
+    #[arg(long, default_value_t = 1)]
+    probes: u32,
+
+    /// milliseconds to wait between the first and the second SYN of a target
+    #[arg(long, default_value_t = DEFAULT_PROBE_DELAY.as_millis() as u64)]
+    probe_delay: u64,
+
+    /// double the wait before every further SYN
+    #[arg(long)]
+    probe_backoff: bool,
+
+    /// how to send and receive packets: afpacket, ring for TPACKET_V3 memory mapped rings, or
+    /// xdp and xdp-native for an AF_XDP socket in generic or driver mode
+    #[arg(long, default_value = "afpacket", value_parser = parse_backend)]
+    backend: Backend,
 
     /// Turn debugging information on
     #[arg(short, long, action = clap::ArgAction::Count)]
@@ -59,7 +179,7 @@ struct Opts {
 
 fn parse_mac(mac: &str) -> Result<[u8; 6], Box<dyn Error>> {
     let mut mac_bytes: [u8; 6] = [0; 6];
-    let parts: Vec<&str> = mac.split(':').into_iter().collect();
+    let parts: Vec<&str> = mac.split(':').collect();
     if parts.len() != 6 {
         Err("wrong len".into())
     } else {
@@ -71,11 +191,94 @@ fn parse_mac(mac: &str) -> Result<[u8; 6], Box<dyn Error>> {
     }
 }
 
+fn parse_port_range(ports: &str) -> Result<RangeInclusive<u16>, Box<dyn Error + Send + Sync>> {
+    let (start, end) = match ports.split_once('-') {
+        Some((start, end)) => (start.parse()?, end.parse()?),
+        None => {
+            let port = ports.parse()?;
+            (port, port)
+        }
+    };
+    if start > end {
+        return Err("source port range start is greater than its end".into());
+    }
+    Ok(start..=end)
+}
+
+fn parse_protocol(protocol: &str) -> Result<u8, Box<dyn Error + Send + Sync>> {
+    match protocol {
+        "tcp" => Ok(u8::from(ip_number::TCP)),
+        "udp" => Ok(u8::from(ip_number::UDP)),
+        "icmp" => Ok(u8::from(ip_number::ICMP)),
+        _ => Err(format!("unknown protocol {}", protocol).into()),
+    }
+}
+
+fn parse_teardown(teardown: &str) -> Result<Teardown, Box<dyn Error + Send + Sync>> {
+    match teardown {
+        "rst" => Ok(Teardown::Rst),
+        "fin" => Ok(Teardown::Fin),
+        _ => Err(format!("unknown teardown {}", teardown).into()),
+    }
+}
+
+fn parse_backend(backend: &str) -> Result<Backend, Box<dyn Error + Send + Sync>> {
+    match backend {
+        "afpacket" => Ok(Backend::AfPacket),
+        "ring" => Ok(Backend::Ring(RingConfig::default())),
+        "xdp" => Ok(Backend::Xdp(XdpConfig::default())),
+        "xdp-native" => Ok(Backend::Xdp(XdpConfig {
+            mode: XdpMode::Native,
+            ..XdpConfig::default()
+        })),
+        _ => Err(format!("unknown backend {}", backend).into()),
+    }
+}
+
+fn parse_shard(shard: &str) -> Result<(u64, u64), Box<dyn Error + Send + Sync>> {
+    let (shard, num_shards) = shard.split_once('/').ok_or("expected shard/shards")?;
+    let (shard, num_shards) = (shard.parse()?, num_shards.parse()?);
+    if shard >= num_shards {
+        return Err("shard must be less than the number of shards".into());
+    }
+    Ok((shard, num_shards))
+}
+
+fn parse_size(size: &str) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    let (digits, multiplier) = match size.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&size[..i], 1_000),
+        Some((i, 'm')) | Some((i, 'M')) => (&size[..i], 1_000_000),
+        Some((i, 'g')) | Some((i, 'G')) => (&size[..i], 1_000_000_000),
+        _ => (size, 1),
+    };
+    let value: u64 = digits.parse()?;
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| "value too large".into())
+}
+
+// A rate of 0 would stop the scan, leaving the option out is how to scan without a limit
+fn parse_rate(rate: &str) -> Result<NonZeroU64, Box<dyn Error + Send + Sync>> {
+    NonZeroU64::new(rate.parse()?).ok_or_else(|| "must be greater than 0".into())
+}
+
+fn parse_bandwidth(bandwidth: &str) -> Result<NonZeroU64, Box<dyn Error + Send + Sync>> {
+    NonZeroU64::new(parse_size(bandwidth)?).ok_or_else(|| "must be greater than 0".into())
+}
+
+fn init_logging(log: &Option<String>) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Some(path) = log {
+        let file = File::create(path).expect("failed to create log file");
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    builder.init();
+}
+
 fn main() {
-    env_logger::init();
     let opts = Opts::parse();
-    let mut ps = RawPacketStream::new().unwrap();
-    ps.bind(&opts.dev)
+    init_logging(&opts.log);
+    let interface = Interface::open(&opts.dev, &opts.backend, &opts.src_ports)
         .expect("failed to bind to specified interface");
 
     let src_ipv4 =
@@ -99,34 +302,214 @@ fn main() {
         dst_mac: parse_mac(&opts.dest_mac).expect("failed to parse src mac"),
         src_ipv4,
         src_ipv6,
-        src_port: opts.src_port,
+        src_ports: opts.src_ports,
         handshakes_file: opts.handshakes_file,
+        service_probes_file: opts.service_probes_file,
+        rate_limit: RateLimit {
+            packets_per_second: opts.rate,
+            bits_per_second: opts.bandwidth,
+            burst: opts.burst,
+        },
+        secret: rand::random(),
+        blocklist_files: opts.blocklist_file,
+        allowlist_files: opts.allowlist_file,
+        default_blocklist: !opts.no_default_blocklist,
+        log_blocked: opts.log_blocked,
+        host_timeout: Duration::from_secs(opts.host_timeout),
+        max_hosts: opts.max_hosts,
+        banner_wait: opts.banner_wait.map(Duration::from_millis),
+        max_capture: opts.max_capture,
+        teardown: opts.teardown,
+        retransmit: Retransmit {
+            probes: opts.probes,
+            delay: Duration::from_millis(opts.probe_delay),
+            backoff: opts.probe_backoff,
+        },
+        backend: opts.backend.clone(),
+    };
+
+    let mut writer = match &opts.output {
+        Some(path) => {
+            let rotation = Rotation {
+                max_bytes: opts.rotate_size,
+                max_age: opts.rotate_secs.map(Duration::from_secs),
+            };
+            OutputWriter::create(path, rotation).expect("failed to create output file")
+        }
+        None => OutputWriter::stdout(),
+    };
+
+    let reader: Box<dyn BufRead + Send> = match &opts.input {
+        None if !opts.targets.is_empty() => Box::new(Cursor::new(opts.targets.join("\n"))),
+        Some(path) => output::open_input(path).expect("failed to open input file"),
+        None => Box::new(BufReader::new(io::stdin())),
     };
 
-    let scanner = Scanner::new(ps, scan_config);
+    // stop reading targets on ctrl-c, then shut down as if the input ended so that all
+    // results received so far make it to the output
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_interrupted = interrupted.clone();
+    ctrlc::set_handler(move || {
+        handler_interrupted.store(true, Ordering::Relaxed);
+    })
+    .expect("failed to set ctrl-c handler");
+
+    let scanner = Scanner::new(interface, scan_config).expect("failed to start scanner");
 
     let results = scanner.result_receiver.clone();
-    std::thread::spawn(|| {
-        print_hits(results);
+    let output_handle = thread::spawn(move || {
+        print_hits(results, &mut writer);
+        writer.finish().expect("failed to finish output");
+    });
+
+    // read on a separate thread, a read from stdin can block forever
+    let (line_sender, line_receiver) = bounded(1024);
+    thread::spawn(move || {
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    log::error!("failed to read input: {}", e);
+                    break;
+                }
+            };
+            if line_sender.send(line).is_err() {
+                break;
+            }
+        }
     });
 
-    let reader = BufReader::new(io::stdin());
-    for line in reader.lines() {
-        let line = line.expect("failed to read line");
-        let target: Target = serde_json::from_str(&line).expect("failed to parse target");
-        thread::sleep(Duration::from_micros(1));
-        log::trace!("sending target to scanner: {:?}", target);
-        scanner.scan_target(&target);
+    let seed = opts.seed.unwrap_or_else(rand::random);
+    let mut resume = opts.resume;
+    let mut failed = 0;
+    let mut stopped = false;
+    'input: while !interrupted.load(Ordering::Relaxed) {
+        let line = match line_receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        // a line is either a single json target or prefixes and ports expanded into targets
+        if line.starts_with('{') {
+            match serde_json::from_str(line) {
+                Ok(target) => {
+                    if !scan(&scanner, &target, &mut failed) {
+                        stopped = true;
+                        break;
+                    }
+                }
+                Err(e) => log::error!("skipping invalid target {}: {}", line, e),
+            }
+            continue;
+        }
+        let spec: TargetSpec = match line.parse() {
+            Ok(spec) => spec,
+            Err(e) => {
+                log::error!("skipping invalid target specification {}: {}", line, e);
+                continue;
+            }
+        };
+        let spec = spec.with_ip_number(opts.protocol);
+        if !opts.shuffle {
+            for target in &spec {
+                if interrupted.load(Ordering::Relaxed) {
+                    break;
+                }
+                if !scan(&scanner, &target, &mut failed) {
+                    stopped = true;
+                    break 'input;
+                }
+            }
+            continue;
+        }
+
+        let permutation = match Permutation::new(spec.len(), seed) {
+            Ok(permutation) => permutation,
+            Err(e) => {
+                log::error!("skipping {}: {}", line, e);
+                continue;
+            }
+        };
+        let (shard, num_shards) = opts.shard.unwrap_or((0, 1));
+        let order = permutation
+            .shard(shard, num_shards)
+            .resume_at(resume.take().unwrap_or(0));
+        let mut targets = spec.permuted(order);
+        for target in &mut targets {
+            if !scan(&scanner, &target, &mut failed) {
+                stopped = true;
+                break 'input;
+            }
+            if interrupted.load(Ordering::Relaxed) {
+                log::info!(
+                    "interrupted {} with seed {}, resume at {}",
+                    line,
+                    seed,
+                    targets.position()
+                );
+                break;
+            }
+        }
+    }
+
+    if !interrupted.load(Ordering::Relaxed) && !stopped {
+        thread::sleep(Duration::from_secs(10));
     }
 
-    thread::sleep(Duration::from_secs(10));
+    log::info!("{} targets blocked", scanner.stats().blocked());
+    if failed > 0 {
+        log::warn!("{} targets could not be scanned", failed);
+    }
 
-    scanner.shutdown();
+    // the results channel closes once the scanner threads are gone, which ends the output
+    // thread. Whatever went wrong, the output is finished so no result gets lost.
+    let mut success = !stopped;
+    if let Err(e) = scanner.shutdown() {
+        log::error!("failed to shut down scanner: {}", e);
+        success = false;
+    }
+    if output_handle.join().is_err() {
+        log::error!("the output thread panicked");
+        success = false;
+    }
+    if !success {
+        process::exit(1);
+    }
 }
 
-fn print_hits(results: Receiver<ScanResult>) {
-    for result in results {
-        let result = serde_json::to_string(&result).expect("failed to serialize result");
-        println!("{}", result);
+// Send a target to the scanner. A target it can't build a probe for is logged and counted,
+// false means the scanner threads are gone.
+fn scan(scanner: &Scanner, target: &Target, failed: &mut u64) -> bool {
+    log::trace!("sending target to scanner: {:?}", target);
+    match scanner.scan_target(target) {
+        Ok(()) => true,
+        Err(ScanError::Stopped) => {
+            log::error!("the scanner stopped, no more targets are scanned");
+            false
+        }
+        Err(e) => {
+            log::warn!("skipping target {}:{}: {}", target.ip, target.port, e);
+            *failed += 1;
+            true
+        }
+    }
+}
+
+fn print_hits(results: Receiver<ScanResult>, writer: &mut OutputWriter) {
+    loop {
+        match results.recv_timeout(OUTPUT_FLUSH_INTERVAL) {
+            Ok(result) => {
+                let result = serde_json::to_vec(&result).expect("failed to serialize result");
+                writer.write_line(&result).expect("failed to write result");
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                writer.flush().expect("failed to flush output");
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
     }
 }
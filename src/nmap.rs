@@ -0,0 +1,338 @@
+use crate::handshake::{Handshake, Matcher, Pattern};
+use regex::bytes::RegexBuilder;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::read_to_string;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+// The probe nmap sends first on every port. Its matches apply to the responses of all other tcp
+// probes, as the banner of a service may arrive before it read our request.
+const NULL_PROBE: &str = "NULL";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceProbesError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ServiceProbesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ServiceProbesError {}
+
+struct Probe {
+    tcp: bool,
+    name: String,
+    request: Vec<u8>,
+    matches: Vec<Matcher>,
+    softmatches: Vec<Matcher>,
+    ports: Vec<RangeInclusive<u16>>,
+    rarity: u8,
+    fallback: Vec<String>,
+}
+
+/// Load the tcp probes of an nmap-service-probes file as handshakes
+pub fn get_service_probes(service_probes_file: &str) -> Result<Vec<Handshake>, Box<dyn Error>> {
+    let s = read_to_string(service_probes_file)?;
+    parse_service_probes(&s)
+        .map_err(|e| format!("{}:{}: {}", service_probes_file, e.line, e.message).into())
+}
+
+/// Parse the nmap-service-probes format into handshakes, one per tcp probe.
+///
+/// The handshake is named after the probe, and each match reports its own service. Softmatches
+/// are tried after the matches of their probe, followed by the matches of the fallback probes and
+/// of the NULL probe. The NULL probe sends nothing, so it is no handshake of its own; the banner
+/// wait takes its place. The matches of a probe are shared by all handshakes trying them, not
+/// copied. The rarity of a probe becomes a negative priority, so common probes are
+/// tried first. UDP probes, sslports, Exclude and the wait times are ignored, as are matches
+/// whose regex uses PCRE features the regex crate lacks.
+pub fn parse_service_probes(s: &str) -> Result<Vec<Handshake>, ServiceProbesError> {
+    let mut probes: Vec<Probe> = vec![];
+    let mut unsupported = 0;
+    for (i, line) in s.lines().enumerate() {
+        let err = |message: String| ServiceProbesError {
+            line: i + 1,
+            message,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (directive, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        if directive == "Probe" {
+            probes.push(parse_probe(rest).map_err(err)?);
+            continue;
+        }
+        if directive == "Exclude" {
+            continue;
+        }
+        let probe = probes
+            .last_mut()
+            .ok_or_else(|| err(format!("{} before the first Probe", directive)))?;
+        match directive {
+            "match" | "softmatch" => match parse_match(rest).map_err(err)? {
+                Some(matcher) if directive == "match" => probe.matches.push(matcher),
+                Some(matcher) => probe.softmatches.push(matcher),
+                None => unsupported += 1,
+            },
+            "ports" => probe.ports = parse_ports(rest).map_err(err)?,
+            "rarity" => {
+                probe.rarity = rest
+                    .parse()
+                    .map_err(|_| err(format!("invalid rarity {}", rest)))?
+            }
+            "fallback" => probe.fallback = rest.split(',').map(|p| p.trim().to_string()).collect(),
+            "sslports" | "totalwaitms" | "tcpwrappedms" => {}
+            _ => return Err(err(format!("unknown directive {}", directive))),
+        }
+    }
+    if unsupported > 0 {
+        log::warn!("skipped {} matches with unsupported regexes", unsupported);
+    }
+
+    // the matches and softmatches of every probe, shared by the handshakes trying them
+    let groups: Vec<Arc<[Matcher]>> = probes
+        .iter_mut()
+        .map(|probe| {
+            let mut matchers = std::mem::take(&mut probe.matches);
+            matchers.append(&mut probe.softmatches);
+            matchers.into()
+        })
+        .collect();
+    let find_probe = |name: &str| probes.iter().position(|p| p.tcp && p.name == name);
+    let null_probe = find_probe(NULL_PROBE);
+    let mut handshakes = vec![];
+    let handshake_probes = probes
+        .iter()
+        .enumerate()
+        .filter(|(i, p)| p.tcp && Some(*i) != null_probe);
+    for (i, probe) in handshake_probes {
+        let mut group_indices = vec![i];
+        for name in &probe.fallback {
+            if let Some(fallback) = find_probe(name) {
+                if fallback != i && Some(fallback) != null_probe {
+                    group_indices.push(fallback);
+                }
+            }
+        }
+        group_indices.extend(null_probe);
+        let matchers: Vec<Arc<[Matcher]>> = group_indices
+            .into_iter()
+            .map(|g| groups[g].clone())
+            .filter(|group| !group.is_empty())
+            .collect();
+        if matchers.is_empty() {
+            log::debug!("skipping probe {} without matches", probe.name);
+            continue;
+        }
+        handshakes.push(Handshake {
+            service: probe.name.clone(),
+            request: probe.request.clone(),
+            matchers,
+            ports: probe.ports.clone(),
+            priority: -i32::from(probe.rarity),
+            tls: None,
+        });
+    }
+    Ok(handshakes)
+}
+
+// Probe <protocol> <name> q|<request>| [no-payload]
+fn parse_probe(s: &str) -> Result<Probe, String> {
+    let mut parts = s.splitn(3, ' ');
+    let tcp = match parts.next() {
+        Some("TCP") => true,
+        Some("UDP") => false,
+        p => return Err(format!("invalid probe protocol {}", p.unwrap_or_default())),
+    };
+    let name = parts.next().ok_or("missing probe name")?.to_string();
+    let request = parts
+        .next()
+        .and_then(|r| r.strip_prefix('q'))
+        .and_then(delimited)
+        .ok_or_else(|| format!("missing request of probe {}", name))?
+        .0;
+    Ok(Probe {
+        tcp,
+        request: unescape(request)?,
+        name,
+        matches: vec![],
+        softmatches: vec![],
+        ports: vec![],
+        rarity: 0,
+        fallback: vec![],
+    })
+}
+
+// <service> m/<regex>/[opts] [p/<product>/] [v/<version>/] ... [cpe:/<cpe>/[a]]. Returns None
+// if the regex crate can't compile the regex.
+fn parse_match(s: &str) -> Result<Option<Matcher>, String> {
+    let (service, rest) = s.split_once(' ').ok_or("missing match pattern")?;
+    let (regex, rest) = rest
+        .trim_start()
+        .strip_prefix('m')
+        .and_then(delimited)
+        .ok_or_else(|| format!("invalid pattern of service {}", service))?;
+    let flags: String = rest.chars().take_while(char::is_ascii_alphabetic).collect();
+    let mut rest = &rest[flags.len()..];
+
+    let mut fields = BTreeMap::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let (name, value) = if let Some(cpe) = rest.strip_prefix("cpe:") {
+            ("cpe", cpe)
+        } else {
+            let name = match rest.as_bytes()[0] {
+                b'p' => "product",
+                b'v' => "version",
+                b'i' => "info",
+                b'h' => "hostname",
+                b'o' => "os",
+                b'd' => "device",
+                _ => return Err(format!("invalid version info {}", rest)),
+            };
+            (name, &rest[1..])
+        };
+        let (value, remainder) =
+            delimited(value).ok_or_else(|| format!("unterminated {} of {}", name, service))?;
+        rest = remainder.strip_prefix('a').unwrap_or(remainder);
+        let template = match name {
+            "cpe" => format!("cpe:/{}", template(value)),
+            _ => template(value),
+        };
+        fields
+            .entry(name.to_string())
+            .and_modify(|v: &mut String| {
+                v.push(' ');
+                v.push_str(&template);
+            })
+            .or_insert(template);
+    }
+
+    let regex = RegexBuilder::new(regex)
+        .unicode(false)
+        .octal(true)
+        .case_insensitive(flags.contains('i'))
+        .dot_matches_new_line(flags.contains('s'))
+        .build();
+    match regex {
+        Ok(regex) => Ok(Some(Matcher {
+            pattern: Pattern::Regex(regex),
+            service: Some(service.to_string()),
+            fields,
+        })),
+        Err(e) => {
+            log::debug!("skipping match for {}: {}", service, e);
+            Ok(None)
+        }
+    }
+}
+
+// ports 21,23,80-85
+fn parse_ports(s: &str) -> Result<Vec<RangeInclusive<u16>>, String> {
+    s.split(',')
+        .map(|port| {
+            let port = port.trim();
+            let (start, end) = port.split_once('-').unwrap_or((port, port));
+            match (start.parse::<u16>(), end.parse::<u16>()) {
+                (Ok(start), Ok(end)) if start <= end => Ok(start..=end),
+                _ => Err(format!("invalid port {}", port)),
+            }
+        })
+        .collect()
+}
+
+// Split "|abc|rest" into ("abc", "rest"), with the first character as the delimiter
+fn delimited(s: &str) -> Option<(&str, &str)> {
+    let delimiter = s.chars().next()?;
+    let s = &s[delimiter.len_utf8()..];
+    let end = s.find(delimiter)?;
+    Some((&s[..end], &s[end + delimiter.len_utf8()..]))
+}
+
+// Resolve the C style escapes nmap allows in probe requests
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        let byte = match chars.next() {
+            Some('0') => 0,
+            Some('a') => 7,
+            Some('b') => 8,
+            Some('t') => b'\t',
+            Some('n') => b'\n',
+            Some('v') => 11,
+            Some('f') => 12,
+            Some('r') => b'\r',
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&hex, 16).map_err(|_| format!("invalid escape \\x{}", hex))?
+            }
+            Some(c) if c.is_ascii_punctuation() => c as u8,
+            c => return Err(format!("invalid escape \\{}", c.unwrap_or_default())),
+        };
+        bytes.push(byte);
+    }
+    Ok(bytes)
+}
+
+// Turn nmap's version info substitutions into the templates of the regex crate. $1 becomes
+// ${1}, so that it can be followed by letters. The helpers $P(1), $SUBST(1,...) and $I(1,...)
+// are approximated by the plain group.
+fn template(s: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+        if !digits.is_empty() {
+            out.push_str(&format!("${{{}}}", digits));
+            rest = &rest[digits.len()..];
+            continue;
+        }
+        let helper = ["P(", "SUBST(", "I("]
+            .iter()
+            .find_map(|helper| rest.strip_prefix(helper));
+        match helper {
+            Some(args) => {
+                let group: String = args.chars().take_while(char::is_ascii_digit).collect();
+                out.push_str(&format!("${{{}}}", group));
+                rest = closing_paren(args)
+                    .map(|end| &args[end + 1..])
+                    .unwrap_or("");
+            }
+            None => out.push_str("$$"),
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// The index of the ) closing the arguments of a helper, skipping quoted arguments
+fn closing_paren(args: &str) -> Option<usize> {
+    let mut quoted = false;
+    for (i, c) in args.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ')' if !quoted => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
@@ -0,0 +1,237 @@
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Pick the compression from the file extension, .gz for gzip and .zst or .zstd for zstd
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") | Some("zstd") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// Open a file for reading line by line, decompressing it if the extension says so
+pub fn open_input(path: &str) -> io::Result<Box<dyn BufRead + Send>> {
+    let path = Path::new(path);
+    let file = File::open(path)?;
+    let reader: Box<dyn BufRead + Send> = match Compression::from_path(path) {
+        Compression::None => Box::new(BufReader::new(file)),
+        Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::new(file)?)),
+    };
+    Ok(reader)
+}
+
+/// When to move on to a new output file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rotation {
+    /// rotate once this many bytes (before compression) were written to a file
+    pub max_bytes: Option<u64>,
+    /// rotate once a file has been open for this long
+    pub max_age: Option<Duration>,
+}
+
+impl Rotation {
+    fn is_enabled(&self) -> bool {
+        self.max_bytes.is_some() || self.max_age.is_some()
+    }
+}
+
+enum Sink {
+    Stdout(BufWriter<io::Stdout>),
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Sink {
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Sink::Stdout(w) => w,
+            Sink::Plain(w) => w,
+            Sink::Gzip(w) => w,
+            Sink::Zstd(w) => w,
+        }
+    }
+
+    // Write the compression trailer, if any, and flush everything to the file
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Sink::Stdout(mut w) => w.flush(),
+            Sink::Plain(mut w) => w.flush(),
+            Sink::Gzip(w) => w.finish()?.flush(),
+            Sink::Zstd(w) => w.finish()?.flush(),
+        }
+    }
+}
+
+/// Line oriented result writer. Writes to stdout or to a file, compressed according to the
+/// file extension, and optionally rotated by size or age. Rotated files are numbered, so
+/// results.json.gz becomes results.0.json.gz, results.1.json.gz, ... Numbers already taken, e.g.
+/// by an earlier run, are skipped rather than overwritten.
+/// Data is only guaranteed to be on disk after `flush` or `finish`.
+pub struct OutputWriter {
+    path: Option<PathBuf>,
+    compression: Compression,
+    rotation: Rotation,
+    sink: Option<Sink>,
+    file_index: u64,
+    bytes_written: u64,
+    opened_at: Instant,
+}
+
+impl OutputWriter {
+    pub fn stdout() -> Self {
+        OutputWriter {
+            path: None,
+            compression: Compression::None,
+            rotation: Rotation::default(),
+            sink: Some(Sink::Stdout(BufWriter::new(io::stdout()))),
+            file_index: 0,
+            bytes_written: 0,
+            opened_at: Instant::now(),
+        }
+    }
+
+    pub fn create(path: &str, rotation: Rotation) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let mut writer = OutputWriter {
+            compression: Compression::from_path(&path),
+            path: Some(path),
+            rotation,
+            sink: None,
+            file_index: 0,
+            bytes_written: 0,
+            opened_at: Instant::now(),
+        };
+        writer.open_next()?;
+        Ok(writer)
+    }
+
+    fn file_path(&self, path: &Path) -> PathBuf {
+        if !self.rotation.is_enabled() {
+            return path.to_path_buf();
+        }
+        // insert the index in front of all extensions, so that results.json.gz keeps its
+        // extensions and stays recognisable as compressed json
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let rotated_name = match file_name.split_once('.') {
+            Some((stem, extensions)) => format!("{}.{}.{}", stem, self.file_index, extensions),
+            None => format!("{}.{}", file_name, self.file_index),
+        };
+        path.with_file_name(rotated_name)
+    }
+
+    // Create the next rotated file, skipping the numbers of files which already exist
+    fn create_next(&mut self, path: &Path) -> io::Result<(PathBuf, File)> {
+        if !self.rotation.is_enabled() {
+            return Ok((path.to_path_buf(), File::create(path)?));
+        }
+        loop {
+            let rotated = self.file_path(path);
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&rotated)
+            {
+                Ok(file) => return Ok((rotated, file)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => self.file_index += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn open_next(&mut self) -> io::Result<()> {
+        let path = match self.path.clone() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(sink) = self.sink.take() {
+            sink.finish()?;
+        }
+        let (path, file) = self.create_next(&path)?;
+        log::info!("writing results to {}", path.display());
+        let file = BufWriter::new(file);
+        self.sink = Some(match self.compression {
+            Compression::None => Sink::Plain(file),
+            Compression::Gzip => Sink::Gzip(GzEncoder::new(file, flate2::Compression::default())),
+            Compression::Zstd => Sink::Zstd(zstd::Encoder::new(file, 0)?),
+        });
+        self.file_index += 1;
+        self.bytes_written = 0;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+
+    fn needs_rotation(&self) -> bool {
+        if self.path.is_none() || self.bytes_written == 0 {
+            return false;
+        }
+        let too_big = match self.rotation.max_bytes {
+            Some(max_bytes) => self.bytes_written >= max_bytes,
+            None => false,
+        };
+        let too_old = match self.rotation.max_age {
+            Some(max_age) => self.opened_at.elapsed() >= max_age,
+            None => false,
+        };
+        too_big || too_old
+    }
+
+    /// Write a single line, a newline is appended
+    pub fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.needs_rotation() {
+            self.open_next()?;
+        }
+        let sink = self
+            .sink
+            .as_mut()
+            .ok_or_else(|| io::Error::other("output writer is finished"))?;
+        let writer = sink.writer();
+        writer.write_all(line)?;
+        writer.write_all(b"\n")?;
+        self.bytes_written += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.sink.as_mut() {
+            Some(sink) => sink.writer().flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Flush all buffered results and finish the compressed stream
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.sink.take() {
+            Some(sink) => sink.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for OutputWriter {
+    fn drop(&mut self) {
+        if let Some(sink) = self.sink.take() {
+            if let Err(e) = sink.finish() {
+                log::error!("failed to finish output: {}", e);
+            }
+        }
+    }
+}
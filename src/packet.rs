@@ -1,8 +1,8 @@
+use crate::Teardown;
 use etherparse::{
     Ethernet2Header, InternetSlice, IpHeader, LinkSlice, PacketBuilder, PacketBuilderStep,
     SlicedPacket, TcpHeader, TransportSlice,
 };
-use std::str;
 
 // Build ip header response to received packet by swapping source and dest ips
 pub fn build_response_ip_header(
@@ -30,6 +30,7 @@ pub fn build_response_ip_header(
 // Build tcp header response to received packet by looking at the received tcp flags.
 // Returns PacketBuilderStep<TcpHeader> with the response tcp header and a bool indicating whether the
 // response may contain a payload (whether the response header is an ack)
+// A reset is never answered, so for a reset None is returned
 pub fn build_response_tcp_header(
     rx_sliced: &SlicedPacket,
     builder: PacketBuilderStep<IpHeader>,
@@ -39,8 +40,11 @@ pub fn build_response_tcp_header(
         &TransportSlice::Udp(_)
         | &TransportSlice::Icmpv4(_)
         | &TransportSlice::Icmpv6(_)
-        | &TransportSlice::Unknown(_) => return None,
+        | &TransportSlice::Unknown(_) => None,
         TransportSlice::Tcp(tcp) => {
+            if tcp.rst() {
+                return None;
+            }
             let builder = builder.tcp(
                 tcp.destination_port(),
                 tcp.source_port(),
@@ -49,23 +53,26 @@ pub fn build_response_tcp_header(
             );
 
             if tcp.syn() && tcp.ack() {
-                return Some((builder.ack(tcp.sequence_number() + 1), true));
+                Some((builder.ack(tcp.sequence_number().wrapping_add(1)), true))
             } else if tcp.syn() {
-                return Some((builder.syn().ack(tcp.sequence_number()), false));
+                Some((
+                    builder.syn().ack(tcp.sequence_number().wrapping_add(1)),
+                    false,
+                ))
             } else if tcp.ack() {
-                return Some((builder.ack(tcp.sequence_number()), true));
+                Some((builder.ack(tcp.sequence_number()), true))
             } else if tcp.fin() {
-                return Some((builder.fin().ack(tcp.sequence_number()), false));
-            } else if tcp.rst() {
-                return Some((builder.rst().ack(tcp.sequence_number()), false));
+                Some((builder.fin().ack(tcp.sequence_number()), false))
             } else {
-                return None;
+                None
             }
         }
     }
 }
 
 // Build a tcp response to the received packet. If the received packet is not a tcp packet, return None
+// The response mirrors the full 4-tuple of the received packet, so it leaves from whichever of our
+// source ports the received packet was addressed to.
 // If applicable, write the argument payload
 pub fn build_tcp_response(
     rx_sliced: &SlicedPacket,
@@ -96,15 +103,101 @@ pub fn build_tcp_response(
 
     let mut len = pkt_builder.size(0);
     if can_include_payload {
+        // like any tcp stack, mark the end of what we had to send
+        let pkt_builder = if payload.is_empty() {
+            pkt_builder
+        } else {
+            pkt_builder.psh()
+        };
         len += payload.len();
-        pkt_builder
-            .write(&mut tx_pkt, payload)
-            .expect("failed to write pkt");
+        pkt_builder.write(&mut tx_pkt, payload).ok()?;
     } else {
-        pkt_builder
-            .write(&mut tx_pkt, &[])
-            .expect("failed to write pkt");
+        pkt_builder.write(&mut tx_pkt, &[]).ok()?;
+    }
+    Some(len)
+}
+
+// Build a reset for the connection of the received tcp packet, with the sequence number the
+// received packet acknowledges, acknowledging everything it carried.
+// If the received packet is not a tcp packet, return None
+pub fn build_tcp_reset(rx_sliced: &SlicedPacket, mut tx_pkt: &mut [u8]) -> Option<usize> {
+    let tcp = match rx_sliced.transport.as_ref()? {
+        TransportSlice::Tcp(tcp) => tcp,
+        _ => return None,
+    };
+    let link = rx_sliced.link.as_ref()?;
+    let LinkSlice::Ethernet2(link) = link;
+    let pkt_builder = PacketBuilder::ethernet2(link.destination(), link.source());
+    let pkt_builder = build_response_ip_header(rx_sliced, pkt_builder)?
+        .tcp(
+            tcp.destination_port(),
+            tcp.source_port(),
+            tcp.acknowledgment_number(),
+            0,
+        )
+        .rst()
+        .ack(
+            tcp.sequence_number()
+                .wrapping_add(rx_sliced.payload.len() as u32),
+        );
+
+    let len = pkt_builder.size(0);
+    pkt_builder.write(&mut tx_pkt, &[]).ok()?;
+    Some(len)
+}
+
+// Build the packet closing the connection of the received tcp packet, a reset or a FIN depending
+// on the teardown, acknowledging everything before ack_number.
+// If the received packet is not a tcp packet, return None
+pub fn build_tcp_teardown(
+    rx_sliced: &SlicedPacket,
+    teardown: Teardown,
+    ack_number: u32,
+    mut tx_pkt: &mut [u8],
+) -> Option<usize> {
+    let link = rx_sliced.link.as_ref()?;
+    let LinkSlice::Ethernet2(link) = link;
+    let pkt_builder = PacketBuilder::ethernet2(link.destination(), link.source());
+    let pkt_builder = build_response_ip_header(rx_sliced, pkt_builder)?;
+    let (pkt_builder, _) = build_response_tcp_header(rx_sliced, pkt_builder)?;
+    let pkt_builder = match teardown {
+        Teardown::Rst => pkt_builder.rst(),
+        Teardown::Fin => pkt_builder.fin(),
     }
+    .ack(ack_number);
+
+    let len = pkt_builder.size(0);
+    pkt_builder.write(&mut tx_pkt, &[]).ok()?;
+    Some(len)
+}
+
+// Build an ack of everything before ack_number on the connection of the received tcp packet,
+// offering the given window so the sender can go on sending.
+// If the received packet is not a tcp packet, return None
+pub fn build_tcp_ack(
+    rx_sliced: &SlicedPacket,
+    ack_number: u32,
+    window: u16,
+    mut tx_pkt: &mut [u8],
+) -> Option<usize> {
+    let tcp = match rx_sliced.transport.as_ref()? {
+        TransportSlice::Tcp(tcp) => tcp,
+        _ => return None,
+    };
+    let link = rx_sliced.link.as_ref()?;
+    let LinkSlice::Ethernet2(link) = link;
+    let pkt_builder = PacketBuilder::ethernet2(link.destination(), link.source());
+    let pkt_builder = build_response_ip_header(rx_sliced, pkt_builder)?
+        .tcp(
+            tcp.destination_port(),
+            tcp.source_port(),
+            tcp.acknowledgment_number(),
+            window,
+        )
+        .ack(ack_number);
+
+    let len = pkt_builder.size(0);
+    pkt_builder.write(&mut tx_pkt, &[]).ok()?;
     Some(len)
 }
 
@@ -112,35 +205,38 @@ pub fn log_response(sliced_pkt: &SlicedPacket) {
     let ip_str = match &sliced_pkt.ip {
         None => String::new(),
         Some(internet_slice) => match &internet_slice {
-            InternetSlice::Ipv4(slice) => serde_json::to_string(&slice.header().to_header())
-                .expect("failed to convert header to string"),
+            InternetSlice::Ipv4(slice) => {
+                serde_json::to_string(&slice.header().to_header()).unwrap_or_default()
+            }
             InternetSlice::Ipv6(ipv6_header_slice) => {
-                serde_json::to_string(&ipv6_header_slice.header().to_header())
-                    .expect("failed to convert header to string")
+                serde_json::to_string(&ipv6_header_slice.header().to_header()).unwrap_or_default()
             }
         },
     };
-    let transport_str =
-        match &sliced_pkt.transport {
-            None => String::new(),
-            Some(transport_slice) => match transport_slice {
-                TransportSlice::Icmpv4(slice) => serde_json::to_string(&slice.header())
-                    .expect("failed to convert header to string"),
-                TransportSlice::Icmpv6(slice) => serde_json::to_string(&slice.header())
-                    .expect("failed to convert header to string"),
-                TransportSlice::Tcp(slice) => serde_json::to_string(&slice.to_header())
-                    .expect("failed to convert_header to string"),
-                TransportSlice::Udp(slice) => serde_json::to_string(&slice.to_header())
-                    .expect("failed to convert header to string"),
-                TransportSlice::Unknown(_) => String::new(),
-            },
-        };
+    let transport_str = match &sliced_pkt.transport {
+        None => String::new(),
+        Some(transport_slice) => match transport_slice {
+            TransportSlice::Icmpv4(slice) => {
+                serde_json::to_string(&slice.header()).unwrap_or_default()
+            }
+            TransportSlice::Icmpv6(slice) => {
+                serde_json::to_string(&slice.header()).unwrap_or_default()
+            }
+            TransportSlice::Tcp(slice) => {
+                serde_json::to_string(&slice.to_header()).unwrap_or_default()
+            }
+            TransportSlice::Udp(slice) => {
+                serde_json::to_string(&slice.to_header()).unwrap_or_default()
+            }
+            TransportSlice::Unknown(_) => String::new(),
+        },
+    };
 
     //let payload = format!("{:x?}", sliced_pkt.payload);
     log::info!(
         "Received packet: ip {}, transport, {}, payload {}",
         ip_str,
         transport_str,
-        str::from_utf8(sliced_pkt.payload).expect("failed to convert payload to str")
+        String::from_utf8_lossy(sliced_pkt.payload)
     );
 }
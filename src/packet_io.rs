@@ -0,0 +1,392 @@
+use crate::ring::{Ring, RingConfig};
+use crate::xdp::{Xdp, XdpConfig};
+use afpacket::sync::RawPacketStream;
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader, BufWriter, ErrorKind};
+use std::mem;
+use std::ops::RangeInclusive;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long the rx thread waits for a frame before it looks after its connections
+pub const DEFAULT_RECV_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Sends and receives ethernet frames for the scanner. The tx and rx threads each get a clone, so
+/// clones must share the underlying interface.
+pub trait PacketIo: Clone + Send + 'static {
+    /// Send one frame
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    /// Receive one frame into `frame` and return its length, or None if no frame arrived within
+    /// the receive timeout
+    fn recv(&mut self, frame: &mut [u8]) -> io::Result<Option<usize>>;
+
+    /// Set how long `recv` waits for a frame
+    fn set_recv_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+
+    /// Send the frames `send` queued rather than sent right away. The tx thread calls it whenever
+    /// it runs out of frames to send.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An AF_PACKET socket bound to an interface
+impl PacketIo for RawPacketStream {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.write_all(frame)
+    }
+
+    fn recv(&mut self, frame: &mut [u8]) -> io::Result<Option<usize>> {
+        match self.read(frame) {
+            Ok(len) => Ok(Some(len)),
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => Ok(None),
+                _ => Err(e),
+            },
+        }
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        let timeout = libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+        };
+        // SAFETY: the socket outlives the call, and timeout is a valid timeval of the given size
+        let ret = unsafe {
+            libc::setsockopt(
+                self.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// How `Scanner::open` sends and receives frames on an interface
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Backend {
+    /// an AF_PACKET socket, with a syscall for every frame
+    #[default]
+    AfPacket,
+    /// an AF_PACKET socket with TPACKET_V3 memory mapped rings
+    Ring(RingConfig),
+    /// an AF_XDP socket, on an interface with a single receive queue
+    Xdp(XdpConfig),
+}
+
+/// An interface opened with one of the backends
+#[derive(Clone)]
+pub enum Interface {
+    AfPacket(RawPacketStream),
+    Ring(Ring),
+    Xdp(Xdp),
+}
+
+impl Interface {
+    /// Open the interface with the backend. `ports` are the source ports of the scan, the XDP
+    /// backend leaves frames to other ports to the kernel.
+    pub fn open(if_name: &str, backend: &Backend, ports: &RangeInclusive<u16>) -> io::Result<Self> {
+        match backend {
+            Backend::AfPacket => {
+                let mut ps = RawPacketStream::new()?;
+                ps.bind(if_name)?;
+                Ok(Interface::AfPacket(ps))
+            }
+            Backend::Ring(conf) => Ok(Interface::Ring(Ring::bind(if_name, conf)?)),
+            Backend::Xdp(conf) => Ok(Interface::Xdp(Xdp::bind(if_name, conf, ports)?)),
+        }
+    }
+}
+
+impl PacketIo for Interface {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        match self {
+            Interface::AfPacket(ps) => ps.send(frame),
+            Interface::Ring(ring) => ring.send(frame),
+            Interface::Xdp(xdp) => xdp.send(frame),
+        }
+    }
+
+    fn recv(&mut self, frame: &mut [u8]) -> io::Result<Option<usize>> {
+        match self {
+            Interface::AfPacket(ps) => ps.recv(frame),
+            Interface::Ring(ring) => ring.recv(frame),
+            Interface::Xdp(xdp) => xdp.recv(frame),
+        }
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        match self {
+            Interface::AfPacket(ps) => ps.set_recv_timeout(timeout),
+            Interface::Ring(ring) => ring.set_recv_timeout(timeout),
+            Interface::Xdp(xdp) => xdp.set_recv_timeout(timeout),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Interface::AfPacket(ps) => PacketIo::flush(ps),
+            Interface::Ring(ring) => PacketIo::flush(ring),
+            Interface::Xdp(xdp) => xdp.flush(),
+        }
+    }
+}
+
+/// One end of an in-memory link, the frames sent on one end are received on the other
+#[derive(Clone, Debug)]
+pub struct Loopback {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+    timeout: Duration,
+}
+
+impl Loopback {
+    /// Both ends of a new link
+    pub fn pair() -> (Loopback, Loopback) {
+        let (a_sender, b_receiver) = unbounded();
+        let (b_sender, a_receiver) = unbounded();
+        let a = Loopback {
+            sender: a_sender,
+            receiver: a_receiver,
+            timeout: DEFAULT_RECV_TIMEOUT,
+        };
+        let b = Loopback {
+            sender: b_sender,
+            receiver: b_receiver,
+            timeout: DEFAULT_RECV_TIMEOUT,
+        };
+        (a, b)
+    }
+}
+
+impl PacketIo for Loopback {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.sender
+            .send(frame.to_vec())
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "the other end is gone"))
+    }
+
+    fn recv(&mut self, frame: &mut [u8]) -> io::Result<Option<usize>> {
+        match self.receiver.recv_timeout(self.timeout) {
+            Ok(received) => {
+                let len = received.len().min(frame.len());
+                frame[..len].copy_from_slice(&received[..len]);
+                Ok(Some(len))
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                ErrorKind::BrokenPipe,
+                "the other end is gone",
+            )),
+        }
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Reads the ethernet frames of a pcap file
+#[derive(Debug)]
+pub struct PcapReader<R> {
+    reader: R,
+    swapped: bool,
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Read the file header, the capture must be of ethernet frames
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 24];
+        reader.read_exact(&mut header)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let swapped = match magic {
+            PCAP_MAGIC | PCAP_MAGIC_NANOS => false,
+            _ if magic.swap_bytes() == PCAP_MAGIC || magic.swap_bytes() == PCAP_MAGIC_NANOS => true,
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, "not a pcap file")),
+        };
+        let pcap = PcapReader { reader, swapped };
+        if pcap.u32(&header[20..24]) != LINKTYPE_ETHERNET {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "not a capture of ethernet frames",
+            ));
+        }
+        Ok(pcap)
+    }
+
+    /// Read the next frame into `frame` and return its length, or None at the end of the file.
+    /// A frame longer than `frame` is cut short.
+    pub fn read_frame(&mut self, frame: &mut [u8]) -> io::Result<Option<usize>> {
+        let mut header = [0; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let captured = self.u32(&header[8..12]) as usize;
+        let len = captured.min(frame.len());
+        self.reader.read_exact(&mut frame[..len])?;
+        io::copy(
+            &mut (&mut self.reader).take((captured - len) as u64),
+            &mut io::sink(),
+        )?;
+        Ok(Some(len))
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        if self.swapped {
+            value.swap_bytes()
+        } else {
+            value
+        }
+    }
+}
+
+/// Writes ethernet frames to a pcap file
+#[derive(Debug)]
+pub struct PcapWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Write the file header
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        // version 2.4
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // utc offset and timestamp accuracy
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(PcapWriter { writer })
+    }
+
+    /// Append a frame, stamped with the current time
+    pub fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let captured = frame.len().min(PCAP_SNAPLEN as usize);
+        let mut header = Vec::with_capacity(16);
+        header.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        header.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        header.extend_from_slice(&(captured as u32).to_le_bytes());
+        header.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&frame[..captured])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Replays the frames of a pcap file as the received frames and appends the sent frames to
+/// another pcap file. Once the input is exhausted, or without an input, nothing is received.
+#[derive(Clone, Debug)]
+pub struct Pcap {
+    input: Option<Arc<Mutex<PcapReader<BufReader<File>>>>>,
+    output: Arc<Mutex<PcapWriter<BufWriter<File>>>>,
+    timeout: Duration,
+}
+
+impl Pcap {
+    /// Open the file to replay, if any, and create the file to write the sent frames to
+    pub fn open<P: AsRef<Path>>(input: Option<P>, output: P) -> io::Result<Self> {
+        let input = match input {
+            Some(path) => {
+                let reader = PcapReader::new(BufReader::new(File::open(path)?))?;
+                Some(Arc::new(Mutex::new(reader)))
+            }
+            None => None,
+        };
+        let writer = PcapWriter::new(BufWriter::new(File::create(output)?))?;
+        Ok(Pcap {
+            input,
+            output: Arc::new(Mutex::new(writer)),
+            timeout: DEFAULT_RECV_TIMEOUT,
+        })
+    }
+
+    /// Write the buffered frames to the output file
+    pub fn flush(&self) -> io::Result<()> {
+        lock(&self.output)?.flush()
+    }
+}
+
+impl PacketIo for Pcap {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        lock(&self.output)?.write_frame(frame)
+    }
+
+    fn recv(&mut self, frame: &mut [u8]) -> io::Result<Option<usize>> {
+        if let Some(input) = &self.input {
+            if let Some(len) = lock(input)?.read_frame(frame)? {
+                return Ok(Some(len));
+            }
+        }
+        thread::sleep(self.timeout);
+        Ok(None)
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Pcap::flush(self)
+    }
+}
+
+// A poisoned lock means the other thread panicked in the middle of sending or receiving
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> io::Result<MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|_| io::Error::other("a thread panicked while sending or receiving"))
+}
+
+// Wait until the socket is ready for events, returns false on timeout
+pub(crate) fn poll(fd: libc::c_int, events: libc::c_short, timeout: Duration) -> io::Result<bool> {
+    let mut pfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    // SAFETY: pfd is a single valid pollfd
+    let ret = unsafe { libc::poll(&mut pfd, 1, timeout.as_millis() as libc::c_int) };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == ErrorKind::Interrupted {
+            return Ok(false);
+        }
+        return Err(e);
+    }
+    Ok(ret > 0)
+}
@@ -0,0 +1,192 @@
+use siphasher::sip::SipHasher24;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hasher;
+
+// Primes just above powers of two, with the distinct prime factors of p - 1 which are needed to
+// find generators of the multiplicative group modulo p. All of them are below 2^64, so a product
+// of two group elements fits in a u128.
+const GROUPS: &[(u64, &[u64])] = &[
+    (257, &[2]),
+    (65_537, &[2]),
+    (16_777_259, &[2, 23, 103, 3541]),
+    (4_294_967_311, &[2, 3, 5, 131, 364_289]),
+    (1_099_511_627_791, &[2, 3, 5, 36_650_387_593]),
+    (281_474_976_710_677, &[2, 3, 7, 1361, 2_462_081_249]),
+    (72_057_594_037_928_017, &[2, 3, 7, 61, 34_501, 14_557_303]),
+    (
+        18_446_744_073_709_551_557,
+        &[2, 11, 137, 547, 5_594_472_617_641],
+    ),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PermutationError {
+    TooLarge(u128),
+}
+
+impl fmt::Display for PermutationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PermutationError::TooLarge(size) => {
+                write!(f, "Cannot permute {} elements, at most 2^64 - 60", size)
+            }
+        }
+    }
+}
+
+impl Error for PermutationError {}
+
+fn mul_mod(a: u64, b: u64, p: u64) -> u64 {
+    (u128::from(a) * u128::from(b) % u128::from(p)) as u64
+}
+
+fn pow_mod(mut base: u64, mut exp: u64, p: u64) -> u64 {
+    let mut result = 1;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul_mod(result, base, p);
+        }
+        base = mul_mod(base, base, p);
+        exp >>= 1;
+    }
+    result
+}
+
+/// A pseudo random order of the indices 0..size, in the style of ZMap. The indices are walked
+/// through the multiplicative group of integers modulo a prime p > size: starting from a random
+/// element, every step multiplies by a random generator of the group, which visits each of the
+/// p - 1 elements exactly once. Elements larger than size are skipped.
+///
+/// The same seed and size always give the same order, so a scan can be split into shards and
+/// resumed from a position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Permutation {
+    size: u64,
+    prime: u64,
+    generator: u64,
+    first: u64,
+}
+
+impl Permutation {
+    pub fn new(size: u128, seed: u64) -> Result<Self, PermutationError> {
+        let (prime, factors) = GROUPS
+            .iter()
+            .find(|(prime, _)| u128::from(*prime) > size)
+            .ok_or(PermutationError::TooLarge(size))?;
+        let prime = *prime;
+
+        let mut counter = 0;
+        let mut random = || {
+            let mut hasher = SipHasher24::new_with_keys(seed, counter);
+            hasher.write_u64(prime);
+            counter += 1;
+            // an element of the group, 1..p-1
+            1 + hasher.finish() % (prime - 1)
+        };
+
+        // g generates the group if g^((p-1)/q) != 1 for every prime factor q of p - 1
+        let generator = loop {
+            let candidate = random();
+            if factors
+                .iter()
+                .all(|factor| pow_mod(candidate, (prime - 1) / factor, prime) != 1)
+            {
+                break candidate;
+            }
+        };
+
+        Ok(Permutation {
+            size: size as u64,
+            prime,
+            generator,
+            first: random(),
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Walk all indices
+    pub fn iter(&self) -> PermutationIter {
+        self.shard(0, 1)
+    }
+
+    /// Walk the indices of shard `shard` of `num_shards`. The shards are disjoint and together
+    /// cover every index once. Panics if shard is not less than num_shards.
+    pub fn shard(&self, shard: u64, num_shards: u64) -> PermutationIter {
+        assert!(
+            shard < num_shards,
+            "shard must be less than the number of shards"
+        );
+        let group_size = self.prime - 1;
+        // shard i takes every num_shards-th step of the cycle, starting at step i
+        let steps = if shard < group_size {
+            (group_size - shard - 1) / num_shards + 1
+        } else {
+            0
+        };
+        PermutationIter {
+            size: self.size,
+            prime: self.prime,
+            start: mul_mod(
+                self.first,
+                pow_mod(self.generator, shard, self.prime),
+                self.prime,
+            ),
+            step: pow_mod(self.generator, num_shards, self.prime),
+            current: 0,
+            position: 0,
+            steps,
+        }
+        .resume_at(0)
+    }
+}
+
+/// Iterator over the indices of a Permutation, or of one of its shards
+#[derive(Clone, Debug)]
+pub struct PermutationIter {
+    size: u64,
+    prime: u64,
+    start: u64,
+    step: u64,
+    current: u64,
+    position: u64,
+    steps: u64,
+}
+
+impl PermutationIter {
+    /// Continue from a position previously returned by `position`
+    pub fn resume_at(mut self, position: u64) -> Self {
+        self.position = position.min(self.steps);
+        self.current = mul_mod(
+            self.start,
+            pow_mod(self.step, self.position, self.prime),
+            self.prime,
+        );
+        self
+    }
+
+    /// Number of steps taken so far, including the skipped elements. Store it to resume the
+    /// walk later with `resume_at`.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl Iterator for PermutationIter {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        while self.position < self.steps {
+            let element = self.current;
+            self.current = mul_mod(self.current, self.step, self.prime);
+            self.position += 1;
+            if element <= self.size {
+                return Some(element - 1);
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU64;
+use std::time::{Duration, Instant};
+
+/// Bytes of preamble, start of frame delimiter, frame check sequence and inter-frame gap that
+/// every ethernet frame costs on the wire in addition to the bytes we write.
+const ETHERNET_OVERHEAD: usize = 24;
+
+pub const DEFAULT_BURST: u64 = 16;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RateLimit {
+    /// maximum packets per second, unlimited if None
+    pub packets_per_second: Option<NonZeroU64>,
+    /// maximum bandwidth in bits per second, unlimited if None
+    pub bits_per_second: Option<NonZeroU64>,
+    /// number of packets which may be sent back to back once the limiter has been idle
+    pub burst: u64,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            packets_per_second: None,
+            bits_per_second: None,
+            burst: DEFAULT_BURST,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: NonZeroU64, capacity: f64) -> Self {
+        TokenBucket {
+            rate: rate.get() as f64,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    // A cost larger than the bucket can ever hold is allowed once the bucket is full, the
+    // deficit is then paid back before the next packet goes out.
+    fn wait_time(&self, cost: f64) -> Duration {
+        let needed = cost.min(self.capacity) - self.tokens;
+        if needed <= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(needed / self.rate)
+        }
+    }
+}
+
+/// Token bucket limiter enforcing a packets per second and a bits per second cap at the same
+/// time. A packet may only be sent once both buckets hold enough tokens for it.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    packets: Option<TokenBucket>,
+    bits: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: &RateLimit) -> Self {
+        let burst = limit.burst.max(1) as f64;
+        RateLimiter {
+            packets: limit
+                .packets_per_second
+                .map(|pps| TokenBucket::new(pps, burst)),
+            bits: limit.bits_per_second.map(|bps| {
+                TokenBucket::new(
+                    bps,
+                    burst * ((crate::MAX_PACKET_SIZE + ETHERNET_OVERHEAD) * 8) as f64,
+                )
+            }),
+        }
+    }
+
+    pub fn set_limit(&mut self, limit: &RateLimit) {
+        *self = RateLimiter::new(limit);
+    }
+
+    /// Take the tokens for a packet of `len` bytes. If there are not enough tokens yet, nothing
+    /// is taken and the time to wait before trying again is returned.
+    pub fn try_acquire(&mut self, len: usize) -> Result<(), Duration> {
+        let now = Instant::now();
+        let bits = ((len + ETHERNET_OVERHEAD) * 8) as f64;
+
+        let mut wait = Duration::from_secs(0);
+        if let Some(packets) = self.packets.as_mut() {
+            packets.refill(now);
+            wait = wait.max(packets.wait_time(1.0));
+        }
+        if let Some(bucket) = self.bits.as_mut() {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait_time(bits));
+        }
+        if wait > Duration::from_secs(0) {
+            return Err(wait);
+        }
+
+        if let Some(packets) = self.packets.as_mut() {
+            packets.tokens -= 1.0;
+        }
+        if let Some(bucket) = self.bits.as_mut() {
+            bucket.tokens -= bits;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The bits a frame of MAX_PACKET_SIZE bytes costs on the wire
+    const MAX_FRAME_BITS: u64 = ((crate::MAX_PACKET_SIZE + ETHERNET_OVERHEAD) * 8) as u64;
+
+    fn limit(pps: u64, bps: u64, burst: u64) -> RateLimit {
+        RateLimit {
+            packets_per_second: NonZeroU64::new(pps),
+            bits_per_second: NonZeroU64::new(bps),
+            burst,
+        }
+    }
+
+    // The burst goes out back to back, after that the wait is about the time one packet costs
+    fn assert_burst_then_wait(
+        limiter: &mut RateLimiter,
+        len: usize,
+        burst: u64,
+        per_packet: Duration,
+    ) {
+        for i in 0..burst {
+            assert_eq!(
+                limiter.try_acquire(len),
+                Ok(()),
+                "packet {} of the burst",
+                i
+            );
+        }
+        let wait = limiter.try_acquire(len).unwrap_err();
+        assert!(wait <= per_packet, "{:?}", wait);
+        assert!(wait > per_packet * 9 / 10, "{:?}", wait);
+    }
+
+    #[test]
+    fn packets_per_second_test() {
+        let mut limiter = RateLimiter::new(&limit(10, 0, 4));
+        assert_burst_then_wait(&mut limiter, 64, 4, Duration::from_millis(100));
+        // nothing was taken by the refused packet, it still waits
+        assert!(limiter.try_acquire(64).is_err());
+    }
+
+    #[test]
+    fn bits_per_second_test() {
+        // ten full sized frames a second
+        let mut limiter = RateLimiter::new(&limit(0, 10 * MAX_FRAME_BITS, 2));
+        assert_burst_then_wait(
+            &mut limiter,
+            crate::MAX_PACKET_SIZE,
+            2,
+            Duration::from_millis(100),
+        );
+    }
+
+    #[test]
+    fn both_limits_test() {
+        // the packets per second are the tighter limit for small packets
+        let mut limiter = RateLimiter::new(&limit(10, 1000 * MAX_FRAME_BITS, 1));
+        assert_burst_then_wait(&mut limiter, 64, 1, Duration::from_millis(100));
+        // and the bits per second for full sized ones
+        let mut limiter = RateLimiter::new(&limit(1000, 10 * MAX_FRAME_BITS, 1));
+        assert_burst_then_wait(
+            &mut limiter,
+            crate::MAX_PACKET_SIZE,
+            1,
+            Duration::from_millis(100),
+        );
+    }
+
+    #[test]
+    fn unlimited_test() {
+        let mut limiter = RateLimiter::new(&RateLimit::default());
+        for _ in 0..100_000 {
+            assert_eq!(limiter.try_acquire(crate::MAX_PACKET_SIZE), Ok(()));
+        }
+    }
+
+    #[test]
+    fn set_limit_test() {
+        let mut limiter = RateLimiter::new(&limit(10, 0, 1));
+        assert_eq!(limiter.try_acquire(64), Ok(()));
+        assert!(limiter.try_acquire(64).is_err());
+
+        // lifting the limit lets the waiting packet go at once
+        limiter.set_limit(&RateLimit::default());
+        for _ in 0..1000 {
+            assert_eq!(limiter.try_acquire(64), Ok(()));
+        }
+
+        // a new limit starts with a full burst at its own rate
+        limiter.set_limit(&limit(5, 0, 3));
+        assert_burst_then_wait(&mut limiter, 64, 3, Duration::from_millis(200));
+    }
+}
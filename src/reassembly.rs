@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+
+/// The bytes a host sent on a connection, put back in order from the segments they arrived in.
+/// Segments ahead of the next expected sequence number are held until the gap before them is
+/// filled. Nothing beyond the capture size is kept.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Hash)]
+pub struct Stream {
+    data: Vec<u8>,
+    next_seq: u32,
+    max_len: usize,
+    // segments which arrived ahead of next_seq
+    pending: Vec<(u32, Vec<u8>)>,
+    // end of the last segment with PSH or FIN
+    push_end: Option<u32>,
+}
+
+// How far a is ahead of b, taking wrap around into account
+fn seq_diff(a: u32, b: u32) -> i32 {
+    a.wrapping_sub(b) as i32
+}
+
+impl Stream {
+    /// A stream whose first byte has the sequence number `seq`, capturing up to `max_len` bytes
+    pub fn new(seq: u32, max_len: usize) -> Self {
+        Stream {
+            data: vec![],
+            next_seq: seq,
+            max_len,
+            pending: vec![],
+            push_end: None,
+        }
+    }
+
+    /// Add a segment, `push` is set if it had PSH or FIN. Returns whether any bytes were added
+    /// to the data in order.
+    pub fn insert(&mut self, seq: u32, payload: &[u8], push: bool) -> bool {
+        if push {
+            self.push_end = Some(seq.wrapping_add(payload.len() as u32));
+        }
+        if payload.is_empty() || self.is_full() {
+            return false;
+        }
+        let offset = seq_diff(seq, self.next_seq);
+        if offset > 0 {
+            let duplicate = self.pending.iter().any(|(s, _)| *s == seq);
+            let pending_len: usize = self.pending.iter().map(|(_, p)| p.len()).sum();
+            if !duplicate
+                && (offset as usize) < self.window()
+                && pending_len + payload.len() <= self.max_len
+            {
+                self.pending.push((seq, payload.to_vec()));
+            }
+            return false;
+        }
+        let added = self.append(seq, payload);
+        // the segment may have filled the gap before segments which arrived early
+        while let Some(i) = self
+            .pending
+            .iter()
+            .position(|(s, _)| seq_diff(*s, self.next_seq) <= 0)
+        {
+            let (seq, payload) = self.pending.swap_remove(i);
+            self.append(seq, &payload);
+        }
+        if self.is_full() {
+            self.pending.clear();
+        }
+        added
+    }
+
+    // Append the part of a segment starting at or before next_seq which is new
+    fn append(&mut self, seq: u32, payload: &[u8]) -> bool {
+        let skip = seq_diff(self.next_seq, seq) as usize;
+        if skip >= payload.len() {
+            return false;
+        }
+        let len = (payload.len() - skip).min(self.window());
+        self.data.extend_from_slice(&payload[skip..skip + len]);
+        self.next_seq = self.next_seq.wrapping_add(len as u32);
+        len > 0
+    }
+
+    /// The data received in order so far
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Take the data received in order so far
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.data)
+    }
+
+    /// Sequence number of the next byte expected, which is what we acknowledge
+    pub fn next_seq(&self) -> u32 {
+        self.next_seq
+    }
+
+    /// Number of bytes which can still be captured
+    pub fn window(&self) -> usize {
+        self.max_len.saturating_sub(self.data.len())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.window() == 0
+    }
+
+    /// Everything up to the last segment with PSH or FIN has arrived, which is where a reply
+    /// usually ends
+    pub fn pushed(&self) -> bool {
+        match self.push_end {
+            Some(end) => seq_diff(end, self.next_seq) <= 0,
+            None => false,
+        }
+    }
+}
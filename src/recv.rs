@@ -1,179 +1,775 @@
-use super::handshake::Handshake;
+use super::handshake::{Handshake, Handshakes};
 use super::packet;
-use crate::packet::build_tcp_response;
-use crate::{ScanConfig, ScanResult, TcpFlags, MAX_PACKET_SIZE};
-use afpacket::sync::RawPacketStream;
+use crate::hosts::{Host, HostTable, Hostnames, State};
+use crate::packet::{build_tcp_ack, build_tcp_reset, build_tcp_response, build_tcp_teardown};
+use crate::packet_io::{PacketIo, DEFAULT_RECV_TIMEOUT};
+use crate::reassembly::Stream;
+use crate::stats::{self, ScanStats};
+use crate::tls;
+use crate::validate::Validator;
+use crate::{
+    icmp, udp, PortState, ScanConfig, ScanError, ScanResult, Target, TcpFlags, Teardown,
+    MAX_PACKET_SIZE,
+};
 use crossbeam_channel::Sender;
-use etherparse::{ip_number, InternetSlice, SlicedPacket, TransportSlice};
-use memchr::memmem;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io::prelude::*;
+use etherparse::{
+    ip_number, InternetSlice, PacketBuilder, SlicedPacket, TcpHeaderSlice, TransportSlice,
+};
+use std::collections::BTreeMap;
 use std::net::IpAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(Clone, Debug, Serialize, Deserialize, Hash, Eq, PartialEq)]
-struct Host {
-    ip: IpAddr,
-    port: u16,
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize, Hash)]
-struct State {
-    handshakes_attempted: usize,
-    tcp_flags: TcpFlags,
-}
+/// How long the state of a connection is kept without hearing from the host
+pub const DEFAULT_HOST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum number of connections tracked at once
+pub const DEFAULT_MAX_HOSTS: usize = 1_000_000;
+/// Bytes of a reply kept by default, enough for the certificate chains of most tls servers
+pub const DEFAULT_MAX_CAPTURE: usize = 64 * 1024;
 
-pub fn start_rx(
-    mut rx: RawPacketStream,
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn start_rx<P: PacketIo>(
+    mut rx: P,
     conf: ScanConfig,
-    handshakes: Vec<Handshake>,
+    stats: Arc<ScanStats>,
+    hostnames: Arc<Hostnames>,
+    handshakes: Handshakes,
     response_sender: Sender<Vec<u8>>,
+    answered_sender: Sender<u32>,
     results_sender: Sender<ScanResult>,
     shutdown: Arc<AtomicBool>,
-) {
+) -> Result<(), ScanError> {
     let mut recv_pkt = [0; MAX_PACKET_SIZE];
-    let mut resp_pkt = [0; MAX_PACKET_SIZE];
-    let mut host_state: HashMap<Host, State> = HashMap::new();
+    let mut responses = vec![];
+    let mut host_state = HostTable::new(conf.host_timeout, conf.max_hosts, hostnames);
+    let validator = Validator::new(&conf.secret);
+    rx.set_recv_timeout(DEFAULT_RECV_TIMEOUT)?;
 
     loop {
         if shutdown.load(Ordering::Relaxed) {
-            break;
-        }
-        let len = rx.read(&mut recv_pkt).expect("failed to read pkt");
-        if let Some((result, resp_len)) = handle_packet(
-            &conf,
-            &recv_pkt[..len],
-            &mut resp_pkt,
-            &handshakes,
-            &mut host_state,
-        ) {
-            results_sender.send(result).expect("failed to send result");
-            if resp_len > 0 {
-                response_sender
-                    .send(resp_pkt[..resp_len].into())
-                    .expect("failed to send response packet");
+            return Ok(());
+        }
+        for (host, state) in host_state.expire() {
+            stats::increment(&stats.hosts_expired);
+            responses.extend(teardown_pkt(&conf, &host, &state));
+            send_timeout_result(&results_sender, &handshakes, host, state)?;
+        }
+        // hosts which did not send a banner get the request of the first handshake
+        for host in host_state.banner_waits_over() {
+            if let Some(state) = host_state.get_mut(&host) {
+                if let Some((request, request_len)) = state.pending_request.take() {
+                    log::debug!("no banner from {}:{}", host.ip, host.port);
+                    state.handshakes_attempted += 1;
+                    state.local_seq = state.local_seq.wrapping_add(request_len);
+                    responses.push(request);
+                }
+            }
+        }
+
+        // without a frame, the loop comes around to the expired connections anyway
+        if let Some(len) = rx.recv(&mut recv_pkt)? {
+            if let Some(result) = handle_packet(
+                &conf,
+                &validator,
+                &stats,
+                &recv_pkt[..len],
+                &handshakes,
+                &mut host_state,
+                &mut responses,
+            ) {
+                // the target answered, the tx thread stops retransmitting its SYN
+                if conf.retransmit.probes > 1
+                    && result.transport_protocol == u8::from(ip_number::TCP)
+                {
+                    let target = Target {
+                        ip: result.ip,
+                        port: result.port,
+                        ip_number: result.transport_protocol,
+                        data: None,
+                        hostname: None,
+                    };
+                    if let Ok(cookie) = target.tcp_cookie(&conf, &validator) {
+                        answered_sender
+                            .send(cookie)
+                            .map_err(|_| ScanError::Stopped)?;
+                    }
+                }
+                results_sender
+                    .send(result)
+                    .map_err(|_| ScanError::Stopped)?;
             }
         }
+        for response in responses.drain(..) {
+            response_sender
+                .send(response)
+                .map_err(|_| ScanError::Stopped)?;
+        }
+
+        for (host, state) in host_state.take_evicted() {
+            stats::increment(&stats.hosts_evicted);
+            responses.extend(teardown_pkt(&conf, &host, &state));
+            send_timeout_result(&results_sender, &handshakes, host, state)?;
+        }
+    }
+}
+
+// The packet closing a connection we give up on. There is no packet of the target to answer, so
+// it is built from what we know about the connection.
+fn teardown_pkt(conf: &ScanConfig, host: &Host, state: &State) -> Option<Vec<u8>> {
+    if state.finished || state.tcp_flags == TcpFlags::Syn {
+        return None;
+    }
+    let pkt_builder = PacketBuilder::ethernet2(conf.src_mac, conf.dst_mac);
+    let pkt_builder = match (host.local_ip, host.ip) {
+        (IpAddr::V4(local_ip), IpAddr::V4(ip)) => {
+            pkt_builder.ipv4(local_ip.octets(), ip.octets(), 20)
+        }
+        (IpAddr::V6(local_ip), IpAddr::V6(ip)) => {
+            pkt_builder.ipv6(local_ip.octets(), ip.octets(), 20)
+        }
+        _ => return None,
+    };
+    let pkt_builder = pkt_builder.tcp(host.local_port, host.port, state.local_seq, 0);
+    let pkt_builder = match conf.teardown {
+        Teardown::Rst => pkt_builder.rst(),
+        Teardown::Fin => pkt_builder.fin(),
     }
+    .ack(state.stream.next_seq());
+    let mut pkt = Vec::with_capacity(pkt_builder.size(0));
+    pkt_builder.write(&mut pkt, &[]).ok()?;
+    Some(pkt)
+}
+
+// Report a connection which was given up on before the host answered our handshake, or before
+// its reply was complete. Whatever part of the reply arrived is matched against the handshakes.
+fn send_timeout_result(
+    results_sender: &Sender<ScanResult>,
+    handshakes: &Handshakes,
+    host: Host,
+    mut state: State,
+) -> Result<(), ScanError> {
+    if state.finished {
+        return Ok(());
+    }
+    log::debug!(
+        "no complete handshake response from {}:{}",
+        host.ip,
+        host.port
+    );
+    let handshakes = handshakes.for_port(host.port);
+    let data = state.stream.take();
+    let (service, captures) = match find_service(handshakes, &data) {
+        Some((service, captures)) => (Some(service), captures),
+        None => (None, BTreeMap::new()),
+    };
+    let scan_result = ScanResult {
+        ip: host.ip,
+        port: host.port,
+        transport_protocol: u8::from(ip_number::TCP),
+        service,
+        probes: attempted_probes(handshakes, &state),
+        captures,
+        tls: None,
+        tcp_flags: Some(state.tcp_flags),
+        port_state: Some(PortState::Open),
+        icmp_type: None,
+        rtt: None,
+        timed_out: true,
+        data,
+    };
+    results_sender
+        .send(scan_result)
+        .map_err(|_| ScanError::Stopped)
 }
 
 fn handle_packet(
     conf: &ScanConfig,
+    validator: &Validator,
+    stats: &ScanStats,
     recvd_pkt: &[u8],
-    resp_pkt: &mut [u8],
-    handshakes: &[Handshake],
-    host_state: &mut HashMap<Host, State>,
-) -> Option<(ScanResult, usize)> {
-    match SlicedPacket::from_ethernet(&recvd_pkt) {
+    handshakes: &Handshakes,
+    host_state: &mut HostTable,
+    responses: &mut Vec<Vec<u8>>,
+) -> Option<ScanResult> {
+    match SlicedPacket::from_ethernet(recvd_pkt) {
         Err(e) => {
-            //log::error!("Error parsing packet error: {:?}", e);
-            //log::error!("Error parsing packet recvd_pkt {:?}", recvd_pkt);
+            log::debug!("failed to parse packet: {:?}", e);
             None
         }
         Ok(value) => {
-            let ip = match &value.ip.as_ref()? {
-                InternetSlice::Ipv4(slice) => IpAddr::V4(slice.header().source_addr()),
-                InternetSlice::Ipv6(slice) => IpAddr::V6(slice.header().source_addr()),
+            let (ip, local_ip) = match &value.ip.as_ref()? {
+                InternetSlice::Ipv4(slice) => (
+                    IpAddr::V4(slice.header().source_addr()),
+                    IpAddr::V4(slice.header().destination_addr()),
+                ),
+                InternetSlice::Ipv6(slice) => (
+                    IpAddr::V6(slice.header().source_addr()),
+                    IpAddr::V6(slice.header().destination_addr()),
+                ),
             };
             let transport = value.transport.as_ref()?;
             match transport {
-                TransportSlice::Icmpv4(_)
-                | TransportSlice::Icmpv6(_)
-                | TransportSlice::Unknown(_)
-                | TransportSlice::Udp(_) => None,
+                TransportSlice::Icmpv4(icmp) => match icmp.type_u8() {
+                    icmp::ICMPV4_ECHO_REPLY => handle_echo_reply(
+                        validator,
+                        stats,
+                        ip,
+                        local_ip,
+                        u8::from(ip_number::ICMP),
+                        icmp.type_u8(),
+                        icmp.bytes5to8(),
+                        icmp.payload(),
+                    ),
+                    icmp::ICMPV4_DEST_UNREACHABLE => handle_unreachable(
+                        conf,
+                        validator,
+                        stats,
+                        local_ip,
+                        icmp.type_u8(),
+                        icmp.payload(),
+                        icmp::icmpv4_unreachable_port_state(icmp.code_u8()),
+                    ),
+                    _ => None,
+                },
+                TransportSlice::Icmpv6(icmp) => match icmp.type_u8() {
+                    icmp::ICMPV6_ECHO_REPLY => handle_echo_reply(
+                        validator,
+                        stats,
+                        ip,
+                        local_ip,
+                        u8::from(ip_number::IPV6_ICMP),
+                        icmp.type_u8(),
+                        icmp.bytes5to8(),
+                        icmp.payload(),
+                    ),
+                    icmp::ICMPV6_DEST_UNREACHABLE => handle_unreachable(
+                        conf,
+                        validator,
+                        stats,
+                        local_ip,
+                        icmp.type_u8(),
+                        icmp.payload(),
+                        icmp::icmpv6_unreachable_port_state(icmp.code_u8()),
+                    ),
+                    _ => None,
+                },
+                TransportSlice::Unknown(_) => None,
+                TransportSlice::Udp(udp) => {
+                    if !conf.src_ports.contains(&udp.destination_port()) {
+                        return None;
+                    }
+                    // there is no sequence number to check, so the source port we picked for
+                    // the target is all we can validate
+                    if validator.src_port(ip, udp.source_port(), &conf.src_ports)
+                        != udp.destination_port()
+                    {
+                        log::debug!(
+                            "dropping unvalidated response from {}:{}",
+                            ip,
+                            udp.source_port()
+                        );
+                        stats::increment(&stats.validation_failed);
+                        return None;
+                    }
+                    packet::log_response(&value);
+                    let scan_result = ScanResult {
+                        ip,
+                        port: udp.source_port(),
+                        transport_protocol: u8::from(ip_number::UDP),
+                        service: udp::default_probe(udp.source_port())
+                            .map(|probe| probe.service.to_string()),
+                        probes: vec![],
+                        captures: BTreeMap::new(),
+                        tls: None,
+                        tcp_flags: None,
+                        port_state: Some(PortState::Open),
+                        icmp_type: None,
+                        rtt: None,
+                        timed_out: false,
+                        data: value.payload.into(),
+                    };
+                    Some(scan_result)
+                }
                 TransportSlice::Tcp(tcp) => {
-                    if tcp.destination_port() != conf.src_port {
+                    if !conf.src_ports.contains(&tcp.destination_port()) {
+                        return None;
+                    }
+                    // the response must come back to the source port we picked for this target.
+                    // a SYN-ACK must acknowledge exactly our SYN, anything else must at least
+                    // acknowledge the SYN and fall within what we could have sent after it
+                    let valid = if validator.src_port(ip, tcp.source_port(), &conf.src_ports)
+                        != tcp.destination_port()
+                    {
+                        false
+                    } else if tcp.syn() {
+                        validator.check_synack(
+                            local_ip,
+                            ip,
+                            tcp.destination_port(),
+                            tcp.source_port(),
+                            tcp.acknowledgment_number(),
+                        )
+                    } else {
+                        validator.check_ack(
+                            local_ip,
+                            ip,
+                            tcp.destination_port(),
+                            tcp.source_port(),
+                            tcp.acknowledgment_number(),
+                        )
+                    };
+                    if !valid || !tcp.ack() {
+                        log::debug!(
+                            "dropping unvalidated response from {}:{}",
+                            ip,
+                            tcp.source_port()
+                        );
+                        stats::increment(&stats.validation_failed);
                         return None;
                     }
                     packet::log_response(&value);
                     let host = Host {
                         ip,
                         port: tcp.source_port(),
+                        local_ip,
+                        local_port: tcp.destination_port(),
                     };
-                    let mut resp_len = 0;
-                    if tcp.syn() && tcp.ack() {
-                        let scan_result = ScanResult {
-                            ip,
-                            port: tcp.source_port(),
-                            transport_protocol: u8::from(ip_number::TCP),
-                            service: None,
-                            tcp_flags: Some(TcpFlags::Synack),
-                            data: vec![],
-                        };
+                    handle_tcp(
+                        conf, validator, &value, host, handshakes, host_state, responses,
+                    )
+                }
+            }
+        }
+    }
+}
 
-                        // have we tried to scan this host previously?
-                        match host_state.get_mut(&host) {
-                            // if so, try next handshake,
-                            Some(state) => {
-                                // this shouldn't be oob, check if we're out of hs in the ack section
-                                let _next_handshake = &handshakes[state.handshakes_attempted];
-                                state.handshakes_attempted += 1;
-                                state.tcp_flags = TcpFlags::Synack;
-                            }
-                            // if not, try first handshake
-                            None => {
-                                let next_handshake = &handshakes[0];
-                                let state = State {
-                                    handshakes_attempted: 1,
-                                    tcp_flags: TcpFlags::Synack,
-                                };
-                                host_state.insert(host, state);
-                                resp_len =
-                                    build_tcp_response(&value, &next_handshake.request, resp_pkt)
-                                        .expect("failed to build tcp response");
-                            }
-                        }
-                        Some((scan_result, resp_len))
-                    } else if tcp.ack() {
-                        log::info!("recv ack");
-
-                        let mut scan_result = ScanResult {
-                            ip,
-                            port: tcp.source_port(),
-                            transport_protocol: u8::from(ip_number::TCP),
-                            service: None,
-                            tcp_flags: Some(TcpFlags::Ack),
-                            data: value.payload.into(),
-                        };
-                        // check handshake responses to see if any match
-                        let payload = value.payload;
-                        for h in handshakes {
-                            log::info!("checking service {}", &h.service);
-                            log::info!("checking {:x?} is in {:x?}", &h.response, payload);
-                            if memmem::find(payload, &h.response).is_some() {
-                                log::info!("match for service {}", &h.service);
-                                scan_result.service = Some(h.service.clone());
-                                break;
-                            }
-                        }
-
-                        Some((scan_result, resp_len))
-                    } else if tcp.rst() {
-                        let scan_result = ScanResult {
-                            ip,
-                            port: tcp.source_port(),
-                            transport_protocol: u8::from(ip_number::TCP),
-                            service: None,
-                            tcp_flags: Some(TcpFlags::Rst),
-                            data: vec![],
+// Names of the handshakes tried on a connection so far
+fn attempted_probes(handshakes: &[Arc<Handshake>], state: &State) -> Vec<String> {
+    handshakes
+        .iter()
+        .take(state.handshakes_attempted)
+        .map(|h| h.service.clone())
+        .collect()
+}
+
+// The service of the first handshake which recognises a response, with what its matcher captured
+fn find_service(
+    handshakes: &[Arc<Handshake>],
+    response: &[u8],
+) -> Option<(String, BTreeMap<String, String>)> {
+    handshakes.iter().find_map(|h| {
+        log::debug!("checking service {}", &h.service);
+        h.find(response)
+            .map(|(service, captures)| (service.to_string(), captures))
+    })
+}
+
+// Walk a validated tcp response through the handshakes. Every SYN-ACK gets the next handshake.
+// The reply to a handshake is put together from its segments until it is complete. If the target
+// resets the connection or answers with something no handshake recognises, the connection is
+// reset and opened again with a new SYN for the next handshake, until one matches or all were
+// tried. The handshakes are tried in the order picked for the port.
+fn handle_tcp(
+    conf: &ScanConfig,
+    validator: &Validator,
+    value: &SlicedPacket,
+    host: Host,
+    handshakes: &Handshakes,
+    host_state: &mut HostTable,
+    responses: &mut Vec<Vec<u8>>,
+) -> Option<ScanResult> {
+    let tcp = match value.transport.as_ref()? {
+        TransportSlice::Tcp(tcp) => tcp,
+        _ => return None,
+    };
+    let handshakes = handshakes.for_port(host.port);
+    let mut resp_pkt = [0; MAX_PACKET_SIZE];
+    let mut scan_result = ScanResult {
+        ip: host.ip,
+        port: host.port,
+        transport_protocol: u8::from(ip_number::TCP),
+        service: None,
+        probes: vec![],
+        captures: BTreeMap::new(),
+        tls: None,
+        tcp_flags: None,
+        port_state: Some(PortState::Open),
+        icmp_type: None,
+        rtt: None,
+        timed_out: false,
+        data: vec![],
+    };
+
+    if tcp.syn() {
+        scan_result.tcp_flags = Some(TcpFlags::Synack);
+        let (state, new_connection) = match host_state.get_mut(&host) {
+            // a SYN-ACK to the SYN we sent after giving up on the previous handshake
+            Some(state) if state.tcp_flags == TcpFlags::Syn => (state, false),
+            // the answer to a retransmitted SYN of a connection we are done with, which would
+            // be left half-open
+            Some(state) if state.finished => {
+                let reset_len = build_tcp_reset(value, &mut resp_pkt)?;
+                responses.push(resp_pkt[..reset_len].to_vec());
+                return None;
+            }
+            // a retransmission of a SYN-ACK we already answered, or the answer to a
+            // retransmitted SYN
+            Some(_) => return None,
+            None => {
+                let hostname = host_state.take_hostname(&host);
+                if handshakes.is_empty() {
+                    // remember the port, so that the answers to the other SYNs are dropped
+                    if conf.retransmit.probes > 1 {
+                        let state = State {
+                            finished: true,
+                            ..State::new(None)
                         };
-                        // have we tried to scan this host previously, and received a synack at some point?
-                        match host_state.get_mut(&host) {
-                            // if so, re-enqueue for syn scan and try next handshake,
-                            Some(_state) => {
-                                //
-                            }
-                            // if not, don't try to scan
-                            None => {}
-                        }
-                        Some((scan_result, resp_len))
-                    } else {
-                        None
+                        host_state.insert(host, state);
                     }
+                    return Some(scan_result);
+                }
+                host_state.insert(host.clone(), State::new(hostname));
+                (host_state.get_mut(&host)?, true)
+            }
+        };
+        let handshake = handshakes.get(state.handshakes_attempted)?;
+        state.tcp_flags = TcpFlags::Synack;
+        // the reply starts right after the SYN of the target
+        state.stream = Stream::new(tcp.sequence_number().wrapping_add(1), conf.max_capture);
+        let request = handshake.render_request(host.ip, host.port, state.hostname.as_deref());
+        // the request only counts once it is sent
+        state.local_seq = tcp.acknowledgment_number();
+        let resp_len = match build_tcp_response(value, &request, &mut resp_pkt) {
+            Some(resp_len) => resp_len,
+            None => {
+                log::error!("failed to build the request to {}:{}", host.ip, host.port);
+                return if new_connection {
+                    Some(scan_result)
+                } else {
+                    None
+                };
+            }
+        };
+        match conf.banner_wait {
+            // give the service the chance to speak first, the request is only sent if it doesn't
+            Some(banner_wait) if new_connection => {
+                state.pending_request = Some((resp_pkt[..resp_len].to_vec(), request.len() as u32));
+                if let Some(ack_len) = build_tcp_response(value, &[], &mut resp_pkt) {
+                    responses.push(resp_pkt[..ack_len].to_vec());
+                }
+                host_state.wait_for_banner(host, banner_wait);
+            }
+            _ => {
+                state.handshakes_attempted += 1;
+                state.local_seq = state.local_seq.wrapping_add(request.len() as u32);
+                responses.push(resp_pkt[..resp_len].to_vec());
+            }
+        }
+        // the port is reported open once, not for every handshake
+        return if new_connection {
+            Some(scan_result)
+        } else {
+            None
+        };
+    }
+
+    if tcp.rst() {
+        scan_result.tcp_flags = Some(TcpFlags::Rst);
+        let state = match host_state.get_mut(&host) {
+            Some(state) if !state.finished => state,
+            Some(_) => return None,
+            None => {
+                // remember the port, so that the answers to the other SYNs are dropped
+                if conf.retransmit.probes > 1 {
+                    let state = State {
+                        tcp_flags: TcpFlags::Rst,
+                        finished: true,
+                        ..State::new(None)
+                    };
+                    host_state.insert(host, state);
                 }
+                scan_result.port_state = Some(PortState::Closed);
+                return Some(scan_result);
             }
+        };
+        state.pending_request = None;
+        if state.tcp_flags != TcpFlags::Syn && state.handshakes_attempted < handshakes.len() {
+            log::debug!(
+                "{}:{} reset the connection, trying the next handshake",
+                host.ip,
+                host.port
+            );
+            state.tcp_flags = TcpFlags::Syn;
+            responses.push(syn_pkt(conf, validator, &host, &mut resp_pkt)?);
+            return None;
         }
+        // a reset instead of a SYN-ACK means the port was closed in the meantime
+        if state.tcp_flags == TcpFlags::Syn {
+            scan_result.port_state = Some(PortState::Closed);
+        }
+        state.finished = true;
+        scan_result.probes = attempted_probes(handshakes, state);
+        return Some(scan_result);
     }
+
+    let payload = value.payload;
+    scan_result.tcp_flags = Some(TcpFlags::Ack);
+    let state = match host_state.get_mut(&host) {
+        Some(state) if !state.finished => state,
+        // the rest of a response we already reported
+        Some(_) => {
+            answer_closed(value, tcp, responses);
+            return None;
+        }
+        None if payload.is_empty() => {
+            answer_closed(value, tcp, responses);
+            return None;
+        }
+        // a connection we don't track, the segment is all there is to report
+        None => {
+            if let Some((service, captures)) = find_service(handshakes, payload) {
+                log::info!("match for service {}", service);
+                scan_result.service = Some(service);
+                scan_result.captures = captures;
+            }
+            scan_result.data = payload.into();
+            return Some(scan_result);
+        }
+    };
+    // the connection was given up on, a new SYN is on its way
+    if state.tcp_flags == TcpFlags::Syn {
+        return None;
+    }
+    // a banner, the request we held back is not needed anymore
+    if !payload.is_empty() {
+        state.pending_request = None;
+    }
+    state
+        .stream
+        .insert(tcp.sequence_number(), payload, tcp.psh() || tcp.fin());
+    if !payload.is_empty() {
+        let window = state.stream.window().min(usize::from(u16::MAX)) as u16;
+        let ack_len = build_tcp_ack(value, state.stream.next_seq(), window, &mut resp_pkt)?;
+        responses.push(resp_pkt[..ack_len].to_vec());
+    }
+
+    // the reply to a ClientHello is complete with the first flight of the server, anything else
+    // once the target pushed what it had to send
+    let sent = state.handshakes_attempted.checked_sub(1);
+    let tls_handshake = sent
+        .and_then(|i| handshakes.get(i))
+        .filter(|h| h.tls.is_some());
+    if let Some(handshake) = tls_handshake {
+        let info = match tls::parse_server_flight(state.stream.data()) {
+            Ok(flight) if flight.complete || state.stream.is_full() => Some(flight.info),
+            Ok(_) => return None,
+            // the server speaks tls, but one of its complete handshake messages is broken, which
+            // no further data mends
+            Err(e @ tls::TlsError::Malformed(_)) => {
+                log::debug!("unreadable tls reply from {}:{}: {}", host.ip, host.port, e);
+                Some(tls::TlsInfo {
+                    error: Some(e.to_string()),
+                    ..Default::default()
+                })
+            }
+            Err(e) => {
+                log::debug!("reply to ClientHello is not tls: {}", e);
+                None
+            }
+        };
+        if let Some(info) = info {
+            state.finished = true;
+            state.tcp_flags = TcpFlags::Ack;
+            scan_result.service = Some(handshake.service.clone());
+            scan_result.tls = Some(info);
+            scan_result.probes = attempted_probes(handshakes, state);
+            scan_result.data = state.stream.take();
+            teardown(conf, value, tcp, state, responses);
+            return Some(scan_result);
+        }
+    }
+    if !state.stream.pushed() && !state.stream.is_full() {
+        return None;
+    }
+    if state.stream.data().is_empty() {
+        return None;
+    }
+    scan_result.data = state.stream.take();
+
+    if let Some((service, captures)) = find_service(handshakes, &scan_result.data) {
+        log::info!("match for service {}", service);
+        scan_result.service = Some(service);
+        scan_result.captures = captures;
+    }
+    state.tcp_flags = TcpFlags::Ack;
+    if scan_result.service.is_none() && state.handshakes_attempted < handshakes.len() {
+        log::debug!(
+            "unrecognised response from {}:{}, trying the next handshake",
+            host.ip,
+            host.port
+        );
+        state.tcp_flags = TcpFlags::Syn;
+        let reset_len = build_tcp_reset(value, &mut resp_pkt)?;
+        responses.push(resp_pkt[..reset_len].to_vec());
+        responses.push(syn_pkt(conf, validator, &host, &mut resp_pkt)?);
+        return None;
+    }
+    state.finished = true;
+    scan_result.probes = attempted_probes(handshakes, state);
+    teardown(conf, value, tcp, state, responses);
+    Some(scan_result)
+}
+
+// Close a connection once the reply of the target was captured
+fn teardown(
+    conf: &ScanConfig,
+    value: &SlicedPacket,
+    tcp: &TcpHeaderSlice,
+    state: &State,
+    responses: &mut Vec<Vec<u8>>,
+) {
+    let mut ack = state.stream.next_seq();
+    // a FIN right at the end of the reply takes up a sequence number as well
+    let end = tcp
+        .sequence_number()
+        .wrapping_add(value.payload.len() as u32);
+    if tcp.fin() && end == ack {
+        ack = ack.wrapping_add(1);
+    }
+    let mut pkt = [0; MAX_PACKET_SIZE];
+    if let Some(len) = build_tcp_teardown(value, conf.teardown, ack, &mut pkt) {
+        responses.push(pkt[..len].to_vec());
+    }
+}
+
+// Answer a packet on a connection we are done with. The FIN of the target is acknowledged so it
+// can close its side, and a target which keeps sending is reset.
+fn answer_closed(value: &SlicedPacket, tcp: &TcpHeaderSlice, responses: &mut Vec<Vec<u8>>) {
+    let mut pkt = [0; MAX_PACKET_SIZE];
+    let len = if tcp.fin() {
+        let ack = tcp
+            .sequence_number()
+            .wrapping_add(value.payload.len() as u32 + 1);
+        build_tcp_ack(value, ack, 0, &mut pkt)
+    } else if !value.payload.is_empty() {
+        build_tcp_reset(value, &mut pkt)
+    } else {
+        None
+    };
+    if let Some(len) = len {
+        responses.push(pkt[..len].to_vec());
+    }
+}
+
+// A new SYN to the remote end of a connection
+fn syn_pkt(
+    conf: &ScanConfig,
+    validator: &Validator,
+    host: &Host,
+    pkt: &mut [u8],
+) -> Option<Vec<u8>> {
+    let target = Target {
+        ip: host.ip,
+        port: host.port,
+        ip_number: u8::from(ip_number::TCP),
+        data: None,
+        hostname: None,
+    };
+    match target.to_pkt(pkt, conf, validator) {
+        Ok(len) => Some(pkt[..len].to_vec()),
+        Err(e) => {
+            log::error!("failed to build syn for {}:{}: {}", host.ip, host.port, e);
+            None
+        }
+    }
+}
+
+// Match the probe quoted in an ICMP destination unreachable message to one we sent, and report
+// the target port as closed or filtered
+fn handle_unreachable(
+    conf: &ScanConfig,
+    validator: &Validator,
+    stats: &ScanStats,
+    local_ip: IpAddr,
+    icmp_type: u8,
+    icmp_payload: &[u8],
+    port_state: PortState,
+) -> Option<ScanResult> {
+    let probe = icmp::parse_quoted_probe(icmp_payload)?;
+    if probe.src_ip != local_ip || !conf.src_ports.contains(&probe.src_port) {
+        return None;
+    }
+    let seq_valid = match probe.tcp_seq {
+        Some(seq) => {
+            seq == validator.tcp_seq(local_ip, probe.dst_ip, probe.src_port, probe.dst_port)
+        }
+        None => true,
+    };
+    if !seq_valid
+        || validator.src_port(probe.dst_ip, probe.dst_port, &conf.src_ports) != probe.src_port
+    {
+        log::debug!(
+            "dropping unvalidated unreachable for {}:{}",
+            probe.dst_ip,
+            probe.dst_port
+        );
+        stats::increment(&stats.validation_failed);
+        return None;
+    }
+
+    let scan_result = ScanResult {
+        ip: probe.dst_ip,
+        port: probe.dst_port,
+        transport_protocol: probe.ip_number,
+        service: None,
+        probes: vec![],
+        captures: BTreeMap::new(),
+        tls: None,
+        tcp_flags: None,
+        port_state: Some(port_state),
+        icmp_type: Some(icmp_type),
+        rtt: None,
+        timed_out: false,
+        data: vec![],
+    };
+    Some(scan_result)
+}
+
+// Validate the identifier and sequence number of an echo reply and report the host as alive
+#[allow(clippy::too_many_arguments)]
+fn handle_echo_reply(
+    validator: &Validator,
+    stats: &ScanStats,
+    ip: IpAddr,
+    local_ip: IpAddr,
+    transport_protocol: u8,
+    icmp_type: u8,
+    bytes5to8: [u8; 4],
+    icmp_payload: &[u8],
+) -> Option<ScanResult> {
+    let id = u16::from_be_bytes([bytes5to8[0], bytes5to8[1]]);
+    let seq = u16::from_be_bytes([bytes5to8[2], bytes5to8[3]]);
+    if icmp::echo_id_seq(validator.echo_cookie(local_ip, ip)) != (id, seq) {
+        log::debug!("dropping unvalidated echo reply from {}", ip);
+        stats::increment(&stats.validation_failed);
+        return None;
+    }
+
+    let scan_result = ScanResult {
+        ip,
+        port: 0,
+        transport_protocol,
+        service: None,
+        probes: vec![],
+        captures: BTreeMap::new(),
+        tls: None,
+        tcp_flags: None,
+        port_state: None,
+        icmp_type: Some(icmp_type),
+        rtt: icmp::echo_rtt(icmp_payload),
+        timed_out: false,
+        data: vec![],
+    };
+    Some(scan_result)
 }
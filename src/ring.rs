@@ -0,0 +1,423 @@
+use crate::packet_io::{lock, poll, PacketIo, DEFAULT_RECV_TIMEOUT};
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_BLOCK_SIZE: u32 = 1 << 20;
+pub const DEFAULT_BLOCK_COUNT: u32 = 16;
+pub const DEFAULT_FRAME_SIZE: u32 = 2048;
+pub const DEFAULT_BLOCK_TIMEOUT: Duration = Duration::from_millis(10);
+pub const DEFAULT_TX_BATCH: u32 = 64;
+/// How long a send waits for the kernel to free a slot of the full send ring before it fails
+pub const TX_TIMEOUT: Duration = Duration::from_secs(1);
+
+// from linux/if_packet.h
+const PACKET_RX_RING: libc::c_int = 5;
+const PACKET_VERSION: libc::c_int = 10;
+const PACKET_TX_RING: libc::c_int = 13;
+const TPACKET_V3: libc::c_int = 2;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+const TP_STATUS_AVAILABLE: u32 = 0;
+const TP_STATUS_SEND_REQUEST: u32 = 1;
+const TP_STATUS_WRONG_FORMAT: u32 = 1 << 2;
+
+// Offsets into struct tpacket_block_desc, whose header is a struct tpacket_hdr_v1
+const BLOCK_STATUS: usize = 8;
+const BLOCK_NUM_PKTS: usize = 12;
+const BLOCK_OFFSET_TO_FIRST_PKT: usize = 16;
+
+// Offsets into struct tpacket3_hdr
+const FRAME_NEXT_OFFSET: usize = 0;
+const FRAME_SNAPLEN: usize = 12;
+const FRAME_LEN: usize = 16;
+const FRAME_STATUS: usize = 20;
+const FRAME_MAC: usize = 24;
+// TPACKET_ALIGN(sizeof(struct tpacket3_hdr)), where the kernel expects the frames we send
+const FRAME_DATA: usize = 48;
+
+#[repr(C)]
+struct TpacketReq3 {
+    tp_block_size: libc::c_uint,
+    tp_block_nr: libc::c_uint,
+    tp_frame_size: libc::c_uint,
+    tp_frame_nr: libc::c_uint,
+    tp_retire_blk_tov: libc::c_uint,
+    tp_sizeof_priv: libc::c_uint,
+    tp_feature_req_word: libc::c_uint,
+}
+
+/// The layout of the TPACKET_V3 rings, which are mapped once for receiving and once for sending
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RingConfig {
+    /// bytes per block, a multiple of the page size
+    pub block_size: u32,
+    /// blocks per ring
+    pub block_count: u32,
+    /// bytes per frame of the send ring, the largest frame we can send is 48 bytes shorter
+    pub frame_size: u32,
+    /// how long the kernel fills a receive block before handing it over partially filled
+    pub block_timeout: Duration,
+    /// frames queued on the send ring before the kernel is told to send them
+    pub tx_batch: u32,
+}
+
+impl Default for RingConfig {
+    fn default() -> Self {
+        RingConfig {
+            block_size: DEFAULT_BLOCK_SIZE,
+            block_count: DEFAULT_BLOCK_COUNT,
+            frame_size: DEFAULT_FRAME_SIZE,
+            block_timeout: DEFAULT_BLOCK_TIMEOUT,
+            tx_batch: DEFAULT_TX_BATCH,
+        }
+    }
+}
+
+/// An AF_PACKET socket with TPACKET_V3 memory mapped rings. Received frames are taken from the
+/// blocks the kernel fills, without a syscall per frame, and sent frames are queued on the send
+/// ring and handed to the kernel in batches.
+#[derive(Clone, Debug)]
+pub struct Ring {
+    socket: Arc<Socket>,
+    rx: Arc<Mutex<RxRing>>,
+    tx: Arc<Mutex<TxRing>>,
+    timeout: Duration,
+}
+
+#[derive(Debug)]
+struct Socket {
+    fd: libc::c_int,
+    map: *mut u8,
+    map_len: usize,
+}
+
+// The mapping is only accessed through the rings, each behind its own lock
+unsafe impl Send for Socket {}
+unsafe impl Sync for Socket {}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        // SAFETY: map and fd were created by Ring::bind and nothing uses them anymore
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.map_len);
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RxRing {
+    map: *mut u8,
+    block_size: usize,
+    block_count: usize,
+    block: usize,
+    // the next frame of the current block and how many are left, None until the kernel hands
+    // the block over
+    next_frame: Option<(usize, u32)>,
+}
+
+unsafe impl Send for RxRing {}
+
+#[derive(Debug)]
+struct TxRing {
+    map: *mut u8,
+    block_size: usize,
+    frame_size: usize,
+    frame_count: usize,
+    frame: usize,
+    batch: u32,
+    queued: u32,
+}
+
+unsafe impl Send for TxRing {}
+
+impl Ring {
+    /// Open an AF_PACKET socket on the interface and map its rings
+    pub fn bind(if_name: &str, conf: &RingConfig) -> io::Result<Self> {
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        // SAFETY: plain socket call, the fd is closed by Socket if anything below fails
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol as libc::c_int) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut socket = Socket {
+            fd,
+            map: ptr::null_mut(),
+            map_len: 0,
+        };
+
+        setsockopt(fd, PACKET_VERSION, &TPACKET_V3)?;
+        let frames_per_block = conf.block_size / conf.frame_size.max(1);
+        let rx_req = TpacketReq3 {
+            tp_block_size: conf.block_size,
+            tp_block_nr: conf.block_count,
+            tp_frame_size: conf.frame_size,
+            tp_frame_nr: frames_per_block * conf.block_count,
+            tp_retire_blk_tov: conf.block_timeout.as_millis().max(1) as libc::c_uint,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        setsockopt(fd, PACKET_RX_RING, &rx_req)?;
+        // the kernel sends frame by frame, the block options only apply to receiving
+        let tx_req = TpacketReq3 {
+            tp_retire_blk_tov: 0,
+            ..rx_req
+        };
+        setsockopt(fd, PACKET_TX_RING, &tx_req)?;
+
+        let ring_len = conf.block_size as usize * conf.block_count as usize;
+        // SAFETY: the kernel maps both rings, the receive ring first
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                2 * ring_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        socket.map = map as *mut u8;
+        socket.map_len = 2 * ring_len;
+
+        let if_name = CString::new(if_name)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid interface name"))?;
+        // SAFETY: if_name is a valid C string
+        let if_index = unsafe { libc::if_nametoindex(if_name.as_ptr()) };
+        if if_index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: sockaddr_ll is plain data
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+        addr.sll_protocol = protocol;
+        addr.sll_ifindex = if_index as libc::c_int;
+        // SAFETY: addr is a valid sockaddr_ll of the given size
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let rx = RxRing {
+            map: socket.map,
+            block_size: conf.block_size as usize,
+            block_count: conf.block_count as usize,
+            block: 0,
+            next_frame: None,
+        };
+        let tx = TxRing {
+            // SAFETY: the send ring follows the receive ring within the mapping
+            map: unsafe { socket.map.add(ring_len) },
+            block_size: conf.block_size as usize,
+            frame_size: conf.frame_size as usize,
+            frame_count: tx_req.tp_frame_nr as usize,
+            frame: 0,
+            batch: conf.tx_batch.max(1),
+            queued: 0,
+        };
+        Ok(Ring {
+            socket: Arc::new(socket),
+            rx: Arc::new(Mutex::new(rx)),
+            tx: Arc::new(Mutex::new(tx)),
+            timeout: DEFAULT_RECV_TIMEOUT,
+        })
+    }
+
+    /// Hand the queued frames to the kernel
+    pub fn flush(&self) -> io::Result<()> {
+        lock(&self.tx)?.kick(self.socket.fd)
+    }
+}
+
+impl PacketIo for Ring {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        lock(&self.tx)?.send(self.socket.fd, frame)
+    }
+
+    fn recv(&mut self, frame: &mut [u8]) -> io::Result<Option<usize>> {
+        lock(&self.rx)?.recv(self.socket.fd, frame, self.timeout)
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ring::flush(self)
+    }
+}
+
+impl RxRing {
+    fn recv(
+        &mut self,
+        fd: libc::c_int,
+        frame: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<Option<usize>> {
+        loop {
+            // SAFETY: block is below block_count, so the block lies within the receive ring
+            let block = unsafe { self.map.add(self.block * self.block_size) };
+            let (offset, left) = match self.next_frame {
+                Some(next_frame) => next_frame,
+                None => {
+                    // SAFETY: the status is shared with the kernel, so it is only accessed
+                    // atomically
+                    let status = unsafe { atomic_u32(block, BLOCK_STATUS) };
+                    if status.load(Ordering::Acquire) & TP_STATUS_USER == 0 {
+                        if !poll(fd, libc::POLLIN, timeout)? {
+                            return Ok(None);
+                        }
+                        continue;
+                    }
+                    // SAFETY: the block belongs to us until we hand it back
+                    unsafe {
+                        (
+                            read_u32(block, BLOCK_OFFSET_TO_FIRST_PKT) as usize,
+                            read_u32(block, BLOCK_NUM_PKTS),
+                        )
+                    }
+                }
+            };
+            if left == 0 {
+                // SAFETY: as above, and the block is not accessed after handing it back
+                unsafe { atomic_u32(block, BLOCK_STATUS) }
+                    .store(TP_STATUS_KERNEL, Ordering::Release);
+                self.block = (self.block + 1) % self.block_count;
+                self.next_frame = None;
+                continue;
+            }
+
+            // SAFETY: the kernel placed a tpacket3_hdr at offset and the frame after it, within
+            // the block
+            let len = unsafe {
+                let hdr = block.add(offset);
+                let snaplen = read_u32(hdr, FRAME_SNAPLEN) as usize;
+                let mac = ptr::read_unaligned(hdr.add(FRAME_MAC) as *const u16) as usize;
+                let len = snaplen.min(frame.len());
+                ptr::copy_nonoverlapping(hdr.add(mac), frame.as_mut_ptr(), len);
+                self.next_frame =
+                    Some((offset + read_u32(hdr, FRAME_NEXT_OFFSET) as usize, left - 1));
+                len
+            };
+            return Ok(Some(len));
+        }
+    }
+}
+
+impl TxRing {
+    fn send(&mut self, fd: libc::c_int, frame: &[u8]) -> io::Result<()> {
+        if frame.len() > self.frame_size - FRAME_DATA {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "frame does not fit in the send ring",
+            ));
+        }
+        let frames_per_block = self.block_size / self.frame_size;
+        let offset = (self.frame / frames_per_block) * self.block_size
+            + (self.frame % frames_per_block) * self.frame_size;
+        // SAFETY: frame is below frame_count, so the slot lies within the send ring
+        let slot = unsafe { self.map.add(offset) };
+        // SAFETY: the status is shared with the kernel, so it is only accessed atomically
+        let status = unsafe { atomic_u32(slot, FRAME_STATUS) };
+        let mut full_since = None;
+        loop {
+            match status.load(Ordering::Acquire) {
+                TP_STATUS_AVAILABLE => break,
+                TP_STATUS_WRONG_FORMAT => {
+                    status.store(TP_STATUS_AVAILABLE, Ordering::Release);
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "the kernel rejected a frame of the send ring",
+                    ));
+                }
+                // the ring is full, wait for the kernel to send what is queued
+                _ => {
+                    if full_since.get_or_insert_with(Instant::now).elapsed() >= TX_TIMEOUT {
+                        return Err(io::Error::new(
+                            ErrorKind::TimedOut,
+                            "the kernel sent nothing from the full send ring",
+                        ));
+                    }
+                    self.kick(fd)?;
+                    poll(fd, libc::POLLOUT, DEFAULT_RECV_TIMEOUT)?;
+                }
+            }
+        }
+        // SAFETY: the slot is available, so the kernel doesn't touch it until we set its status
+        unsafe {
+            ptr::copy_nonoverlapping(frame.as_ptr(), slot.add(FRAME_DATA), frame.len());
+            ptr::write_unaligned(slot.add(FRAME_NEXT_OFFSET) as *mut u32, 0);
+            ptr::write_unaligned(slot.add(FRAME_SNAPLEN) as *mut u32, frame.len() as u32);
+            ptr::write_unaligned(slot.add(FRAME_LEN) as *mut u32, frame.len() as u32);
+        }
+        status.store(TP_STATUS_SEND_REQUEST, Ordering::Release);
+        self.frame = (self.frame + 1) % self.frame_count;
+        self.queued += 1;
+        if self.queued >= self.batch {
+            self.kick(fd)?;
+        }
+        Ok(())
+    }
+
+    // Tell the kernel to send the frames marked with TP_STATUS_SEND_REQUEST
+    fn kick(&mut self, fd: libc::c_int) -> io::Result<()> {
+        if self.queued == 0 {
+            return Ok(());
+        }
+        // SAFETY: a send without a buffer, the frames are taken from the ring
+        let ret = unsafe { libc::sendto(fd, ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            // the kernel picks the frames up with the next kick
+            if e.kind() != ErrorKind::WouldBlock && e.raw_os_error() != Some(libc::ENOBUFS) {
+                return Err(e);
+            }
+            return Ok(());
+        }
+        self.queued = 0;
+        Ok(())
+    }
+}
+
+fn setsockopt<T>(fd: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    // SAFETY: value is a valid T of the given size
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_PACKET,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// SAFETY: base + offset must be a 4 byte aligned u32 within the mapping
+unsafe fn atomic_u32<'a>(base: *mut u8, offset: usize) -> &'a AtomicU32 {
+    &*(base.add(offset) as *const AtomicU32)
+}
+
+// SAFETY: base + offset must be a u32 within the mapping
+unsafe fn read_u32(base: *mut u8, offset: usize) -> u32 {
+    ptr::read_unaligned(base.add(offset) as *const u32)
+}
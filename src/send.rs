@@ -1,19 +1,259 @@
-use afpacket::sync::RawPacketStream;
-use crossbeam_channel::{Receiver, TryRecvError};
-use std::io::prelude::*;
+use crate::packet_io::PacketIo;
+use crate::ratelimit::{RateLimit, RateLimiter};
+use crate::ScanError;
+use crossbeam_channel::{Receiver, RecvError, Select, TryRecvError};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub fn start_tx(mut tx: RawPacketStream, pkts: Receiver<Vec<u8>>, shutdown: Arc<AtomicBool>) {
+// Waits shorter than this are spun instead of slept, sleeping overshoots by more than that
+const MIN_SLEEP: Duration = Duration::from_micros(100);
+// Upper bound on a single sleep so shutdown and rate limit changes are picked up promptly
+const MAX_SLEEP: Duration = Duration::from_millis(10);
+
+pub const DEFAULT_PROBE_DELAY: Duration = Duration::from_secs(1);
+
+/// How often the SYN of a tcp target is sent. A probe is sent again until it was sent the given
+/// number of times or the target answered it, the answers to copies already on their way are
+/// dropped by the rx thread.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Retransmit {
+    /// number of times each probe is sent
+    pub probes: u32,
+    /// wait between the first and the second probe
+    pub delay: Duration,
+    /// double the wait before every further probe
+    pub backoff: bool,
+}
+
+impl Default for Retransmit {
+    fn default() -> Self {
+        Retransmit {
+            probes: 1,
+            delay: DEFAULT_PROBE_DELAY,
+            backoff: false,
+        }
+    }
+}
+
+// A probe waiting to be sent again
+struct QueuedProbe {
+    due: Instant,
+    first_sent: Instant,
+    cookie: u32,
+    pkt: Vec<u8>,
+}
+
+// Probes waiting to be sent again, queued by the number of times they were sent. Every probe
+// waits equally long between the same two sends, so each queue is ordered by due time. Probes
+// are identified by their validation cookie, the rx thread reports the cookies of the probes the
+// targets answered and those are not sent again.
+struct RetransmitQueue {
+    retransmit: Retransmit,
+    queues: Vec<VecDeque<QueuedProbe>>,
+    // when each cookie was last reported answered, and the reports in the order they arrived
+    answered: HashMap<u32, Instant>,
+    answered_order: VecDeque<(Instant, u32)>,
+}
+
+impl RetransmitQueue {
+    fn new(retransmit: Retransmit) -> Self {
+        let queues = (1..retransmit.probes).map(|_| VecDeque::new()).collect();
+        RetransmitQueue {
+            retransmit,
+            queues,
+            answered: HashMap::new(),
+            answered_order: VecDeque::new(),
+        }
+    }
+
+    // Queue a probe which was sent `sent` times, unless it was sent often enough
+    fn schedule(&mut self, sent: usize, first_sent: Instant, cookie: u32, pkt: Vec<u8>) {
+        let queue = match self.queues.get_mut(sent - 1) {
+            Some(queue) => queue,
+            None => return,
+        };
+        let mut delay = self.retransmit.delay;
+        if self.retransmit.backoff {
+            delay = delay.saturating_mul(1 << (sent - 1).min(16));
+        }
+        queue.push_back(QueuedProbe {
+            due: Instant::now() + delay,
+            first_sent,
+            cookie,
+            pkt,
+        });
+    }
+
+    fn answered(&mut self, cookie: u32) {
+        let now = Instant::now();
+        self.answered.insert(cookie, now);
+        self.answered_order.push_back((now, cookie));
+    }
+
+    // A probe due to be sent again, which is queued for its next send right away. Probes which
+    // were answered since they were first sent are dropped.
+    fn pop_due(&mut self) -> Option<Vec<u8>> {
+        self.forget_answers();
+        let now = Instant::now();
+        loop {
+            let i = self
+                .queues
+                .iter()
+                .position(|queue| matches!(queue.front(), Some(probe) if probe.due <= now))?;
+            let probe = self.queues[i].pop_front()?;
+            if matches!(self.answered.get(&probe.cookie), Some(at) if *at >= probe.first_sent) {
+                continue;
+            }
+            // the first send is not queued, queue i holds probes sent i + 1 times
+            self.schedule(i + 2, probe.first_sent, probe.cookie, probe.pkt.clone());
+            return Some(probe.pkt);
+        }
+    }
+
+    // When the next probe is due to be sent again
+    fn next_due(&self) -> Option<Instant> {
+        self.queues
+            .iter()
+            .filter_map(|queue| queue.front())
+            .map(|probe| probe.due)
+            .min()
+    }
+
+    // An answer only stops the probes sent before it, so it is forgotten once every queued
+    // probe was first sent after it
+    fn forget_answers(&mut self) {
+        let oldest = self
+            .queues
+            .iter()
+            .filter_map(|queue| queue.front())
+            .map(|probe| probe.first_sent)
+            .min();
+        while let Some((at, cookie)) = self.answered_order.front().copied() {
+            if matches!(oldest, Some(oldest) if at >= oldest) {
+                break;
+            }
+            self.answered_order.pop_front();
+            if self.answered.get(&cookie) == Some(&at) {
+                self.answered.remove(&cookie);
+            }
+        }
+    }
+}
+
+// The next packet to send. Due retransmissions go first, then the responses and targets of pkts,
+// then new probes which are retransmitted. Fails once pkts or probes is empty and has no sender
+// left, as the scanner is gone then.
+fn next_pkt(
+    pkts: &Receiver<Vec<u8>>,
+    probes: &Receiver<(u32, Vec<u8>)>,
+    answered: &Receiver<u32>,
+    retransmits: &mut RetransmitQueue,
+) -> Result<Option<Vec<u8>>, RecvError> {
+    for cookie in answered.try_iter() {
+        retransmits.answered(cookie);
+    }
+    if let Some(pkt) = retransmits.pop_due() {
+        return Ok(Some(pkt));
+    }
+    match pkts.try_recv() {
+        Ok(pkt) => return Ok(Some(pkt)),
+        Err(TryRecvError::Empty) => {}
+        Err(TryRecvError::Disconnected) => return Err(RecvError),
+    }
+    match probes.try_recv() {
+        Ok((cookie, pkt)) => {
+            retransmits.schedule(1, Instant::now(), cookie, pkt.clone());
+            Ok(Some(pkt))
+        }
+        Err(TryRecvError::Empty) => Ok(None),
+        Err(TryRecvError::Disconnected) => Err(RecvError),
+    }
+}
+
+// Wait until there may be a packet to send: a frame or a probe came in, or a probe is due to be
+// sent again
+fn wait_for_pkt(
+    pkts: &Receiver<Vec<u8>>,
+    probes: &Receiver<(u32, Vec<u8>)>,
+    retransmits: &RetransmitQueue,
+) {
+    let timeout = match retransmits.next_due() {
+        Some(due) => due.saturating_duration_since(Instant::now()).min(MAX_SLEEP),
+        None => MAX_SLEEP,
+    };
+    let mut select = Select::new();
+    select.recv(pkts);
+    select.recv(probes);
+    let _ = select.ready_timeout(timeout);
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn start_tx<P: PacketIo>(
+    mut tx: P,
+    pkts: Receiver<Vec<u8>>,
+    probes: Receiver<(u32, Vec<u8>)>,
+    answered: Receiver<u32>,
+    retransmit: Retransmit,
+    rate_limit: RateLimit,
+    rate_limit_updates: Receiver<RateLimit>,
+    shutdown: Arc<AtomicBool>,
+) -> Result<(), ScanError> {
+    let mut limiter = RateLimiter::new(&rate_limit);
+    let mut retransmits = RetransmitQueue::new(retransmit);
+    let mut pending: Option<Vec<u8>> = None;
+    // whether frames were sent since the last flush of tx
+    let mut unflushed = false;
     loop {
         if shutdown.load(Ordering::Relaxed) {
-            break;
+            tx.flush()?;
+            return Ok(());
+        }
+        if let Some(rate_limit) = rate_limit_updates.try_iter().last() {
+            log::info!("updating rate limit to {:?}", rate_limit);
+            limiter.set_limit(&rate_limit);
         }
-        match pkts.try_recv() {
-            Err(TryRecvError::Empty) => (),
-            Err(_) => log::error!("failed to read from target channel"),
-            Ok(pkt) => {
-                tx.write_all(&pkt).expect("failed to write packet");
+
+        let pkt = match pending.take() {
+            Some(pkt) => pkt,
+            None => match next_pkt(&pkts, &probes, &answered, &mut retransmits) {
+                Ok(Some(pkt)) => pkt,
+                Ok(None) => {
+                    if unflushed {
+                        tx.flush()?;
+                        unflushed = false;
+                    }
+                    wait_for_pkt(&pkts, &probes, &retransmits);
+                    continue;
+                }
+                // the scanner was dropped without a shutdown, nothing is left to send
+                Err(RecvError) => {
+                    log::debug!("target channels closed, stopping tx");
+                    tx.flush()?;
+                    return Ok(());
+                }
+            },
+        };
+
+        match limiter.try_acquire(pkt.len()) {
+            Ok(()) => {
+                tx.send(&pkt)?;
+                unflushed = true;
+            }
+            Err(wait) => {
+                pending = Some(pkt);
+                if unflushed {
+                    tx.flush()?;
+                    unflushed = false;
+                }
+                if wait >= MIN_SLEEP {
+                    thread::sleep(wait.min(MAX_SLEEP));
+                } else {
+                    thread::yield_now();
+                }
             }
         }
     }
@@ -0,0 +1,36 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters shared between the scanner threads
+#[derive(Debug, Default)]
+pub struct ScanStats {
+    /// responses dropped because they did not match a probe we sent
+    pub validation_failed: AtomicU64,
+    /// targets not scanned because of the blocklist
+    pub blocked: AtomicU64,
+    /// connections forgotten after the host timeout
+    pub hosts_expired: AtomicU64,
+    /// connections forgotten because too many were tracked
+    pub hosts_evicted: AtomicU64,
+}
+
+impl ScanStats {
+    pub fn validation_failed(&self) -> u64 {
+        self.validation_failed.load(Ordering::Relaxed)
+    }
+
+    pub fn blocked(&self) -> u64 {
+        self.blocked.load(Ordering::Relaxed)
+    }
+
+    pub fn hosts_expired(&self) -> u64 {
+        self.hosts_expired.load(Ordering::Relaxed)
+    }
+
+    pub fn hosts_evicted(&self) -> u64 {
+        self.hosts_evicted.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
@@ -0,0 +1,362 @@
+use crate::permutation::PermutationIter;
+use crate::Target;
+use etherparse::ip_number;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetSpecError {
+    InvalidAddress(String),
+    InvalidPrefixLength(String),
+    InvalidPort(String),
+}
+
+impl fmt::Display for TargetSpecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TargetSpecError::InvalidAddress(s) => write!(f, "Invalid address {}", s),
+            TargetSpecError::InvalidPrefixLength(s) => write!(f, "Invalid prefix length {}", s),
+            TargetSpecError::InvalidPort(s) => write!(f, "Invalid port {}", s),
+        }
+    }
+}
+
+impl Error for TargetSpecError {}
+
+/// An IPv4 or IPv6 network in CIDR notation. The address is always the network address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IpPrefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl IpPrefix {
+    pub fn new(addr: IpAddr, len: u8) -> Result<Self, TargetSpecError> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if len > max_len {
+            return Err(TargetSpecError::InvalidPrefixLength(len.to_string()));
+        }
+        let addr = match addr {
+            IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & v4_mask(len))),
+            IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & v6_mask(len))),
+        };
+        Ok(IpPrefix { addr, len })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        self.addr.is_ipv4()
+    }
+
+    /// Number of addresses in the prefix. ::/0 saturates at u128::MAX.
+    pub fn size(&self) -> u128 {
+        let host_bits = match self.addr {
+            IpAddr::V4(_) => 32 - u32::from(self.len),
+            IpAddr::V6(_) => 128 - u32::from(self.len),
+        };
+        1u128.checked_shl(host_bits).unwrap_or(u128::MAX)
+    }
+
+    /// The address at offset from the start of the prefix, offset must be less than size()
+    pub fn nth(&self, offset: u128) -> IpAddr {
+        match self.addr {
+            IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) + offset as u32)),
+            IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) + offset)),
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                u32::from(ip) & v4_mask(self.len) == u32::from(net)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                u128::from(ip) & v6_mask(self.len) == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn v4_mask(len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0)
+}
+
+fn v6_mask(len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0)
+}
+
+impl FromStr for IpPrefix {
+    type Err = TargetSpecError;
+
+    /// Parse a.b.c.d/len or an IPv6 prefix, optionally in brackets. A bare address is a /32 or
+    /// /128 prefix.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .unwrap_or(s);
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| TargetSpecError::InvalidAddress(addr.into()))?;
+        let len = match len {
+            Some(len) => len
+                .parse()
+                .map_err(|_| TargetSpecError::InvalidPrefixLength(len.into()))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        IpPrefix::new(addr, len)
+    }
+}
+
+impl fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+fn parse_ports(s: &str) -> Result<Vec<RangeInclusive<u16>>, TargetSpecError> {
+    s.split(',')
+        .map(|part| {
+            let invalid = || TargetSpecError::InvalidPort(part.into());
+            let (start, end) = match part.split_once('-') {
+                Some((start, end)) => (start, end),
+                None => (part, part),
+            };
+            let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+            let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+            if start > end {
+                return Err(invalid());
+            }
+            Ok(start..=end)
+        })
+        .collect()
+}
+
+fn parse_prefixes(s: &str) -> Result<Vec<IpPrefix>, TargetSpecError> {
+    s.split(',').map(|prefix| prefix.parse()).collect()
+}
+
+/// A set of targets given as a list of prefixes and a list of ports, expanded lazily into the
+/// cross product of every address and every port, e.g.
+///
+/// ```text
+/// 10.0.0.0/8:80,443,8000-8100
+/// 192.168.0.0/16,172.16.0.0/12:22
+/// [2001:db8::/120]:53
+/// 2001:db8::/64,2001:db8:1::/64:80
+/// ```
+///
+/// Ports come after the last colon. IPv6 hosts without a prefix length should be put in
+/// brackets, otherwise the last group of the address is read as the port. Without ports every
+/// address is a target with port 0, which is what ICMP echo probes use.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TargetSpec {
+    prefixes: Vec<IpPrefix>,
+    ports: Vec<RangeInclusive<u16>>,
+    ip_number: u8,
+}
+
+impl TargetSpec {
+    pub fn new(prefixes: Vec<IpPrefix>, ports: Vec<RangeInclusive<u16>>) -> Self {
+        TargetSpec {
+            prefixes,
+            ports,
+            ip_number: u8::from(ip_number::TCP),
+        }
+    }
+
+    /// Probe the targets with another protocol than tcp
+    pub fn with_ip_number(mut self, ip_number: u8) -> Self {
+        self.ip_number = ip_number;
+        self
+    }
+
+    pub fn prefixes(&self) -> &[IpPrefix] {
+        &self.prefixes
+    }
+
+    fn num_addresses(&self) -> u128 {
+        self.prefixes
+            .iter()
+            .fold(0u128, |sum, prefix| sum.saturating_add(prefix.size()))
+    }
+
+    fn num_ports(&self) -> u128 {
+        self.ports
+            .iter()
+            .map(|ports| u128::from(*ports.end() - *ports.start()) + 1)
+            .sum()
+    }
+
+    /// Number of targets in the spec, saturating at u128::MAX
+    pub fn len(&self) -> u128 {
+        self.num_addresses().saturating_mul(self.num_ports())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn address(&self, mut index: u128) -> Option<IpAddr> {
+        for prefix in &self.prefixes {
+            if index < prefix.size() {
+                return Some(prefix.nth(index));
+            }
+            index -= prefix.size();
+        }
+        None
+    }
+
+    fn port(&self, mut index: u128) -> Option<u16> {
+        for ports in &self.ports {
+            let count = u128::from(*ports.end() - *ports.start()) + 1;
+            if index < count {
+                return Some(*ports.start() + index as u16);
+            }
+            index -= count;
+        }
+        None
+    }
+
+    /// The target at index, in the order of iteration: every port of the first address, then
+    /// every port of the second address and so on
+    pub fn get(&self, index: u128) -> Option<Target> {
+        let num_ports = self.num_ports();
+        if num_ports == 0 {
+            return None;
+        }
+        Some(Target {
+            ip: self.address(index / num_ports)?,
+            port: self.port(index % num_ports)?,
+            ip_number: self.ip_number,
+            data: None,
+            hostname: None,
+        })
+    }
+
+    /// The targets in the order of a permutation of 0..len(), see Permutation
+    pub fn permuted(&self, order: PermutationIter) -> PermutedTargets<'_> {
+        PermutedTargets { spec: self, order }
+    }
+
+    pub fn iter(&self) -> TargetIter<'_> {
+        TargetIter {
+            spec: self,
+            prefix: 0,
+            offset: 0,
+            port_range: 0,
+            port: self.ports.first().map(|ports| *ports.start()),
+        }
+    }
+}
+
+impl FromStr for TargetSpec {
+    type Err = TargetSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some((prefixes, ports)) = s.rsplit_once(':') {
+            if let (Ok(prefixes), Ok(ports)) = (parse_prefixes(prefixes), parse_ports(ports)) {
+                return Ok(TargetSpec::new(prefixes, ports));
+            }
+        }
+        Ok(TargetSpec::new(parse_prefixes(s)?, vec![0..=0]))
+    }
+}
+
+/// Iterator over the targets of a TargetSpec, producing one target at a time
+#[derive(Clone, Debug)]
+pub struct TargetIter<'a> {
+    spec: &'a TargetSpec,
+    prefix: usize,
+    offset: u128,
+    port_range: usize,
+    // next port to produce in the current port range, None once the spec is exhausted
+    port: Option<u16>,
+}
+
+impl<'a> Iterator for TargetIter<'a> {
+    type Item = Target;
+
+    fn next(&mut self) -> Option<Target> {
+        let prefix = self.spec.prefixes.get(self.prefix)?;
+        let port = self.port?;
+        let target = Target {
+            ip: prefix.nth(self.offset),
+            port,
+            ip_number: self.spec.ip_number,
+            data: None,
+            hostname: None,
+        };
+
+        // advance the port, then the address within the prefix, then the prefix
+        let ports = &self.spec.ports[self.port_range];
+        if port < *ports.end() {
+            self.port = Some(port + 1);
+            return Some(target);
+        }
+        self.port_range += 1;
+        if self.port_range == self.spec.ports.len() {
+            self.port_range = 0;
+            self.offset += 1;
+            if self.offset == prefix.size() {
+                self.offset = 0;
+                self.prefix += 1;
+            }
+        }
+        self.port = Some(*self.spec.ports[self.port_range].start());
+        Some(target)
+    }
+}
+
+impl<'a> IntoIterator for &'a TargetSpec {
+    type Item = Target;
+    type IntoIter = TargetIter<'a>;
+
+    fn into_iter(self) -> TargetIter<'a> {
+        self.iter()
+    }
+}
+
+/// Iterator over the targets of a TargetSpec in a pseudo random order
+#[derive(Clone, Debug)]
+pub struct PermutedTargets<'a> {
+    spec: &'a TargetSpec,
+    order: PermutationIter,
+}
+
+impl<'a> PermutedTargets<'a> {
+    /// Position in the permutation, to resume from later
+    pub fn position(&self) -> u64 {
+        self.order.position()
+    }
+}
+
+impl<'a> Iterator for PermutedTargets<'a> {
+    type Item = Target;
+
+    fn next(&mut self) -> Option<Target> {
+        let index = self.order.next()?;
+        self.spec.get(u128::from(index))
+    }
+}
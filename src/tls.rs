@@ -0,0 +1,483 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_with::base64::Base64;
+use serde_with::serde_as;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+const CONTENT_CHANGE_CIPHER_SPEC: u8 = 20;
+const CONTENT_ALERT: u8 = 21;
+const CONTENT_HANDSHAKE: u8 = 22;
+const CONTENT_APPLICATION_DATA: u8 = 23;
+
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const HANDSHAKE_SERVER_HELLO: u8 = 2;
+const HANDSHAKE_CERTIFICATE: u8 = 11;
+const HANDSHAKE_SERVER_HELLO_DONE: u8 = 14;
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_EC_POINT_FORMATS: u16 = 11;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXT_ALPN: u16 = 16;
+const EXT_EXTENDED_MASTER_SECRET: u16 = 23;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+const EXT_PSK_KEY_EXCHANGE_MODES: u16 = 45;
+const EXT_KEY_SHARE: u16 = 51;
+
+const GROUP_X25519: u16 = 0x001d;
+const SUPPORTED_GROUPS: &[u16] = &[GROUP_X25519, 0x0017, 0x0018];
+const SIGNATURE_ALGORITHMS: &[u16] = &[
+    0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601, 0x0201,
+];
+
+/// The cipher suites offered by default. A server picks one of the suites we offer, so these
+/// names cover every suite a server can answer with unless cipher_suites is configured.
+pub const CIPHER_SUITES: &[(u16, &str)] = &[
+    (0x1301, "TLS_AES_128_GCM_SHA256"),
+    (0x1302, "TLS_AES_256_GCM_SHA384"),
+    (0x1303, "TLS_CHACHA20_POLY1305_SHA256"),
+    (0xc02b, "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256"),
+    (0xc02f, "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"),
+    (0xc02c, "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384"),
+    (0xc030, "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"),
+    (0xcca9, "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256"),
+    (0xcca8, "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256"),
+    (0xc013, "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA"),
+    (0xc014, "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA"),
+    (0x009c, "TLS_RSA_WITH_AES_128_GCM_SHA256"),
+    (0x009d, "TLS_RSA_WITH_AES_256_GCM_SHA384"),
+    (0x002f, "TLS_RSA_WITH_AES_128_CBC_SHA"),
+    (0x0035, "TLS_RSA_WITH_AES_256_CBC_SHA"),
+    (0x000a, "TLS_RSA_WITH_3DES_EDE_CBC_SHA"),
+];
+
+#[derive(
+    Debug, Clone, Copy, Default, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn code(self) -> u16 {
+        match self {
+            TlsVersion::Tls10 => 0x0301,
+            TlsVersion::Tls11 => 0x0302,
+            TlsVersion::Tls12 => 0x0303,
+            TlsVersion::Tls13 => 0x0304,
+        }
+    }
+}
+
+/// What the ClientHello of a tls handshake offers
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// protocols offered with ALPN, such as h2 and http/1.1
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    /// highest version offered. With 1.3 the server encrypts its certificates, so they are only
+    /// seen from servers which pick 1.2 or lower.
+    #[serde(default)]
+    pub max_version: TlsVersion,
+    /// cipher suites offered instead of the defaults in CIPHER_SUITES
+    #[serde(default)]
+    pub cipher_suites: Vec<u16>,
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Certificate {
+    #[serde_as(as = "Base64")]
+    pub der: Vec<u8>,
+    pub subject: Option<String>,
+    pub issuer: Option<String>,
+    /// DNS names and addresses of the subject alternative name extension
+    pub sans: Vec<String>,
+    pub not_before: Option<String>,
+    pub not_after: Option<String>,
+}
+
+impl Certificate {
+    /// Parse the fields of a DER certificate. Fields which can't be parsed are left empty.
+    pub fn from_der(der: &[u8]) -> Self {
+        let mut certificate = Certificate {
+            der: der.to_vec(),
+            ..Default::default()
+        };
+        let cert = match X509Certificate::from_der(der) {
+            Ok((_, cert)) => cert,
+            Err(e) => {
+                log::debug!("failed to parse certificate: {}", e);
+                return certificate;
+            }
+        };
+        certificate.subject = Some(cert.subject().to_string());
+        certificate.issuer = Some(cert.issuer().to_string());
+        certificate.not_before = cert.validity().not_before.to_rfc2822().ok();
+        certificate.not_after = cert.validity().not_after.to_rfc2822().ok();
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(name) => certificate.sans.push(name.to_string()),
+                    GeneralName::IPAddress(ip) => {
+                        if let Some(ip) = ip_from_bytes(ip) {
+                            certificate.sans.push(ip.to_string());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        certificate
+    }
+}
+
+fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => None,
+    }
+}
+
+/// What a server told us in the unencrypted part of its handshake
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// negotiated version such as TLSv1.2
+    pub version: Option<String>,
+    /// name of the negotiated cipher suite, or its number in hex if the name is unknown
+    pub cipher_suite: Option<String>,
+    /// protocol picked with ALPN
+    pub alpn: Option<String>,
+    /// the certificate chain, starting with the certificate of the server
+    pub certificates: Vec<Certificate>,
+    /// description of the alert the server sent instead of completing the handshake
+    pub alert: Option<u8>,
+    /// why the handshake of the server could not be read
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlsError {
+    /// the data is not a tls record
+    NotTls,
+    /// a handshake message is inconsistent with its length
+    Malformed(&'static str),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::NotTls => write!(f, "Not a tls record"),
+            TlsError::Malformed(what) => write!(f, "Malformed {}", what),
+        }
+    }
+}
+
+impl Error for TlsError {}
+
+/// The part of a server flight received so far
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServerFlight {
+    pub info: TlsInfo,
+    /// everything the server sends before waiting for us was received
+    pub complete: bool,
+}
+
+/// Build a ClientHello record. The server name is sent with SNI, unless it is an address.
+pub fn client_hello(conf: &TlsConfig, server_name: Option<&str>) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let tls13 = conf.max_version >= TlsVersion::Tls13;
+
+    let mut body = vec![];
+    put_u16(&mut body, conf.max_version.code().min(0x0303));
+    body.extend(rng.gen::<[u8; 32]>());
+    // a session id makes servers speaking 1.3 fall back to the middlebox compatible mode
+    body.push(32);
+    body.extend(rng.gen::<[u8; 32]>());
+    let suites: Vec<u16> = if conf.cipher_suites.is_empty() {
+        CIPHER_SUITES
+            .iter()
+            .map(|(suite, _)| *suite)
+            .filter(|suite| tls13 || *suite >> 8 != 0x13)
+            .collect()
+    } else {
+        conf.cipher_suites.clone()
+    };
+    put_u16(&mut body, (suites.len() * 2) as u16);
+    for suite in suites {
+        put_u16(&mut body, suite);
+    }
+    // no compression
+    body.extend([1, 0]);
+
+    let mut extensions = vec![];
+    if let Some(name) = server_name.filter(|name| name.parse::<IpAddr>().is_err()) {
+        let mut list = vec![0];
+        put_u16(&mut list, name.len() as u16);
+        list.extend(name.as_bytes());
+        let mut ext = vec![];
+        put_u16(&mut ext, list.len() as u16);
+        ext.extend(list);
+        put_extension(&mut extensions, EXT_SERVER_NAME, &ext);
+    }
+    put_extension(
+        &mut extensions,
+        EXT_SUPPORTED_GROUPS,
+        &u16_list(SUPPORTED_GROUPS, 2),
+    );
+    put_extension(&mut extensions, EXT_EC_POINT_FORMATS, &[1, 0]);
+    put_extension(
+        &mut extensions,
+        EXT_SIGNATURE_ALGORITHMS,
+        &u16_list(SIGNATURE_ALGORITHMS, 2),
+    );
+    if !conf.alpn.is_empty() {
+        let mut protocols = vec![];
+        for protocol in &conf.alpn {
+            protocols.push(protocol.len() as u8);
+            protocols.extend(protocol.as_bytes());
+        }
+        let mut ext = vec![];
+        put_u16(&mut ext, protocols.len() as u16);
+        ext.extend(protocols);
+        put_extension(&mut extensions, EXT_ALPN, &ext);
+    }
+    put_extension(&mut extensions, EXT_EXTENDED_MASTER_SECRET, &[]);
+    if tls13 {
+        put_extension(
+            &mut extensions,
+            EXT_SUPPORTED_VERSIONS,
+            &u16_list(&[0x0304, 0x0303], 1),
+        );
+        put_extension(&mut extensions, EXT_PSK_KEY_EXCHANGE_MODES, &[1, 1]);
+        // we never finish the handshake, so any 32 bytes do as our x25519 share
+        let mut share = vec![];
+        put_u16(&mut share, GROUP_X25519);
+        put_u16(&mut share, 32);
+        share.extend(rng.gen::<[u8; 32]>());
+        let mut ext = vec![];
+        put_u16(&mut ext, share.len() as u16);
+        ext.extend(share);
+        put_extension(&mut extensions, EXT_KEY_SHARE, &ext);
+    }
+    put_u16(&mut body, extensions.len() as u16);
+    body.extend(extensions);
+
+    let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+    put_u24(&mut handshake, body.len());
+    handshake.extend(body);
+
+    let mut record = vec![CONTENT_HANDSHAKE];
+    put_u16(&mut record, 0x0301);
+    put_u16(&mut record, handshake.len() as u16);
+    record.extend(handshake);
+    record
+}
+
+/// Parse the records a server sent in reply to our ClientHello. The flight is complete with
+/// ServerHelloDone, an alert, or once the server switches to encrypted records as in 1.3.
+pub fn parse_server_flight(data: &[u8]) -> Result<ServerFlight, TlsError> {
+    let record_type = CONTENT_CHANGE_CIPHER_SPEC..=CONTENT_APPLICATION_DATA;
+    if matches!(data.first(), Some(t) if !record_type.contains(t))
+        || matches!(data.get(1), Some(major) if *major != 3)
+    {
+        return Err(TlsError::NotTls);
+    }
+    let mut flight = ServerFlight::default();
+    // handshake messages may span records, so the fragments are joined first
+    let mut handshake = vec![];
+    let mut rest = data;
+    while rest.len() >= 5 {
+        let content_type = rest[0];
+        if rest[1] != 3 {
+            return Err(TlsError::NotTls);
+        }
+        let len = usize::from(u16::from_be_bytes([rest[3], rest[4]]));
+        let fragment = match rest.get(5..5 + len) {
+            Some(fragment) => fragment,
+            None => break,
+        };
+        rest = &rest[5 + len..];
+        match content_type {
+            CONTENT_HANDSHAKE => handshake.extend_from_slice(fragment),
+            CONTENT_ALERT => {
+                flight.info.alert = fragment.get(1).copied();
+                flight.complete = true;
+            }
+            CONTENT_CHANGE_CIPHER_SPEC | CONTENT_APPLICATION_DATA => flight.complete = true,
+            _ => return Err(TlsError::NotTls),
+        }
+    }
+    let mut messages = &handshake[..];
+    while messages.len() >= 4 {
+        let len = read_u24(&messages[1..4]);
+        let message = match messages.get(4..4 + len) {
+            Some(message) => message,
+            None => break,
+        };
+        match messages[0] {
+            HANDSHAKE_SERVER_HELLO => {
+                parse_server_hello(message, &mut flight.info)?;
+                // everything after the ServerHello of 1.3 is encrypted
+                if flight.info.version.as_deref() == Some("TLSv1.3") {
+                    flight.complete = true;
+                }
+            }
+            HANDSHAKE_CERTIFICATE => {
+                flight.info.certificates = parse_certificates(message)?;
+            }
+            HANDSHAKE_SERVER_HELLO_DONE => flight.complete = true,
+            _ => {}
+        }
+        messages = &messages[4 + len..];
+    }
+    Ok(flight)
+}
+
+fn parse_server_hello(message: &[u8], info: &mut TlsInfo) -> Result<(), TlsError> {
+    let malformed = TlsError::Malformed("ServerHello");
+    let mut reader = Reader(message);
+    let mut version = reader.u16().ok_or_else(|| malformed.clone())?;
+    reader.take(32).ok_or_else(|| malformed.clone())?;
+    let session_id_len = reader.u8().ok_or_else(|| malformed.clone())?;
+    reader
+        .take(usize::from(session_id_len))
+        .ok_or_else(|| malformed.clone())?;
+    let suite = reader.u16().ok_or_else(|| malformed.clone())?;
+    reader.u8().ok_or_else(|| malformed.clone())?;
+
+    if let Some(extensions_len) = reader.u16() {
+        let mut extensions = Reader(
+            reader
+                .take(usize::from(extensions_len))
+                .ok_or_else(|| malformed.clone())?,
+        );
+        while let (Some(ext_type), Some(ext_len)) = (extensions.u16(), extensions.u16()) {
+            let mut ext = Reader(
+                extensions
+                    .take(usize::from(ext_len))
+                    .ok_or_else(|| malformed.clone())?,
+            );
+            match ext_type {
+                EXT_SUPPORTED_VERSIONS => {
+                    version = ext.u16().ok_or_else(|| malformed.clone())?;
+                }
+                EXT_ALPN => {
+                    ext.u16().ok_or_else(|| malformed.clone())?;
+                    let len = ext.u8().ok_or_else(|| malformed.clone())?;
+                    let protocol = ext
+                        .take(usize::from(len))
+                        .ok_or_else(|| malformed.clone())?;
+                    info.alpn = Some(String::from_utf8_lossy(protocol).into_owned());
+                }
+                _ => {}
+            }
+        }
+    }
+    info.version = Some(version_name(version));
+    info.cipher_suite = Some(cipher_suite_name(suite));
+    Ok(())
+}
+
+fn parse_certificates(message: &[u8]) -> Result<Vec<Certificate>, TlsError> {
+    let malformed = TlsError::Malformed("Certificate");
+    let mut reader = Reader(message);
+    let len = reader.u24().ok_or_else(|| malformed.clone())?;
+    let mut list = Reader(reader.take(len).ok_or_else(|| malformed.clone())?);
+    let mut certificates = vec![];
+    while let Some(len) = list.u24() {
+        let der = list.take(len).ok_or_else(|| malformed.clone())?;
+        certificates.push(Certificate::from_der(der));
+    }
+    Ok(certificates)
+}
+
+fn version_name(version: u16) -> String {
+    match version {
+        0x0300 => "SSLv3".into(),
+        0x0301 => "TLSv1.0".into(),
+        0x0302 => "TLSv1.1".into(),
+        0x0303 => "TLSv1.2".into(),
+        0x0304 => "TLSv1.3".into(),
+        _ => format!("0x{:04x}", version),
+    }
+}
+
+fn cipher_suite_name(suite: u16) -> String {
+    match CIPHER_SUITES.iter().find(|(s, _)| *s == suite) {
+        Some((_, name)) => name.to_string(),
+        None => format!("0x{:04x}", suite),
+    }
+}
+
+// Reads big endian integers and slices off the front of a message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        Some(read_u24(self.take(3)?))
+    }
+}
+
+fn read_u24(bytes: &[u8]) -> usize {
+    (usize::from(bytes[0]) << 16) | (usize::from(bytes[1]) << 8) | usize::from(bytes[2])
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend(value.to_be_bytes());
+}
+
+fn put_u24(buf: &mut Vec<u8>, value: usize) {
+    buf.extend(&(value as u32).to_be_bytes()[1..]);
+}
+
+fn put_extension(buf: &mut Vec<u8>, ext_type: u16, data: &[u8]) {
+    put_u16(buf, ext_type);
+    put_u16(buf, data.len() as u16);
+    buf.extend_from_slice(data);
+}
+
+// A list of u16 values with a length prefix of len_bytes bytes
+fn u16_list(values: &[u16], len_bytes: usize) -> Vec<u8> {
+    let mut list = vec![];
+    match len_bytes {
+        1 => list.push((values.len() * 2) as u8),
+        _ => put_u16(&mut list, (values.len() * 2) as u16),
+    }
+    for value in values {
+        put_u16(&mut list, *value);
+    }
+    list
+}
@@ -0,0 +1,47 @@
+/// Payload sent to a UDP port when the target doesn't carry its own data.
+/// Most UDP services stay silent unless they receive a valid request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UdpProbe {
+    pub service: &'static str,
+    pub payload: &'static [u8],
+}
+
+// TXT query for version.bind in the CHAOS class
+const DNS: &[u8] =
+    b"\x00\x06\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07version\x04bind\x00\x00\x10\x00\x03";
+
+// NBSTAT query for the wildcard name
+const NETBIOS_NS: &[u8] = b"\x80\xf0\x00\x10\x00\x01\x00\x00\x00\x00\x00\x00\x20CKAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\x00\x00\x21\x00\x01";
+
+// version 3 client request
+const NTP: &[u8] = b"\xe3\x00\x04\xfa\x00\x01\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+
+// SNMPv1 GetRequest for sysDescr.0 with community public
+const SNMP: &[u8] = b"\x30\x26\x02\x01\x00\x04\x06public\xa0\x19\x02\x01\x01\x02\x01\x00\x02\x01\x00\x30\x0e\x30\x0c\x06\x08\x2b\x06\x01\x02\x01\x01\x01\x00\x05\x00";
+
+// SQL Server Resolution Protocol broadcast request
+const MSSQL: &[u8] = b"\x02";
+
+const SSDP: &[u8] = b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: ssdp:all\r\n\r\n";
+
+// binding request with a fixed transaction id
+const STUN: &[u8] = b"\x00\x01\x00\x00\x21\x12\xa4\x42rscanrscanrs";
+
+// stats command behind the 8 byte UDP frame header
+const MEMCACHED: &[u8] = b"\x00\x00\x00\x00\x00\x01\x00\x00stats\r\n";
+
+/// The default probe for a well known UDP port
+pub fn default_probe(port: u16) -> Option<UdpProbe> {
+    let (service, payload) = match port {
+        53 => ("dns", DNS),
+        123 => ("ntp", NTP),
+        137 => ("netbios-ns", NETBIOS_NS),
+        161 => ("snmp", SNMP),
+        1434 => ("ms-sql-m", MSSQL),
+        1900 => ("ssdp", SSDP),
+        3478 => ("stun", STUN),
+        11211 => ("memcached", MEMCACHED),
+        _ => return None,
+    };
+    Some(UdpProbe { service, payload })
+}
@@ -0,0 +1,98 @@
+use siphasher::sip::SipHasher24;
+use std::convert::TryInto;
+use std::hash::Hasher;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+
+/// How far past the initial sequence number the acknowledgment number of a response may be.
+/// Everything we send on a connection after the SYN must fit into this window.
+pub const VALIDATION_WINDOW: u32 = u16::MAX as u32;
+
+/// Stateless validation of responses, in the spirit of SYN cookies. The initial sequence number
+/// of every probe is a keyed hash of the connection 4-tuple, so a response can be checked against
+/// the probe without remembering which probes were sent.
+#[derive(Clone, Debug)]
+pub struct Validator {
+    k0: u64,
+    k1: u64,
+}
+
+impl Validator {
+    pub fn new(secret: &[u8; 16]) -> Self {
+        Validator {
+            k0: u64::from_le_bytes(secret[..8].try_into().unwrap()),
+            k1: u64::from_le_bytes(secret[8..].try_into().unwrap()),
+        }
+    }
+
+    fn hash(&self, ips: &[IpAddr], ports: &[u16]) -> u64 {
+        let mut hasher = SipHasher24::new_with_keys(self.k0, self.k1);
+        for ip in ips {
+            match ip {
+                IpAddr::V4(ip) => hasher.write(&ip.octets()),
+                IpAddr::V6(ip) => hasher.write(&ip.octets()),
+            }
+        }
+        for port in ports {
+            hasher.write(&port.to_be_bytes());
+        }
+        hasher.finish()
+    }
+
+    /// The source port used for probes to remote_ip:remote_port, picked from the range by a
+    /// keyed hash of the target so that responses can be matched without keeping state
+    pub fn src_port(
+        &self,
+        remote_ip: IpAddr,
+        remote_port: u16,
+        src_ports: &RangeInclusive<u16>,
+    ) -> u16 {
+        let start = *src_ports.start();
+        let count = (*src_ports.end() as u64).saturating_sub(start as u64) + 1;
+        let offset = self.hash(&[remote_ip], &[remote_port]) % count;
+        start + offset as u16
+    }
+
+    /// The initial sequence number of a probe sent from local_ip:local_port to remote_ip:remote_port
+    pub fn tcp_seq(
+        &self,
+        local_ip: IpAddr,
+        remote_ip: IpAddr,
+        local_port: u16,
+        remote_port: u16,
+    ) -> u32 {
+        self.hash(&[local_ip, remote_ip], &[local_port, remote_port]) as u32
+    }
+
+    /// Cookie for probes without ports, such as ICMP echo requests
+    pub fn echo_cookie(&self, local_ip: IpAddr, remote_ip: IpAddr) -> u32 {
+        self.hash(&[local_ip, remote_ip], &[]) as u32
+    }
+
+    /// Check that a SYN-ACK acknowledges exactly our SYN
+    pub fn check_synack(
+        &self,
+        local_ip: IpAddr,
+        remote_ip: IpAddr,
+        local_port: u16,
+        remote_port: u16,
+        ack_number: u32,
+    ) -> bool {
+        let seq = self.tcp_seq(local_ip, remote_ip, local_port, remote_port);
+        ack_number.wrapping_sub(1) == seq
+    }
+
+    /// Check that a segment acknowledges our SYN, plus at most VALIDATION_WINDOW bytes sent after it
+    pub fn check_ack(
+        &self,
+        local_ip: IpAddr,
+        remote_ip: IpAddr,
+        local_port: u16,
+        remote_port: u16,
+        ack_number: u32,
+    ) -> bool {
+        let seq = self.tcp_seq(local_ip, remote_ip, local_port, remote_port);
+        let delta = ack_number.wrapping_sub(seq);
+        (1..=1 + VALIDATION_WINDOW).contains(&delta)
+    }
+}
@@ -0,0 +1,866 @@
+use crate::packet_io::{lock, poll, PacketIo, DEFAULT_RECV_TIMEOUT};
+use crate::ring::TX_TIMEOUT;
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::ops::RangeInclusive;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_FRAME_COUNT: u32 = 4096;
+pub const DEFAULT_FRAME_SIZE: u32 = 4096;
+pub const DEFAULT_RING_SIZE: u32 = 2048;
+pub const DEFAULT_TX_BATCH: u32 = 64;
+
+// from linux/if_xdp.h
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x8000_0000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x1_0000_0000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x1_8000_0000;
+const XDP_COPY: u16 = 1 << 1;
+
+// from linux/if_link.h
+const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+
+// from linux/bpf.h
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_LINK_CREATE: libc::c_long = 28;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_PASS: i32 = 2;
+
+// from linux/ethtool.h and linux/sockios.h
+const SIOCETHTOOL: libc::c_ulong = 0x8946;
+const ETHTOOL_GCHANNELS: u32 = 0x3c;
+
+/// Where the XDP program redirecting frames to the socket runs
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum XdpMode {
+    /// generic XDP, after the kernel allocated a socket buffer for the frame. Works with any
+    /// driver, veth included, and copies every frame.
+    #[default]
+    Skb,
+    /// XDP in the driver, which must support it. Frames are copied only if the driver can't
+    /// place them in the UMEM itself.
+    Native,
+}
+
+/// The layout of the UMEM and the rings of an AF_XDP socket
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct XdpConfig {
+    pub mode: XdpMode,
+    /// frames of the UMEM, half of them for receiving and half for sending
+    pub frame_count: u32,
+    /// bytes per frame, 2048 or 4096
+    pub frame_size: u32,
+    /// entries per ring, a power of two and at least half the frame count
+    pub ring_size: u32,
+    /// frames queued on the send ring before the kernel is told to send them
+    pub tx_batch: u32,
+}
+
+impl Default for XdpConfig {
+    fn default() -> Self {
+        XdpConfig {
+            mode: XdpMode::default(),
+            frame_count: DEFAULT_FRAME_COUNT,
+            frame_size: DEFAULT_FRAME_SIZE,
+            ring_size: DEFAULT_RING_SIZE,
+            tx_batch: DEFAULT_TX_BATCH,
+        }
+    }
+}
+
+#[repr(C)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[repr(C)]
+struct SockaddrXdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct EthtoolChannels {
+    cmd: u32,
+    max_rx: u32,
+    max_tx: u32,
+    max_other: u32,
+    max_combined: u32,
+    rx_count: u32,
+    tx_count: u32,
+    other_count: u32,
+    combined_count: u32,
+}
+
+#[repr(C)]
+struct Ifreq {
+    ifr_name: [libc::c_char; libc::IFNAMSIZ],
+    ifr_data: *mut libc::c_void,
+    _pad: [u8; 16],
+}
+
+#[repr(C)]
+struct BpfInsn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl BpfInsn {
+    const fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        BpfInsn {
+            code,
+            regs: (src << 4) | dst,
+            off,
+            imm,
+        }
+    }
+}
+
+/// An AF_XDP socket on an interface with a single receive queue. An XDP program redirects the
+/// replies of a scan to the socket, which receives them into the UMEM shared with the kernel,
+/// and frames are sent from the UMEM in batches.
+#[derive(Clone, Debug)]
+pub struct Xdp {
+    socket: Arc<Socket>,
+    rx: Arc<Mutex<RxQueues>>,
+    tx: Arc<Mutex<TxQueues>>,
+    timeout: Duration,
+}
+
+#[derive(Debug)]
+struct Socket {
+    fd: libc::c_int,
+    umem: *mut u8,
+    umem_len: usize,
+    // the XSKMAP, the program and the link attaching the program to the interface
+    bpf_fds: Vec<libc::c_int>,
+}
+
+// The UMEM is partitioned between the rx and tx queues, each behind its own lock
+unsafe impl Send for Socket {}
+unsafe impl Sync for Socket {}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        // SAFETY: the fds and the mapping were created by Xdp::bind and nothing uses them
+        // anymore. Closing the link detaches the program.
+        unsafe {
+            for fd in self.bpf_fds.iter().rev() {
+                libc::close(*fd);
+            }
+            libc::close(self.fd);
+            if !self.umem.is_null() {
+                libc::munmap(self.umem as *mut libc::c_void, self.umem_len);
+            }
+        }
+    }
+}
+
+// One of the four rings shared with the kernel, indexed by free running producer and consumer
+// counters
+#[derive(Debug)]
+struct Queue {
+    map: *mut u8,
+    map_len: usize,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    desc: *mut u8,
+    mask: u32,
+}
+
+unsafe impl Send for Queue {}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        // SAFETY: the ring was mapped by Queue::map and is only accessed through self
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.map_len);
+        }
+    }
+}
+
+impl Queue {
+    fn map<T>(
+        fd: libc::c_int,
+        offsets: &XdpRingOffset,
+        size: u32,
+        pgoff: libc::off_t,
+    ) -> io::Result<Self> {
+        let map_len = offsets.desc as usize + size as usize * mem::size_of::<T>();
+        // SAFETY: the kernel maps the ring set up on the socket at pgoff
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                pgoff,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let map = map as *mut u8;
+        // SAFETY: the offsets the kernel reported lie within the ring
+        unsafe {
+            Ok(Queue {
+                map,
+                map_len,
+                producer: map.add(offsets.producer as usize) as *const AtomicU32,
+                consumer: map.add(offsets.consumer as usize) as *const AtomicU32,
+                desc: map.add(offsets.desc as usize),
+                mask: size - 1,
+            })
+        }
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        // SAFETY: the counter lives as long as the mapping
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        // SAFETY: the counter lives as long as the mapping
+        unsafe { &*self.consumer }
+    }
+
+    // SAFETY: T must be the entry type of the ring
+    unsafe fn read<T: Copy>(&self, index: u32) -> T {
+        ptr::read(
+            self.desc
+                .add((index & self.mask) as usize * mem::size_of::<T>()) as *const T,
+        )
+    }
+
+    // SAFETY: T must be the entry type of the ring
+    unsafe fn write<T>(&self, index: u32, entry: T) {
+        ptr::write(
+            self.desc
+                .add((index & self.mask) as usize * mem::size_of::<T>()) as *mut T,
+            entry,
+        )
+    }
+}
+
+#[derive(Debug)]
+struct RxQueues {
+    rx: Queue,
+    fill: Queue,
+    umem: *mut u8,
+}
+
+unsafe impl Send for RxQueues {}
+
+#[derive(Debug)]
+struct TxQueues {
+    tx: Queue,
+    completion: Queue,
+    umem: *mut u8,
+    frame_size: usize,
+    // UMEM addresses of the send frames which the kernel is done with
+    free: Vec<u64>,
+    batch: u32,
+    queued: u32,
+}
+
+unsafe impl Send for TxQueues {}
+
+impl Xdp {
+    /// Open an AF_XDP socket on the interface and attach the XDP program redirecting to it the tcp
+    /// and udp frames to `ports`, and ICMP echo replies and unreachables. Every other frame goes
+    /// to the kernel as usual. The program is detached once the last clone is dropped.
+    pub fn bind(if_name: &str, conf: &XdpConfig, ports: &RangeInclusive<u16>) -> io::Result<Self> {
+        if !conf.ring_size.is_power_of_two() || conf.frame_count / 2 > conf.ring_size {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "the ring size must be a power of two and at least half the frame count",
+            ));
+        }
+        let if_name = CString::new(if_name)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid interface name"))?;
+        // SAFETY: if_name is a valid C string
+        let if_index = unsafe { libc::if_nametoindex(if_name.as_ptr()) };
+        if if_index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // replies arriving on any other queue would never reach the socket
+        let queues = rx_queues(&if_name)?;
+        if queues > 1 {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "the interface has {} receive queues, AF_XDP needs it to have one",
+                    queues
+                ),
+            ));
+        }
+
+        // SAFETY: plain socket call, the fd is closed by Socket if anything below fails
+        let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut socket = Socket {
+            fd,
+            umem: ptr::null_mut(),
+            umem_len: 0,
+            bpf_fds: vec![],
+        };
+
+        let umem_len = conf.frame_count as usize * conf.frame_size as usize;
+        // SAFETY: an anonymous mapping, page aligned as the kernel requires
+        let umem = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                umem_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+                -1,
+                0,
+            )
+        };
+        if umem == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        socket.umem = umem as *mut u8;
+        socket.umem_len = umem_len;
+
+        let reg = XdpUmemReg {
+            addr: umem as u64,
+            len: umem_len as u64,
+            chunk_size: conf.frame_size,
+            headroom: 0,
+            flags: 0,
+            tx_metadata_len: 0,
+        };
+        setsockopt(fd, XDP_UMEM_REG, &reg)?;
+        for ring in [
+            XDP_UMEM_FILL_RING,
+            XDP_UMEM_COMPLETION_RING,
+            XDP_RX_RING,
+            XDP_TX_RING,
+        ]
+        .iter()
+        {
+            setsockopt(fd, *ring, &conf.ring_size)?;
+        }
+
+        let mut offsets = XdpMmapOffsets::default();
+        let mut len = mem::size_of::<XdpMmapOffsets>() as libc::socklen_t;
+        // SAFETY: offsets is a valid XdpMmapOffsets of the given size
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                &mut offsets as *mut XdpMmapOffsets as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if len as usize != mem::size_of::<XdpMmapOffsets>() {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "the kernel is too old for AF_XDP",
+            ));
+        }
+        let rx = Queue::map::<XdpDesc>(fd, &offsets.rx, conf.ring_size, XDP_PGOFF_RX_RING)?;
+        let tx = Queue::map::<XdpDesc>(fd, &offsets.tx, conf.ring_size, XDP_PGOFF_TX_RING)?;
+        let fill = Queue::map::<u64>(fd, &offsets.fr, conf.ring_size, XDP_UMEM_PGOFF_FILL_RING)?;
+        let completion = Queue::map::<u64>(
+            fd,
+            &offsets.cr,
+            conf.ring_size,
+            XDP_UMEM_PGOFF_COMPLETION_RING,
+        )?;
+
+        // the first half of the frames receive, the second half send
+        let rx_frames = conf.frame_count / 2;
+        for i in 0..rx_frames {
+            // SAFETY: the fill ring holds u64 addresses, and has room for all receive frames
+            unsafe { fill.write(i, u64::from(i) * u64::from(conf.frame_size)) };
+        }
+        fill.producer().store(rx_frames, Ordering::Release);
+        let free = (rx_frames..conf.frame_count)
+            .map(|i| u64::from(i) * u64::from(conf.frame_size))
+            .collect();
+
+        let (bind_flags, attach_flags) = match conf.mode {
+            XdpMode::Skb => (XDP_COPY, XDP_FLAGS_SKB_MODE),
+            XdpMode::Native => (0, XDP_FLAGS_DRV_MODE),
+        };
+        let addr = SockaddrXdp {
+            sxdp_family: AF_XDP as u16,
+            sxdp_flags: bind_flags,
+            sxdp_ifindex: if_index,
+            sxdp_queue_id: 0,
+            sxdp_shared_umem_fd: 0,
+        };
+        // SAFETY: addr is a valid sockaddr_xdp of the given size
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const SockaddrXdp as *const libc::sockaddr,
+                mem::size_of::<SockaddrXdp>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        attach_program(&mut socket, if_index, ports, attach_flags)?;
+
+        let rx = RxQueues {
+            rx,
+            fill,
+            umem: socket.umem,
+        };
+        let tx = TxQueues {
+            tx,
+            completion,
+            umem: socket.umem,
+            frame_size: conf.frame_size as usize,
+            free,
+            batch: conf.tx_batch.max(1),
+            queued: 0,
+        };
+        Ok(Xdp {
+            socket: Arc::new(socket),
+            rx: Arc::new(Mutex::new(rx)),
+            tx: Arc::new(Mutex::new(tx)),
+            timeout: DEFAULT_RECV_TIMEOUT,
+        })
+    }
+}
+
+impl PacketIo for Xdp {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        lock(&self.tx)?.send(self.socket.fd, frame)
+    }
+
+    fn recv(&mut self, frame: &mut [u8]) -> io::Result<Option<usize>> {
+        lock(&self.rx)?.recv(self.socket.fd, frame, self.timeout)
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        lock(&self.tx)?.kick(self.socket.fd)
+    }
+}
+
+impl RxQueues {
+    fn recv(
+        &mut self,
+        fd: libc::c_int,
+        frame: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<Option<usize>> {
+        loop {
+            let consumer = self.rx.consumer().load(Ordering::Relaxed);
+            if self.rx.producer().load(Ordering::Acquire) == consumer {
+                // polling also makes the kernel pick up the fill ring
+                if !poll(fd, libc::POLLIN, timeout)? {
+                    return Ok(None);
+                }
+                continue;
+            }
+            // SAFETY: the rx ring holds xdp_descs, and the kernel produced the one at consumer
+            let desc: XdpDesc = unsafe { self.rx.read(consumer) };
+            let len = (desc.len as usize).min(frame.len());
+            // SAFETY: the kernel placed the frame within the UMEM, in a receive frame
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    self.umem.add(desc.addr as usize),
+                    frame.as_mut_ptr(),
+                    len,
+                );
+            }
+            self.rx
+                .consumer()
+                .store(consumer.wrapping_add(1), Ordering::Release);
+
+            // hand the frame back, the fill ring has room for every receive frame
+            let producer = self.fill.producer().load(Ordering::Relaxed);
+            // SAFETY: the fill ring holds u64 addresses
+            unsafe { self.fill.write(producer, desc.addr) };
+            self.fill
+                .producer()
+                .store(producer.wrapping_add(1), Ordering::Release);
+            return Ok(Some(len));
+        }
+    }
+}
+
+impl TxQueues {
+    fn send(&mut self, fd: libc::c_int, frame: &[u8]) -> io::Result<()> {
+        if frame.len() > self.frame_size {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "frame does not fit in a UMEM frame",
+            ));
+        }
+        self.reclaim();
+        let mut full_since = None;
+        while self.free.is_empty() {
+            if full_since.get_or_insert_with(Instant::now).elapsed() >= TX_TIMEOUT {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    "the kernel sent nothing from the full send ring",
+                ));
+            }
+            // every send frame is queued, wait for the kernel to send some of them
+            self.kick(fd)?;
+            poll(fd, libc::POLLOUT, DEFAULT_RECV_TIMEOUT)?;
+            self.reclaim();
+        }
+        let addr = self.free.pop().unwrap_or_default();
+        // SAFETY: addr is a free send frame, which the kernel doesn't touch until we queue it
+        unsafe {
+            ptr::copy_nonoverlapping(frame.as_ptr(), self.umem.add(addr as usize), frame.len());
+        }
+        // the tx ring has room for every send frame
+        let producer = self.tx.producer().load(Ordering::Relaxed);
+        let desc = XdpDesc {
+            addr,
+            len: frame.len() as u32,
+            options: 0,
+        };
+        // SAFETY: the tx ring holds xdp_descs
+        unsafe { self.tx.write(producer, desc) };
+        self.tx
+            .producer()
+            .store(producer.wrapping_add(1), Ordering::Release);
+        self.queued += 1;
+        if self.queued >= self.batch {
+            self.kick(fd)?;
+        }
+        Ok(())
+    }
+
+    // Take back the send frames the kernel is done with
+    fn reclaim(&mut self) {
+        let producer = self.completion.producer().load(Ordering::Acquire);
+        let mut consumer = self.completion.consumer().load(Ordering::Relaxed);
+        while consumer != producer {
+            // SAFETY: the completion ring holds u64 addresses
+            self.free.push(unsafe { self.completion.read(consumer) });
+            consumer = consumer.wrapping_add(1);
+        }
+        self.completion
+            .consumer()
+            .store(consumer, Ordering::Release);
+    }
+
+    // Tell the kernel to send the queued frames. In generic mode the kernel sends at most 32
+    // frames per call, so it is told again for as long as it takes frames off the tx ring.
+    fn kick(&mut self, fd: libc::c_int) -> io::Result<()> {
+        if self.queued == 0 {
+            return Ok(());
+        }
+        loop {
+            let consumer = self.tx.consumer().load(Ordering::Acquire);
+            if consumer == self.tx.producer().load(Ordering::Relaxed) {
+                self.queued = 0;
+                return Ok(());
+            }
+            // SAFETY: a send without a buffer, the frames are taken from the tx ring
+            let ret =
+                unsafe { libc::sendto(fd, ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0) };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS) => {}
+                    _ => return Err(e),
+                }
+            }
+            // the kernel is busy with the ring, or sends in the background as drivers do in
+            // native mode, and picks the rest up with the next kick
+            if self.tx.consumer().load(Ordering::Acquire) == consumer {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// The receive queues of the interface, which are the channels ethtool reports. Interfaces
+// without channels have one. AF_XDP sockets don't pass ioctls on to the interface, so the
+// ioctl goes through an AF_INET socket, as with ethtool.
+fn rx_queues(if_name: &CString) -> io::Result<u32> {
+    let mut channels = EthtoolChannels {
+        cmd: ETHTOOL_GCHANNELS,
+        ..EthtoolChannels::default()
+    };
+    let mut ifr = Ifreq {
+        ifr_name: [0; libc::IFNAMSIZ],
+        ifr_data: &mut channels as *mut EthtoolChannels as *mut libc::c_void,
+        _pad: [0; 16],
+    };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(if_name.as_bytes()) {
+        *dst = *src as libc::c_char;
+    }
+    // SAFETY: plain socket call, the fd is closed below
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: ifr is a valid ifreq pointing to an ethtool_channels, both outlive the call
+    let ret = unsafe { libc::ioctl(fd, SIOCETHTOOL as _, &mut ifr as *mut Ifreq) };
+    let e = io::Error::last_os_error();
+    // SAFETY: fd is the socket opened above, which nothing else uses
+    unsafe { libc::close(fd) };
+    if ret < 0 {
+        if e.raw_os_error() == Some(libc::EOPNOTSUPP) {
+            return Ok(1);
+        }
+        return Err(e);
+    }
+    Ok((channels.rx_count + channels.combined_count).max(1))
+}
+
+// Create an XSKMAP holding the socket at queue 0, and attach a program redirecting the replies of
+// the scan to the map, and every other frame to the kernel:
+//
+//   r7 = ctx->rx_queue_index
+//   if the frame is IPv4, not a later fragment, and carries tcp or udp to one of the ports, or
+//      an ICMP echo reply or destination unreachable
+//   or if it is IPv6 and carries tcp or udp to one of the ports, or an ICMPv6 echo reply or
+//      destination unreachable
+//     return bpf_redirect_map(&xskmap, r7, XDP_PASS)
+//   return XDP_PASS
+//
+// Frames with VLAN tags or IPv6 extension headers go to the kernel.
+fn attach_program(
+    socket: &mut Socket,
+    if_index: u32,
+    ports: &RangeInclusive<u16>,
+    attach_flags: u32,
+) -> io::Result<()> {
+    let mut attr = [0u32; 32];
+    attr[0] = BPF_MAP_TYPE_XSKMAP;
+    attr[1] = 4;
+    attr[2] = 4;
+    attr[3] = 1;
+    let map_fd = bpf(BPF_MAP_CREATE, &attr)?;
+    socket.bpf_fds.push(map_fd);
+
+    let key = 0u32;
+    let value = socket.fd as u32;
+    let mut attr = [0u32; 32];
+    attr[0] = map_fd as u32;
+    set_u64(&mut attr, 2, &key as *const u32 as u64);
+    set_u64(&mut attr, 4, &value as *const u32 as u64);
+    bpf(BPF_MAP_UPDATE_ELEM, &attr)?;
+
+    // jump offsets count the instructions after the jump, the comments give the targets. The
+    // ethertype and the fragment offset are loaded in network order, so their constants are
+    // swapped.
+    let insns = [
+        // 0: BPF_LDX | BPF_MEM | BPF_W, offsetof(struct xdp_md, rx_queue_index)
+        BpfInsn::new(0x61, 7, 1, 16, 0),
+        // 1-2: r2 = ctx->data, r3 = ctx->data_end
+        BpfInsn::new(0x61, 2, 1, 0, 0),
+        BpfInsn::new(0x61, 3, 1, 4, 0),
+        // 3-5: BPF_ALU64 | BPF_MOV | BPF_X, BPF_ALU64 | BPF_ADD | BPF_K, and
+        // BPF_JMP | BPF_JGT | BPF_X to pass unless the ethernet header is there
+        BpfInsn::new(0xbf, 4, 2, 0, 0),
+        BpfInsn::new(0x07, 4, 0, 0, 14),
+        BpfInsn::new(0x2d, 4, 3, 53, 0),
+        // 6-8: BPF_LDX | BPF_MEM | BPF_H of the ethertype, BPF_JMP | BPF_JEQ | BPF_K to ipv6 on
+        // 0x86dd, BPF_JMP | BPF_JNE | BPF_K to pass unless 0x0800
+        BpfInsn::new(0x69, 5, 2, 12, 0),
+        BpfInsn::new(0x15, 5, 0, 15, 0xdd86),
+        BpfInsn::new(0x55, 5, 0, 50, 0x0008),
+        // 9-11: ipv4, pass unless the IPv4 header is there
+        BpfInsn::new(0xbf, 4, 2, 0, 0),
+        BpfInsn::new(0x07, 4, 0, 0, 34),
+        BpfInsn::new(0x2d, 4, 3, 47, 0),
+        // 12-14: pass if the fragment offset isn't 0, with BPF_ALU64 | BPF_AND | BPF_K
+        BpfInsn::new(0x69, 5, 2, 20, 0),
+        BpfInsn::new(0x57, 5, 0, 0, 0xff1f),
+        BpfInsn::new(0x55, 5, 0, 44, 0),
+        // 15: BPF_LDX | BPF_MEM | BPF_B of the protocol
+        BpfInsn::new(0x71, 6, 2, 23, 0),
+        // 16-20: r2 += 14 + ihl * 4, with BPF_ALU64 | BPF_LSH | BPF_K and
+        // BPF_ALU64 | BPF_ADD | BPF_X
+        BpfInsn::new(0x71, 5, 2, 14, 0),
+        BpfInsn::new(0x57, 5, 0, 0, 0x0f),
+        BpfInsn::new(0x67, 5, 0, 0, 2),
+        BpfInsn::new(0x07, 2, 0, 0, 14),
+        BpfInsn::new(0x0f, 2, 5, 0, 0),
+        // 21-22: to icmp on ICMP, BPF_JMP | BPF_JA to l4 otherwise
+        BpfInsn::new(0x15, 6, 0, 17, 1),
+        BpfInsn::new(0x05, 0, 0, 6, 0),
+        // 23-25: ipv6, pass unless the IPv6 header is there
+        BpfInsn::new(0xbf, 4, 2, 0, 0),
+        BpfInsn::new(0x07, 4, 0, 0, 54),
+        BpfInsn::new(0x2d, 4, 3, 33, 0),
+        // 26-28: the next header, r2 += 54, and to icmpv6 on ICMPv6
+        BpfInsn::new(0x71, 6, 2, 20, 0),
+        BpfInsn::new(0x07, 2, 0, 0, 54),
+        BpfInsn::new(0x15, 6, 0, 17, 58),
+        // 29-30: l4, to ports on tcp, pass unless udp
+        BpfInsn::new(0x15, 6, 0, 1, 6),
+        BpfInsn::new(0x55, 6, 0, 28, 17),
+        // 31-33: ports, pass unless the ports are there
+        BpfInsn::new(0xbf, 4, 2, 0, 0),
+        BpfInsn::new(0x07, 4, 0, 0, 4),
+        BpfInsn::new(0x2d, 4, 3, 25, 0),
+        // 34-35: the destination port, BPF_ALU | BPF_END | BPF_TO_BE to host order
+        BpfInsn::new(0x69, 5, 2, 2, 0),
+        BpfInsn::new(0xdc, 5, 0, 0, 16),
+        // 36-38: BPF_JMP | BPF_JLT | BPF_K and BPF_JMP | BPF_JGT | BPF_K to pass outside the
+        // ports, to redirect otherwise
+        BpfInsn::new(0xa5, 5, 0, 22, i32::from(*ports.start())),
+        BpfInsn::new(0x25, 5, 0, 21, i32::from(*ports.end())),
+        BpfInsn::new(0x05, 0, 0, 14, 0),
+        // 39-45: icmp, pass unless the type is there, to redirect on echo reply or destination
+        // unreachable
+        BpfInsn::new(0xbf, 4, 2, 0, 0),
+        BpfInsn::new(0x07, 4, 0, 0, 1),
+        BpfInsn::new(0x2d, 4, 3, 17, 0),
+        BpfInsn::new(0x71, 5, 2, 0, 0),
+        BpfInsn::new(0x15, 5, 0, 9, 0),
+        BpfInsn::new(0x15, 5, 0, 8, 3),
+        BpfInsn::new(0x05, 0, 0, 13, 0),
+        // 46-52: icmpv6, the same for ICMPv6
+        BpfInsn::new(0xbf, 4, 2, 0, 0),
+        BpfInsn::new(0x07, 4, 0, 0, 1),
+        BpfInsn::new(0x2d, 4, 3, 10, 0),
+        BpfInsn::new(0x71, 5, 2, 0, 0),
+        BpfInsn::new(0x15, 5, 0, 2, 129),
+        BpfInsn::new(0x15, 5, 0, 1, 1),
+        BpfInsn::new(0x05, 0, 0, 6, 0),
+        // 53-54: redirect, BPF_LD | BPF_DW | BPF_IMM of the map, which takes two instructions
+        BpfInsn::new(0x18, 1, BPF_PSEUDO_MAP_FD, 0, map_fd),
+        BpfInsn::new(0, 0, 0, 0, 0),
+        // 55-56: r2 = r7, r3 = XDP_PASS with BPF_ALU64 | BPF_MOV | BPF_K
+        BpfInsn::new(0xbf, 2, 7, 0, 0),
+        BpfInsn::new(0xb7, 3, 0, 0, XDP_PASS),
+        // 57-58: BPF_JMP | BPF_CALL, BPF_JMP | BPF_EXIT
+        BpfInsn::new(0x85, 0, 0, 0, BPF_FUNC_REDIRECT_MAP),
+        BpfInsn::new(0x95, 0, 0, 0, 0),
+        // 59-60: pass
+        BpfInsn::new(0xb7, 0, 0, 0, XDP_PASS),
+        BpfInsn::new(0x95, 0, 0, 0, 0),
+    ];
+    let license = b"GPL\0";
+    let mut attr = [0u32; 32];
+    attr[0] = BPF_PROG_TYPE_XDP;
+    attr[1] = insns.len() as u32;
+    set_u64(&mut attr, 2, insns.as_ptr() as u64);
+    set_u64(&mut attr, 4, license.as_ptr() as u64);
+    let prog_fd = bpf(BPF_PROG_LOAD, &attr)?;
+    socket.bpf_fds.push(prog_fd);
+
+    let mut attr = [0u32; 32];
+    attr[0] = prog_fd as u32;
+    attr[1] = if_index;
+    attr[2] = BPF_XDP;
+    attr[3] = attach_flags;
+    let link_fd = bpf(BPF_LINK_CREATE, &attr)?;
+    socket.bpf_fds.push(link_fd);
+    Ok(())
+}
+
+// A u64 field of a bpf_attr, at the index of its first u32
+fn set_u64(attr: &mut [u32; 32], index: usize, value: u64) {
+    attr[index] = value as u32;
+    attr[index + 1] = (value >> 32) as u32;
+}
+
+fn bpf(cmd: libc::c_long, attr: &[u32; 32]) -> io::Result<libc::c_int> {
+    // SAFETY: attr is a bpf_attr of the given size, whose pointers are valid for the call
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr.as_ptr(),
+            mem::size_of::<[u32; 32]>(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as libc::c_int)
+}
+
+fn setsockopt<T>(fd: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    // SAFETY: value is a valid T of the given size
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_XDP,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,331 @@
+mod setup;
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use afpacket::sync::RawPacketStream;
+use etherparse::{ip_number, PacketBuilder, SlicedPacket, TransportSlice};
+
+use rscan::packet::build_tcp_response;
+use rscan::packet_io::{Backend, Interface, PacketIo};
+use rscan::ring::{Ring, RingConfig};
+use rscan::xdp::{Xdp, XdpConfig};
+use rscan::{ScanConfig, Scanner, Target, TcpFlags};
+
+const SRC_IP: [u8; 4] = [192, 168, 69, 1];
+const DST_IP: [u8; 4] = [192, 168, 69, 2];
+const SRC_IPV6: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+const DST_IPV6: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+
+const MAX_PACKET_SIZE: usize = 1500;
+
+fn synacker<P: PacketIo>(mut io: P, shutdown: Arc<AtomicBool>) {
+    let mut rx_pkt = [0; MAX_PACKET_SIZE];
+    let mut tx_pkt = [0; MAX_PACKET_SIZE];
+    while !shutdown.load(Ordering::Relaxed) {
+        let len = match io.recv(&mut rx_pkt).expect("failed to read pkt") {
+            Some(len) => len,
+            None => {
+                io.flush().expect("failed to flush");
+                continue;
+            }
+        };
+        let sliced = match SlicedPacket::from_ethernet(&rx_pkt[..len]) {
+            Ok(sliced) => sliced,
+            Err(e) => {
+                log::error!("Err {:?}", e);
+                continue;
+            }
+        };
+        match &sliced.transport {
+            Some(TransportSlice::Tcp(tcp))
+                if tcp.syn() && !tcp.ack() && tcp.source_port() >= 10000 => {}
+            _ => continue,
+        }
+        if let Some(len) = build_tcp_response(&sliced, &[], &mut tx_pkt) {
+            io.send(&tx_pkt[..len]).expect("failed to write pkt");
+        }
+    }
+}
+
+#[test]
+fn ring_syn_test() {
+    fn test_fn(dev1: String, dev2: String) {
+        let scan_config = ScanConfig {
+            handshakes_file: None,
+            backend: Backend::Ring(RingConfig::default()),
+            ..setup::scan_config()
+        };
+        let scanner = Scanner::open(&dev1, scan_config).expect("failed to start scanner");
+        let ring = Ring::bind(&dev2, &RingConfig::default()).expect("failed to bind ring");
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let synacker_shutdown = shutdown.clone();
+        let synacker_handle = thread::Builder::new()
+            .name("synacker test".into())
+            .spawn(move || synacker(ring, synacker_shutdown))
+            .expect("failed to start synacker thread");
+
+        thread::sleep(Duration::from_secs(1));
+
+        let ports: Vec<u16> = (1..10000).collect();
+        for port in ports.iter() {
+            scanner
+                .scan_target(&Target {
+                    ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+                    port: *port,
+                    ip_number: u8::from(ip_number::TCP),
+                    data: None,
+                    hostname: None,
+                })
+                .expect("failed to scan target");
+        }
+
+        let mut scan_results = vec![];
+        let start = Instant::now();
+        while scan_results.len() < ports.len() && start.elapsed() < Duration::from_secs(5) {
+            if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+                if scan_result.tcp_flags == Some(TcpFlags::Synack) {
+                    scan_results.push(scan_result);
+                }
+            }
+        }
+
+        scanner.shutdown().expect("failed to shut down scanner");
+        shutdown.swap(true, Ordering::Relaxed);
+        synacker_handle
+            .join()
+            .expect("failed to wait for synacker thread");
+
+        assert_eq!(scan_results.len(), ports.len());
+    }
+
+    setup::run_test_with_devs(test_fn);
+}
+
+#[test]
+fn xdp_syn_test() {
+    fn test_fn(dev1: String, dev2: String) {
+        // both ends receive the whole burst of SYNs or SYN-ACKs before they took many of them
+        let xdp_config = XdpConfig {
+            frame_count: 32768,
+            frame_size: 2048,
+            ring_size: 16384,
+            ..XdpConfig::default()
+        };
+        let scan_config = ScanConfig {
+            handshakes_file: None,
+            backend: Backend::Xdp(xdp_config.clone()),
+            ..setup::scan_config()
+        };
+        let scanner = Scanner::open(&dev1, scan_config).expect("failed to start scanner");
+        let xdp = Xdp::bind(&dev2, &xdp_config, &(1..=9999)).expect("failed to bind xdp socket");
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let synacker_shutdown = shutdown.clone();
+        let synacker_handle = thread::Builder::new()
+            .name("synacker test".into())
+            .spawn(move || synacker(xdp, synacker_shutdown))
+            .expect("failed to start synacker thread");
+
+        thread::sleep(Duration::from_secs(1));
+
+        let ports: Vec<u16> = (1..10000).collect();
+        for port in ports.iter() {
+            scanner
+                .scan_target(&Target {
+                    ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+                    port: *port,
+                    ip_number: u8::from(ip_number::TCP),
+                    data: None,
+                    hostname: None,
+                })
+                .expect("failed to scan target");
+        }
+
+        let mut scan_results = vec![];
+        let start = Instant::now();
+        while scan_results.len() < ports.len() && start.elapsed() < Duration::from_secs(5) {
+            if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+                if scan_result.tcp_flags == Some(TcpFlags::Synack) {
+                    scan_results.push(scan_result);
+                }
+            }
+        }
+
+        scanner.shutdown().expect("failed to shut down scanner");
+        shutdown.swap(true, Ordering::Relaxed);
+        synacker_handle
+            .join()
+            .expect("failed to wait for synacker thread");
+
+        assert_eq!(scan_results.len(), ports.len());
+    }
+
+    setup::run_test_with_devs(test_fn);
+}
+
+// The frame a PacketBuilder step writes, with the payload
+macro_rules! frame {
+    ($builder:expr, $payload:expr) => {{
+        let builder = $builder;
+        let payload: &[u8] = $payload;
+        let mut frame = Vec::with_capacity(builder.size(payload.len()));
+        builder
+            .write(&mut frame, payload)
+            .expect("failed to write pkt");
+        frame
+    }};
+}
+
+// Only the replies to the scan are redirected to the AF_XDP socket, every other frame reaches the
+// kernel and with it the packet sockets on the interface
+#[test]
+fn xdp_pass_test() {
+    fn test_fn(dev1: String, dev2: String) {
+        let eth = || PacketBuilder::ethernet2([0; 6], [0; 6]);
+        let replies = [
+            frame!(
+                eth()
+                    .ipv4(DST_IP, SRC_IP, 64)
+                    .tcp(80, 10000, 0, 65535)
+                    .syn()
+                    .ack(1),
+                &[]
+            ),
+            frame!(eth().ipv4(DST_IP, SRC_IP, 64).udp(53, 10999), b"reply"),
+            frame!(eth().ipv4(DST_IP, SRC_IP, 64).icmpv4_echo_reply(1, 1), &[]),
+            frame!(
+                eth()
+                    .ipv6(DST_IPV6, SRC_IPV6, 64)
+                    .tcp(80, 10500, 0, 65535)
+                    .rst(),
+                &[]
+            ),
+            frame!(
+                eth().ipv6(DST_IPV6, SRC_IPV6, 64).icmpv6_echo_reply(1, 1),
+                &[]
+            ),
+        ];
+        let others = [
+            frame!(
+                eth()
+                    .ipv4(DST_IP, SRC_IP, 64)
+                    .tcp(10000, 80, 0, 65535)
+                    .syn(),
+                &[]
+            ),
+            frame!(eth().ipv4(DST_IP, SRC_IP, 64).udp(10999, 53), b"query"),
+            frame!(
+                eth().ipv4(DST_IP, SRC_IP, 64).icmpv4_echo_request(1, 1),
+                &[]
+            ),
+            frame!(eth().ipv6(DST_IPV6, SRC_IPV6, 64).udp(10500, 123), b"ntp"),
+            frame!(
+                eth().ipv6(DST_IPV6, SRC_IPV6, 64).icmpv6_echo_request(1, 1),
+                &[]
+            ),
+        ];
+
+        let mut xdp = Xdp::bind(&dev2, &XdpConfig::default(), &(10000..=10999))
+            .expect("failed to bind xdp socket");
+        let mut kernel = RawPacketStream::new().expect("failed to create raw packet stream");
+        kernel.bind(&dev2).expect("failed to bind");
+        let mut tx = RawPacketStream::new().expect("failed to create raw packet stream");
+        tx.bind(&dev1).expect("failed to bind");
+        for frame in replies.iter().chain(others.iter()) {
+            PacketIo::send(&mut tx, frame).expect("failed to write pkt");
+        }
+
+        let mut redirected = vec![];
+        let mut rx_pkt = [0; MAX_PACKET_SIZE];
+        while let Some(len) = xdp.recv(&mut rx_pkt).expect("failed to read pkt") {
+            redirected.push(rx_pkt[..len].to_vec());
+        }
+        let mut passed = vec![];
+        kernel
+            .set_recv_timeout(Duration::from_millis(100))
+            .expect("failed to set timeout");
+        while let Some(len) = PacketIo::recv(&mut kernel, &mut rx_pkt).expect("failed to read pkt")
+        {
+            passed.push(rx_pkt[..len].to_vec());
+        }
+
+        for (i, frame) in replies.iter().enumerate() {
+            assert!(redirected.contains(frame), "reply {}", i);
+            assert!(!passed.contains(frame), "reply {}", i);
+        }
+        for (i, frame) in others.iter().enumerate() {
+            assert!(!redirected.contains(frame), "frame {}", i);
+            assert!(passed.contains(frame), "frame {}", i);
+        }
+    }
+
+    setup::run_test_with_devs(test_fn);
+}
+
+// Send as many frames as possible from one end of the veth pair to the other with each backend,
+// and log the rates. Run with
+// RUST_LOG=info cargo test --test backend_test -- --ignored --nocapture
+#[test]
+#[ignore]
+fn backend_throughput_test() {
+    fn test_fn(dev1: String, dev2: String) {
+        let builder = PacketBuilder::ethernet2([0; 6], [0; 6])
+            .ipv4(SRC_IP, DST_IP, 20)
+            .tcp(10000, 80, 0, 65535)
+            .syn();
+        let mut frame = Vec::with_capacity(builder.size(0));
+        builder.write(&mut frame, &[]).expect("failed to write pkt");
+
+        let num_frames = 1_000_000;
+        let backends = [
+            ("afpacket", Backend::AfPacket),
+            ("ring", Backend::Ring(RingConfig::default())),
+            ("xdp", Backend::Xdp(XdpConfig::default())),
+        ];
+        for (name, backend) in backends.iter() {
+            let mut tx =
+                Interface::open(&dev1, backend, &(80..=80)).expect("failed to open tx interface");
+            let mut rx =
+                Interface::open(&dev2, backend, &(80..=80)).expect("failed to open rx interface");
+            rx.set_recv_timeout(Duration::from_millis(100))
+                .expect("failed to set timeout");
+            let received = Arc::new(AtomicU64::new(0));
+            let shutdown = Arc::new(AtomicBool::new(false));
+            let rx_received = received.clone();
+            let rx_shutdown = shutdown.clone();
+            let rx_handle = thread::spawn(move || {
+                let mut rx_pkt = [0; MAX_PACKET_SIZE];
+                while !rx_shutdown.load(Ordering::Relaxed) {
+                    if rx.recv(&mut rx_pkt).expect("failed to read pkt").is_some() {
+                        rx_received.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+
+            let start = Instant::now();
+            for _ in 0..num_frames {
+                tx.send(&frame).expect("failed to write pkt");
+            }
+            tx.flush().expect("failed to flush");
+            let tx_elapsed = start.elapsed();
+            thread::sleep(Duration::from_secs(1));
+            shutdown.swap(true, Ordering::Relaxed);
+            rx_handle.join().expect("failed to wait for rx thread");
+
+            let received = received.load(Ordering::Relaxed);
+            log::info!(
+                "{}: sent {} frames/s, received {} of {}",
+                name,
+                (num_frames as f64 / tx_elapsed.as_secs_f64()) as u64,
+                received,
+                num_frames
+            );
+            assert!(received > 0);
+        }
+    }
+
+    setup::run_test_with_devs(test_fn);
+}
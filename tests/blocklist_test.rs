@@ -0,0 +1,114 @@
+mod setup;
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use etherparse::ip_number;
+
+use afpacket::sync::RawPacketStream;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rscan::blocklist::Blocklist;
+use rscan::{ScanConfig, Scanner, Target};
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn write_list(contents: &str) -> String {
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+    let path: PathBuf = std::env::temp_dir().join(format!("rscan_blocklist_{}", rand_string));
+    fs::write(&path, contents).expect("failed to write list");
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn default_blocklist_test() {
+    let blocklist = Blocklist::with_defaults();
+    for blocked in &[
+        "10.1.2.3",
+        "127.0.0.1",
+        "192.168.69.2",
+        "224.0.0.1",
+        "255.255.255.255",
+        "::1",
+        "fe80::1",
+        "ff02::1",
+        "2001:db8::1",
+    ] {
+        assert!(!blocklist.is_allowed(ip(blocked)), "{}", blocked);
+    }
+    for allowed in &["1.1.1.1", "8.8.8.8", "172.32.0.1", "2606:4700::1111"] {
+        assert!(blocklist.is_allowed(ip(allowed)), "{}", allowed);
+    }
+
+    let blocklist = Blocklist::default();
+    assert!(blocklist.is_allowed(ip("10.1.2.3")));
+}
+
+#[test]
+fn blocklist_file_test() {
+    let blocklist_file = write_list(
+        "# opted out\n\
+         1.2.3.0/24\n\
+         \n\
+         5.6.7.8 # single host\n\
+         2606:4700::/32\n",
+    );
+    let allowlist_file = write_list("1.0.0.0/8\n5.0.0.0/8\n10.0.0.0/8\n2606::/16\n");
+
+    let blocklist = Blocklist::load(std::slice::from_ref(&blocklist_file), &[], true).unwrap();
+    assert!(!blocklist.is_allowed(ip("1.2.3.4")));
+    assert!(blocklist.is_allowed(ip("1.2.4.4")));
+    assert!(!blocklist.is_allowed(ip("5.6.7.8")));
+    assert!(blocklist.is_allowed(ip("5.6.7.9")));
+    assert!(!blocklist.is_allowed(ip("2606:4700::1111")));
+    assert!(blocklist.is_allowed(ip("2606:4800::1")));
+
+    let blocklist = Blocklist::load(&[blocklist_file], &[allowlist_file], true).unwrap();
+    // the blocklist wins over the allowlist
+    assert!(!blocklist.is_allowed(ip("1.2.3.4")));
+    assert!(blocklist.is_allowed(ip("1.2.4.4")));
+    // the allowlist overrides the default blocklist
+    assert!(blocklist.is_allowed(ip("10.1.2.3")));
+    // nothing outside the allowlist is scanned
+    assert!(!blocklist.is_allowed(ip("8.8.8.8")));
+    assert!(!blocklist.is_allowed(ip("2a00::1")));
+    assert!(blocklist.is_allowed(ip("2606:4800::1")));
+
+    let invalid_file = write_list("1.2.3.0/24\nnot an address\n");
+    let err = Blocklist::load(&[invalid_file], &[], true).unwrap_err();
+    assert!(err.to_string().contains(":2:"), "{}", err);
+}
+
+#[test]
+fn scanner_blocklist_test() {
+    fn test_fn(dev1_ps: RawPacketStream, _dev2_ps: RawPacketStream) {
+        let scan_config = ScanConfig {
+            blocklist_files: vec![write_list("192.168.69.2\n")],
+            default_blocklist: true,
+            log_blocked: true,
+            ..setup::scan_config()
+        };
+
+        let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
+        for ip in &["192.168.69.2", "192.168.69.3", "10.0.0.1", "ff02::1"] {
+            scanner
+                .scan_target(&Target {
+                    ip: ip.parse().unwrap(),
+                    port: 80,
+                    ip_number: u8::from(ip_number::TCP),
+                    data: None,
+                    hostname: None,
+                })
+                .expect("failed to scan target");
+        }
+        assert_eq!(scanner.stats().blocked(), 4);
+    }
+
+    setup::run_test(test_fn);
+}
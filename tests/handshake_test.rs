@@ -0,0 +1,210 @@
+use std::fs;
+use std::net::IpAddr;
+
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rscan::handshake::{get_service_handshakes, handshakes_for_port, Handshakes};
+
+fn write_handshakes(contents: &str) -> String {
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+    let path = std::env::temp_dir().join(format!("rscan_handshakes_{}.yaml", rand_string));
+    fs::write(&path, contents).expect("failed to write handshakes");
+    path.to_string_lossy().into_owned()
+}
+
+fn services(handshakes: &[&rscan::handshake::Handshake]) -> Vec<String> {
+    handshakes.iter().map(|h| h.service.clone()).collect()
+}
+
+#[test]
+fn handshake_order_test() {
+    let path = write_handshakes(
+        r#"
+- service: generic
+  request: AA==
+  response: AA==
+- service: http
+  request: AA==
+  response: AA==
+  ports: [80, "8000-8100"]
+  priority: 5
+- service: ssh
+  request: AA==
+  response: AA==
+  ports: [22]
+- service: http-alt
+  request: AA==
+  response: AA==
+  ports: [8080]
+  priority: 10
+"#,
+    );
+    let handshakes = get_service_handshakes(&path).expect("failed to load handshakes");
+
+    assert_eq!(
+        services(&handshakes_for_port(&handshakes, 22)),
+        vec!["ssh", "http-alt", "http", "generic"]
+    );
+    assert_eq!(
+        services(&handshakes_for_port(&handshakes, 80)),
+        vec!["http", "http-alt", "generic", "ssh"]
+    );
+    // both http handshakes list 8080, the higher priority goes first
+    assert_eq!(
+        services(&handshakes_for_port(&handshakes, 8080)),
+        vec!["http-alt", "http", "generic", "ssh"]
+    );
+    // no hints for the port, only priorities and file order count
+    assert_eq!(
+        services(&handshakes_for_port(&handshakes, 443)),
+        vec!["http-alt", "http", "generic", "ssh"]
+    );
+
+    // the order worked out at load time is the same on every port, including the edges of the
+    // port ranges
+    let ordered = Handshakes::new(handshakes.clone());
+    for port in [
+        0, 21, 22, 23, 79, 80, 81, 7999, 8000, 8080, 8100, 8101, 65535,
+    ] {
+        let precomputed: Vec<String> = ordered
+            .for_port(port)
+            .iter()
+            .map(|h| h.service.clone())
+            .collect();
+        assert_eq!(
+            precomputed,
+            services(&handshakes_for_port(&handshakes, port)),
+            "port {}",
+            port
+        );
+    }
+}
+
+#[test]
+fn handshake_file_error_test() {
+    let path = write_handshakes(
+        r#"
+- service: http
+  request: AA==
+  response: AA==
+  ports: ["8100-8000"]
+"#,
+    );
+    let err = get_service_handshakes(&path).unwrap_err();
+    assert!(err.to_string().contains("http"), "{}", err);
+
+    // the repo's handshakes still load
+    let handshakes = get_service_handshakes("handshakes.yaml").unwrap();
+    assert_eq!(services(&handshakes_for_port(&handshakes, 22))[0], "ssh");
+}
+
+#[test]
+fn handshake_matcher_test() {
+    let path = write_handshakes(
+        r#"
+- service: ssh
+  request: AA==
+  matchers:
+    - regex: '^SSH-2\.0-OpenSSH_([^\s]+)'
+      fields:
+        product: OpenSSH
+        version: $1
+    - regex: '^SSH-(?P<protocol>[\d.]+)-(?P<software>[^\s]+)'
+    - prefix: U1NILQ==
+- service: ftp
+  request: AA==
+  response: RlRQ
+"#,
+    );
+    let handshakes = get_service_handshakes(&path).expect("failed to load handshakes");
+    let ssh = &handshakes[0];
+
+    let (_, fields) = ssh.find(b"SSH-2.0-OpenSSH_8.4p1 Debian-5\r\n").unwrap();
+    assert_eq!(fields["product"], "OpenSSH");
+    assert_eq!(fields["version"], "8.4p1");
+    assert_eq!(fields.len(), 2);
+
+    // the first matcher fails, the named groups of the second become fields
+    let (_, fields) = ssh.find(b"SSH-1.99-Cisco-1.25\r\n").unwrap();
+    assert_eq!(fields["protocol"], "1.99");
+    assert_eq!(fields["software"], "Cisco-1.25");
+
+    // the prefix matches, but has nothing to capture
+    assert!(ssh.find(b"SSH-\xff\xfe").unwrap().1.is_empty());
+    assert!(ssh.find(b"HTTP/1.1 200 OK\r\n").is_none());
+
+    // response is shorthand for a contains matcher
+    let ftp = &handshakes[1];
+    assert!(ftp.find(b"220 FTP server ready\r\n").is_some());
+    assert!(ftp.find(b"220 SMTP server ready\r\n").is_none());
+}
+
+#[test]
+fn repo_handshakes_test() {
+    let handshakes = get_service_handshakes("handshakes.yaml").expect("failed to load handshakes");
+    let ssh = handshakes
+        .iter()
+        .find(|h| h.service == "ssh")
+        .expect("no ssh handshake");
+
+    let (service, fields) = ssh.find(b"SSH-2.0-OpenSSH_9.6\r\n").unwrap();
+    assert_eq!(service, "ssh");
+    assert_eq!(fields["product"], "OpenSSH");
+    assert_eq!(fields["version"], "9.6");
+    // only the prefix matches a banner without a software version
+    assert!(ssh.find(b"SSH-2.0-\r\n").unwrap().1.is_empty());
+    assert!(ssh.find(b"SSH-1.5\r\n").is_none());
+}
+
+#[test]
+fn handshake_matcher_error_test() {
+    for handshake in &[
+        // nothing to match against
+        "- service: none\n  request: AA==\n",
+        // more than one pattern in a matcher
+        "- service: both\n  request: AA==\n  matchers:\n    - regex: a\n      prefix: YQ==\n",
+        "- service: regex\n  request: AA==\n  matchers:\n    - regex: '('\n",
+    ] {
+        let path = write_handshakes(handshake);
+        assert!(get_service_handshakes(&path).is_err(), "{}", handshake);
+    }
+}
+
+#[test]
+fn handshake_request_template_test() {
+    // GET / HTTP/1.1\r\nHost: {hostname}:{port}\r\nX-Ip: {ip}\r\nX-Nonce: {nonce}\r\nX: {other}\r\n\r\n
+    let path = write_handshakes(
+        r#"
+- service: http
+  request: R0VUIC8gSFRUUC8xLjENCkhvc3Q6IHtob3N0bmFtZX06e3BvcnR9DQpYLUlwOiB7aXB9DQpYLU5vbmNlOiB7bm9uY2V9DQpYOiB7b3RoZXJ9DQoNCg==
+  response: SFRUUC8xLjE=
+"#,
+    );
+    let handshakes = get_service_handshakes(&path).expect("failed to load handshakes");
+    let http = &handshakes[0];
+    let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+    let request = http.render_request(ip, 8080, Some("example.com"));
+    let request = String::from_utf8(request).unwrap();
+    let lines: Vec<&str> = request.split("\r\n").collect();
+    assert_eq!(lines[1], "Host: example.com:8080");
+    assert_eq!(lines[2], "X-Ip: 192.0.2.1");
+    let nonce = lines[3].strip_prefix("X-Nonce: ").unwrap();
+    assert_eq!(nonce.len(), 16);
+    assert!(nonce.chars().all(|c| c.is_ascii_hexdigit()));
+    // unknown placeholders are kept
+    assert_eq!(lines[4], "X: {other}");
+
+    // every request gets a new nonce
+    let other = String::from_utf8(http.render_request(ip, 8080, Some("example.com"))).unwrap();
+    assert_ne!(request, other);
+
+    // without a hostname the address is used
+    let request = http.render_request(ip, 80, None);
+    assert!(request.starts_with(b"GET / HTTP/1.1\r\nHost: 192.0.2.1:80\r\n"));
+    let request = http.render_request("2001:db8::1".parse().unwrap(), 80, None);
+    assert!(request.starts_with(b"GET / HTTP/1.1\r\nHost: [2001:db8::1]:80\r\n"));
+}
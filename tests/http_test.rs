@@ -1,5 +1,6 @@
 mod setup;
 
+use std::collections::HashMap;
 use std::fs;
 use std::io::prelude::*;
 use std::net::{IpAddr, Ipv4Addr};
@@ -8,13 +9,17 @@ use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use etherparse::{ip_number, SlicedPacket};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+
+use etherparse::{ip_number, PacketBuilder, SlicedPacket, TransportSlice};
 
 use afpacket::sync::RawPacketStream;
-use rscan::packet::build_tcp_response;
-use rscan::{ScanConfig, ScanResult, Scanner, Target, TcpFlags};
+use rscan::packet::{
+    build_response_ip_header, build_tcp_reset, build_tcp_response, build_tcp_teardown,
+};
+use rscan::packet_io::{PacketIo, DEFAULT_RECV_TIMEOUT};
+use rscan::{ScanConfig, ScanResult, Scanner, Target, TcpFlags, Teardown};
 
-const SRC_IP: [u8; 4] = [192, 168, 69, 1];
 const DST_IP: [u8; 4] = [192, 168, 69, 2];
 
 const MAX_PACKET_SIZE: usize = 1500;
@@ -43,19 +48,208 @@ fn http_responder(mut ps: RawPacketStream, shutdown: Arc<AtomicBool>) {
     }
 }
 
+// Answers every request with an http response echoing the request in its body
+fn echo_responder(mut ps: RawPacketStream, shutdown: Arc<AtomicBool>) {
+    let mut rx_pkt = [0; MAX_PACKET_SIZE];
+    let mut tx_pkt = [0; MAX_PACKET_SIZE];
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let len = ps.read(&mut rx_pkt).expect("failed to read pkt");
+        let sliced = match SlicedPacket::from_ethernet(&rx_pkt[..len]) {
+            Ok(sliced) => sliced,
+            Err(e) => {
+                log::error!("Err {:?}", e);
+                continue;
+            }
+        };
+        let tcp = match &sliced.transport {
+            Some(TransportSlice::Tcp(tcp)) if tcp.source_port() >= 10000 => tcp,
+            _ => continue,
+        };
+        let len = if tcp.syn() {
+            build_tcp_response(&sliced, &[], &mut tx_pkt)
+        } else if !sliced.payload.is_empty() {
+            let mut response = b"HTTP/1.1 200 OK\r\n\r\n".to_vec();
+            response.extend_from_slice(sliced.payload);
+            build_tcp_response(&sliced, &response, &mut tx_pkt)
+        } else {
+            None
+        };
+        if let Some(len) = len {
+            ps.write_all(&tx_pkt[..len]).expect("failed to write pkt");
+        }
+    }
+}
+
+// Answers every request with the http response split over three segments, which are sent out of
+// order. Only the last segment has PSH set.
+fn segmented_responder(mut ps: RawPacketStream, shutdown: Arc<AtomicBool>) {
+    let http_bin = read_http_response_bytes();
+    let segments: Vec<&[u8]> = http_bin.chunks(http_bin.len() / 3 + 1).collect();
+    let mut rx_pkt = [0; MAX_PACKET_SIZE];
+    let mut tx_pkt = [0; MAX_PACKET_SIZE];
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let len = ps.read(&mut rx_pkt).expect("failed to read pkt");
+        let sliced = match SlicedPacket::from_ethernet(&rx_pkt[..len]) {
+            Ok(sliced) => sliced,
+            Err(e) => {
+                log::error!("Err {:?}", e);
+                continue;
+            }
+        };
+        let tcp = match &sliced.transport {
+            Some(TransportSlice::Tcp(tcp)) if tcp.source_port() >= 10000 => tcp,
+            _ => continue,
+        };
+        if tcp.syn() {
+            if let Some(len) = build_tcp_response(&sliced, &[], &mut tx_pkt) {
+                ps.write_all(&tx_pkt[..len]).expect("failed to write pkt");
+            }
+            continue;
+        }
+        if !sliced.payload.starts_with(b"GET") {
+            continue;
+        }
+        let ack = tcp
+            .sequence_number()
+            .wrapping_add(sliced.payload.len() as u32);
+        for &i in &[1, 2, 0] {
+            let offset: usize = segments[..i].iter().map(|s| s.len()).sum();
+            let seq = tcp.acknowledgment_number().wrapping_add(offset as u32);
+            let builder = PacketBuilder::ethernet2([0; 6], [0; 6]);
+            let builder = build_response_ip_header(&sliced, builder)
+                .expect("failed to build ip header")
+                .tcp(tcp.destination_port(), tcp.source_port(), seq, 65535)
+                .ack(ack);
+            let builder = if i == segments.len() - 1 {
+                builder.psh()
+            } else {
+                builder
+            };
+            let mut tx_pkt = Vec::with_capacity(builder.size(segments[i].len()));
+            builder
+                .write(&mut tx_pkt, segments[i])
+                .expect("failed to write pkt");
+            ps.write_all(&tx_pkt).expect("failed to write pkt");
+        }
+    }
+}
+
+// Answers the http request and records how the scanner closes each connection. A FIN is answered
+// with a FIN of our own, which the scanner should acknowledge.
+fn closing_responder(
+    mut ps: RawPacketStream,
+    shutdown: Arc<AtomicBool>,
+) -> Vec<(u16, &'static str)> {
+    let mut rx_pkt = [0; MAX_PACKET_SIZE];
+    let mut tx_pkt = [0; MAX_PACKET_SIZE];
+    let mut closes = vec![];
+    // the ack we expect for our FIN, by port
+    let mut fin_acks = HashMap::new();
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let len = ps.read(&mut rx_pkt).expect("failed to read pkt");
+        let sliced = match SlicedPacket::from_ethernet(&rx_pkt[..len]) {
+            Ok(sliced) => sliced,
+            Err(e) => {
+                log::error!("Err {:?}", e);
+                continue;
+            }
+        };
+        let tcp = match &sliced.transport {
+            Some(TransportSlice::Tcp(tcp)) if tcp.source_port() >= 10000 => tcp,
+            _ => continue,
+        };
+        let port = tcp.destination_port();
+        let len = if tcp.syn() {
+            build_tcp_response(&sliced, &[], &mut tx_pkt)
+        } else if tcp.rst() {
+            closes.push((port, "rst"));
+            None
+        } else if tcp.fin() {
+            closes.push((port, "fin"));
+            fin_acks.insert(port, tcp.acknowledgment_number().wrapping_add(1));
+            let ack = tcp.sequence_number().wrapping_add(1);
+            build_tcp_teardown(&sliced, Teardown::Fin, ack, &mut tx_pkt)
+        } else if sliced.payload.starts_with(b"GET") {
+            build_tcp_response(&sliced, b"HTTP/1.1 200 OK\r\n\r\n", &mut tx_pkt)
+        } else {
+            if fin_acks.get(&port) == Some(&tcp.acknowledgment_number()) {
+                closes.push((port, "ack"));
+            }
+            None
+        };
+        if let Some(len) = len {
+            ps.write_all(&tx_pkt[..len]).expect("failed to write pkt");
+        }
+    }
+    closes
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SshResponder {
+    ResetHttp,
+    AnswerHttp,
+    SendBanner,
+}
+
+const SSH_BANNER: &[u8] = b"SSH-2.0-OpenSSH_8.4p1 Debian-5\r\n";
+
+// Only speaks ssh. The http handshake is either reset or answered with something no handshake
+// recognises, which should make the scanner move on to the ssh handshake. With SendBanner the
+// banner is sent as soon as the connection is established, like a real ssh server.
+fn ssh_responder(mut ps: RawPacketStream, mode: SshResponder, shutdown: Arc<AtomicBool>) {
+    let mut rx_pkt = [0; MAX_PACKET_SIZE];
+    let mut tx_pkt = [0; MAX_PACKET_SIZE];
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let len = ps.read(&mut rx_pkt).expect("failed to read pkt");
+        let sliced = match SlicedPacket::from_ethernet(&rx_pkt[..len]) {
+            Ok(sliced) => sliced,
+            Err(e) => {
+                log::error!("Err {:?}", e);
+                continue;
+            }
+        };
+        // skip our own packets, which come from the scanned ports
+        let tcp = match &sliced.transport {
+            Some(TransportSlice::Tcp(tcp)) if tcp.source_port() >= 10000 => tcp,
+            _ => continue,
+        };
+        let len = if tcp.syn() {
+            build_tcp_response(&sliced, &[], &mut tx_pkt)
+        } else if sliced.payload.is_empty() && !tcp.rst() && mode == SshResponder::SendBanner {
+            build_tcp_response(&sliced, SSH_BANNER, &mut tx_pkt)
+        } else if sliced.payload.starts_with(b"GET") && mode == SshResponder::ResetHttp {
+            build_tcp_reset(&sliced, &mut tx_pkt)
+        } else if sliced.payload.starts_with(b"GET") {
+            build_tcp_response(&sliced, b"what?\r\n", &mut tx_pkt)
+        } else if sliced.payload.starts_with(b"SSH-") {
+            build_tcp_response(&sliced, SSH_BANNER, &mut tx_pkt)
+        } else {
+            None
+        };
+        if let Some(len) = len {
+            ps.write_all(&tx_pkt[..len]).expect("failed to write pkt");
+        }
+    }
+}
+
 #[test]
 fn http_test() {
     fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
-        let scan_config = ScanConfig {
-            src_mac: [0, 0, 0, 0, 0, 0],
-            dst_mac: [0, 0, 0, 0, 0, 0],
-            src_ipv4: Some(Ipv4Addr::from(SRC_IP)),
-            src_ipv6: None,
-            src_port: 10000,
-            handshakes_file: "handshakes.yaml".into(),
-        };
+        let scan_config = setup::scan_config();
 
-        let scanner = Scanner::new(dev1_ps, scan_config);
+        let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
         let shutdown = Arc::new(AtomicBool::new(false));
         let test_receiver_shutdown = shutdown.clone();
         let test_receiver_handle = thread::Builder::new()
@@ -69,18 +263,18 @@ fn http_test() {
 
         let max_port = 5;
         let targets: Vec<Target> = (1..max_port)
-            .into_iter()
             .map(|port| Target {
                 ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
                 port,
                 ip_number: u8::from(ip_number::TCP),
                 data: None,
+                hostname: None,
             })
             .collect();
 
         for target in targets.iter() {
             thread::sleep(Duration::from_micros(1));
-            scanner.scan_target(&target);
+            scanner.scan_target(target).expect("failed to scan target");
         }
 
         let mut syn_results: Vec<ScanResult> = vec![];
@@ -97,10 +291,10 @@ fn http_test() {
                         log::info!("{:?}", scan_result);
                         if scan_result.tcp_flags == Some(TcpFlags::Synack) {
                             syn_results.push(scan_result);
-                        } else if scan_result.tcp_flags == Some(TcpFlags::Ack) {
-                            if scan_result.service == Some("http".into()) {
-                                handshake_results.push(scan_result);
-                            }
+                        } else if scan_result.tcp_flags == Some(TcpFlags::Ack)
+                            && scan_result.service == Some("http".into())
+                        {
+                            handshake_results.push(scan_result);
                         }
                     }
                 }
@@ -110,11 +304,11 @@ fn http_test() {
             }
         }
 
-        scanner.shutdown();
+        scanner.shutdown().expect("failed to shut down scanner");
 
         shutdown.swap(true, Ordering::Relaxed);
 
-        let _ = test_receiver_handle
+        test_receiver_handle
             .join()
             .expect("failed to wait for receive thread");
 
@@ -127,3 +321,567 @@ fn http_test() {
 
     setup::run_test(test_fn);
 }
+
+fn ssh_scan(
+    dev1_ps: RawPacketStream,
+    dev2_ps: RawPacketStream,
+    mode: SshResponder,
+    banner_wait: Option<Duration>,
+    ports: Vec<u16>,
+    expected_probes: &[&str],
+) {
+    let scan_config = ScanConfig {
+        banner_wait,
+        ..setup::scan_config()
+    };
+
+    let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let test_receiver_shutdown = shutdown.clone();
+    let test_receiver_handle = thread::Builder::new()
+        .name("ssh test".into())
+        .spawn(move || {
+            ssh_responder(dev2_ps, mode, test_receiver_shutdown);
+        })
+        .expect("failed to start ssh responder thread");
+
+    thread::sleep(Duration::from_secs(1));
+
+    for port in ports.iter().copied() {
+        scanner
+            .scan_target(&Target {
+                ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+                port,
+                ip_number: u8::from(ip_number::TCP),
+                data: None,
+                hostname: None,
+            })
+            .expect("failed to scan target");
+    }
+
+    let mut results = vec![];
+    let start = Instant::now();
+    let mut filler_port: u16 = 1000;
+    while start.elapsed() < Duration::from_secs(3) {
+        // keep packets coming, so the rx thread notices when a banner wait is over
+        if banner_wait.is_some()
+            && start.elapsed() > Duration::from_millis(100 * u64::from(filler_port - 1000))
+        {
+            scanner
+                .scan_target(&Target {
+                    ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+                    port: filler_port,
+                    ip_number: u8::from(ip_number::TCP),
+                    data: None,
+                    hostname: None,
+                })
+                .expect("failed to scan target");
+            filler_port += 1;
+        }
+        if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+            log::info!("{:?}", scan_result);
+            results.push(scan_result);
+        }
+    }
+
+    scanner.shutdown().expect("failed to shut down scanner");
+    shutdown.swap(true, Ordering::Relaxed);
+    test_receiver_handle
+        .join()
+        .expect("failed to wait for receive thread");
+
+    for port in ports {
+        let port_results: Vec<&ScanResult> = results.iter().filter(|r| r.port == port).collect();
+        // one SYN-ACK result for the port, no matter how often it was opened
+        assert_eq!(
+            port_results
+                .iter()
+                .filter(|r| r.tcp_flags == Some(TcpFlags::Synack))
+                .count(),
+            1
+        );
+        let handshake_results: Vec<&&ScanResult> = port_results
+            .iter()
+            .filter(|r| r.tcp_flags != Some(TcpFlags::Synack))
+            .collect();
+        assert_eq!(handshake_results.len(), 1, "port {}", port);
+        let result = handshake_results[0];
+        assert_eq!(result.tcp_flags, Some(TcpFlags::Ack));
+        assert_eq!(result.service, Some("ssh".into()));
+        assert_eq!(result.probes, expected_probes);
+        assert_eq!(result.captures["product"], "OpenSSH");
+        assert_eq!(result.captures["version"], "8.4p1");
+    }
+}
+
+#[test]
+fn handshake_reset_fallback_test() {
+    setup::run_test(|dev1_ps, dev2_ps| {
+        ssh_scan(
+            dev1_ps,
+            dev2_ps,
+            SshResponder::ResetHttp,
+            None,
+            vec![1, 2, 3, 4],
+            &["http", "ssh"],
+        )
+    });
+}
+
+#[test]
+fn handshake_unrecognised_fallback_test() {
+    setup::run_test(|dev1_ps, dev2_ps| {
+        ssh_scan(
+            dev1_ps,
+            dev2_ps,
+            SshResponder::AnswerHttp,
+            None,
+            vec![1, 2, 3, 4],
+            &["http", "ssh"],
+        )
+    });
+}
+
+#[test]
+fn handshake_port_hint_test() {
+    // ssh is tried first on its own ports, so no http handshake is wasted
+    setup::run_test(|dev1_ps, dev2_ps| {
+        ssh_scan(
+            dev1_ps,
+            dev2_ps,
+            SshResponder::ResetHttp,
+            None,
+            vec![22, 2222],
+            &["ssh"],
+        )
+    });
+}
+
+#[test]
+fn banner_grab_test() {
+    setup::run_test(|dev1_ps, dev2_ps| {
+        ssh_scan(
+            dev1_ps,
+            dev2_ps,
+            SshResponder::SendBanner,
+            Some(Duration::from_secs(1)),
+            vec![1, 2, 3, 4],
+            &[],
+        )
+    });
+}
+
+#[test]
+fn banner_wait_probe_test() {
+    // no banner arrives, so the ssh handshake is sent once the wait is over
+    setup::run_test(|dev1_ps, dev2_ps| {
+        ssh_scan(
+            dev1_ps,
+            dev2_ps,
+            SshResponder::AnswerHttp,
+            Some(Duration::from_millis(200)),
+            vec![22, 2222],
+            &["ssh"],
+        )
+    });
+}
+
+// Answers SYNs and nothing else, like a service which neither sends a banner nor answers the
+// request. Returns the sequence number, payload length and RST flag of every other segment the
+// scanner sent.
+fn silent_responder(mut ps: RawPacketStream, shutdown: Arc<AtomicBool>) -> Vec<(u32, usize, bool)> {
+    let mut rx_pkt = [0; MAX_PACKET_SIZE];
+    let mut tx_pkt = [0; MAX_PACKET_SIZE];
+    let mut segments = vec![];
+    ps.set_recv_timeout(DEFAULT_RECV_TIMEOUT)
+        .expect("failed to set timeout");
+    while !shutdown.load(Ordering::Relaxed) {
+        let len = match ps.recv(&mut rx_pkt).expect("failed to read pkt") {
+            Some(len) => len,
+            None => continue,
+        };
+        let sliced = match SlicedPacket::from_ethernet(&rx_pkt[..len]) {
+            Ok(sliced) => sliced,
+            Err(e) => {
+                log::error!("Err {:?}", e);
+                continue;
+            }
+        };
+        let tcp = match &sliced.transport {
+            Some(TransportSlice::Tcp(tcp)) if tcp.source_port() >= 10000 => tcp,
+            _ => continue,
+        };
+        if !tcp.syn() {
+            segments.push((tcp.sequence_number(), sliced.payload.len(), tcp.rst()));
+            continue;
+        }
+        if let Some(len) = build_tcp_response(&sliced, &[], &mut tx_pkt) {
+            ps.write_all(&tx_pkt[..len]).expect("failed to write pkt");
+        }
+    }
+    segments
+}
+
+// The connection expires without an answer from the target, either after the request was sent
+// at the end of the banner wait or while still waiting for a banner. The reset must follow
+// whatever was sent, and only that.
+fn banner_wait_teardown_scan(
+    dev1_ps: RawPacketStream,
+    dev2_ps: RawPacketStream,
+    banner_wait: Duration,
+    host_timeout: Duration,
+    request_sent: bool,
+) {
+    let scan_config = ScanConfig {
+        host_timeout,
+        banner_wait: Some(banner_wait),
+        ..setup::scan_config()
+    };
+
+    let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let test_receiver_shutdown = shutdown.clone();
+    let test_receiver_handle = thread::Builder::new()
+        .name("silent test".into())
+        .spawn(move || silent_responder(dev2_ps, test_receiver_shutdown))
+        .expect("failed to start silent responder thread");
+
+    thread::sleep(Duration::from_secs(1));
+
+    scanner
+        .scan_target(&Target {
+            ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+            port: 22,
+            ip_number: u8::from(ip_number::TCP),
+            data: None,
+            hostname: None,
+        })
+        .expect("failed to scan target");
+
+    let mut timed_out = false;
+    let start = Instant::now();
+    while !timed_out && start.elapsed() < Duration::from_secs(3) {
+        if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+            timed_out = scan_result.timed_out;
+        }
+    }
+    // give the reset time to arrive
+    thread::sleep(Duration::from_millis(200));
+
+    scanner.shutdown().expect("failed to shut down scanner");
+    shutdown.swap(true, Ordering::Relaxed);
+    let segments = test_receiver_handle
+        .join()
+        .expect("failed to wait for receive thread");
+
+    assert!(timed_out);
+    // the ACK of the SYN-ACK, the request if the banner wait was over, and the reset
+    let (ack_seq, _, _) = segments[0];
+    let requests: Vec<&(u32, usize, bool)> = segments.iter().filter(|s| s.1 > 0).collect();
+    let resets: Vec<&(u32, usize, bool)> = segments.iter().filter(|s| s.2).collect();
+    assert_eq!(resets.len(), 1, "{:?}", segments);
+    let expected_seq = match requests[..] {
+        [(request_seq, request_len, _)] if request_sent => {
+            assert_eq!(*request_seq, ack_seq);
+            request_seq.wrapping_add(*request_len as u32)
+        }
+        [] if !request_sent => ack_seq,
+        _ => panic!("unexpected requests {:?}", requests),
+    };
+    assert_eq!(resets[0].0, expected_seq);
+}
+
+#[test]
+fn banner_wait_teardown_test() {
+    // the request goes out once the wait is over, the connection expires later
+    setup::run_test(|dev1_ps, dev2_ps| {
+        banner_wait_teardown_scan(
+            dev1_ps,
+            dev2_ps,
+            Duration::from_millis(200),
+            Duration::from_secs(1),
+            true,
+        )
+    });
+    // the connection expires during the wait, the request is never sent
+    setup::run_test(|dev1_ps, dev2_ps| {
+        banner_wait_teardown_scan(
+            dev1_ps,
+            dev2_ps,
+            Duration::from_secs(5),
+            Duration::from_millis(500),
+            false,
+        )
+    });
+}
+
+#[test]
+fn hostname_test() {
+    fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
+        let scan_config = setup::scan_config();
+
+        let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let test_receiver_shutdown = shutdown.clone();
+        let test_receiver_handle = thread::Builder::new()
+            .name("echo test".into())
+            .spawn(move || {
+                echo_responder(dev2_ps, test_receiver_shutdown);
+            })
+            .expect("failed to start echo responder thread");
+
+        thread::sleep(Duration::from_secs(1));
+
+        let hostnames = [
+            (80, Some("example.com")),
+            (8080, Some("example.org")),
+            (8000, None),
+        ];
+        for (port, hostname) in hostnames.iter() {
+            scanner
+                .scan_target(&Target {
+                    ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+                    port: *port,
+                    ip_number: u8::from(ip_number::TCP),
+                    data: None,
+                    hostname: hostname.map(String::from),
+                })
+                .expect("failed to scan target");
+        }
+
+        let mut results = vec![];
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(3) {
+            if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+                if scan_result.tcp_flags == Some(TcpFlags::Ack) {
+                    results.push(scan_result);
+                }
+            }
+        }
+
+        scanner.shutdown().expect("failed to shut down scanner");
+        shutdown.swap(true, Ordering::Relaxed);
+        test_receiver_handle
+            .join()
+            .expect("failed to wait for receive thread");
+
+        for (port, hostname) in hostnames.iter() {
+            let result = results
+                .iter()
+                .find(|r| r.port == *port)
+                .expect("no handshake result");
+            assert_eq!(result.service, Some("http".into()));
+            let host_header = match hostname {
+                Some(hostname) => format!("\r\nHost: {}\r\n", hostname),
+                None => format!("\r\nHost: {}\r\n", Ipv4Addr::from(DST_IP)),
+            };
+            let data = String::from_utf8_lossy(&result.data);
+            assert!(data.contains(&host_header), "{}", data);
+        }
+    }
+
+    setup::run_test(test_fn);
+}
+
+#[test]
+fn service_probes_test() {
+    // the NULL probe must not use up the first attempt on a port without a hint, the server only
+    // speaks once it got a request
+    fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
+        let probes = r#"
+Probe TCP NULL q||
+match ssh m/^SSH-/
+Probe TCP GetRequest q|GET / HTTP/1.0\r\n\r\n|
+rarity 1
+ports 80
+match http m|^HTTP/1\.[01] \d\d\d|
+"#;
+        let rand_string: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+        let path = std::env::temp_dir().join(format!("rscan_service_probes_{}", rand_string));
+        fs::write(&path, probes).expect("failed to write probes");
+        let scan_config = ScanConfig {
+            handshakes_file: None,
+            service_probes_file: Some(path.to_string_lossy().into()),
+            ..setup::scan_config()
+        };
+
+        let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
+        fs::remove_file(&path).expect("failed to remove probes");
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let test_receiver_shutdown = shutdown.clone();
+        let test_receiver_handle = thread::Builder::new()
+            .name("echo test".into())
+            .spawn(move || {
+                echo_responder(dev2_ps, test_receiver_shutdown);
+            })
+            .expect("failed to start echo responder thread");
+
+        thread::sleep(Duration::from_secs(1));
+
+        scanner
+            .scan_target(&Target {
+                ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+                port: 5000,
+                ip_number: u8::from(ip_number::TCP),
+                data: None,
+                hostname: None,
+            })
+            .expect("failed to scan target");
+
+        let mut result = None;
+        let start = Instant::now();
+        while result.is_none() && start.elapsed() < Duration::from_secs(3) {
+            if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+                if scan_result.tcp_flags != Some(TcpFlags::Synack) {
+                    result = Some(scan_result);
+                }
+            }
+        }
+
+        scanner.shutdown().expect("failed to shut down scanner");
+        shutdown.swap(true, Ordering::Relaxed);
+        test_receiver_handle
+            .join()
+            .expect("failed to wait for receive thread");
+
+        let result = result.expect("no handshake result");
+        assert!(!result.timed_out);
+        assert_eq!(result.service, Some("http".into()));
+        assert_eq!(result.probes, vec!["GetRequest"]);
+    }
+
+    setup::run_test(test_fn);
+}
+
+#[test]
+fn segmented_response_test() {
+    fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
+        let scan_config = setup::scan_config();
+
+        let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let test_receiver_shutdown = shutdown.clone();
+        let test_receiver_handle = thread::Builder::new()
+            .name("segmented test".into())
+            .spawn(move || {
+                segmented_responder(dev2_ps, test_receiver_shutdown);
+            })
+            .expect("failed to start segmented responder thread");
+
+        thread::sleep(Duration::from_secs(1));
+
+        let ports = [80, 8080];
+        for port in ports.iter() {
+            scanner
+                .scan_target(&Target {
+                    ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+                    port: *port,
+                    ip_number: u8::from(ip_number::TCP),
+                    data: None,
+                    hostname: None,
+                })
+                .expect("failed to scan target");
+        }
+
+        let mut results = vec![];
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(3) {
+            if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+                if scan_result.tcp_flags == Some(TcpFlags::Ack) {
+                    results.push(scan_result);
+                }
+            }
+        }
+
+        scanner.shutdown().expect("failed to shut down scanner");
+        shutdown.swap(true, Ordering::Relaxed);
+        test_receiver_handle
+            .join()
+            .expect("failed to wait for receive thread");
+
+        let http_bin = read_http_response_bytes();
+        for port in ports.iter() {
+            let port_results: Vec<_> = results.iter().filter(|r| r.port == *port).collect();
+            assert_eq!(port_results.len(), 1, "port {}", port);
+            let result = port_results[0];
+            assert_eq!(result.service, Some("http".into()));
+            assert_eq!(result.probes, vec!["http"]);
+            // the segments were put back in order
+            assert_eq!(result.data, http_bin);
+        }
+    }
+
+    setup::run_test(test_fn);
+}
+
+fn teardown_scan(
+    dev1_ps: RawPacketStream,
+    dev2_ps: RawPacketStream,
+    teardown: Teardown,
+) -> Vec<(u16, &'static str)> {
+    let scan_config = ScanConfig {
+        teardown,
+        ..setup::scan_config()
+    };
+
+    let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let test_receiver_shutdown = shutdown.clone();
+    let test_receiver_handle = thread::Builder::new()
+        .name("teardown test".into())
+        .spawn(move || closing_responder(dev2_ps, test_receiver_shutdown))
+        .expect("failed to start closing responder thread");
+
+    thread::sleep(Duration::from_secs(1));
+
+    scanner
+        .scan_target(&Target {
+            ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+            port: 80,
+            ip_number: u8::from(ip_number::TCP),
+            data: None,
+            hostname: None,
+        })
+        .expect("failed to scan target");
+
+    let mut results = vec![];
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(3) {
+        if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+            if scan_result.tcp_flags == Some(TcpFlags::Ack) {
+                results.push(scan_result);
+            }
+        }
+    }
+
+    scanner.shutdown().expect("failed to shut down scanner");
+    shutdown.swap(true, Ordering::Relaxed);
+    let closes = test_receiver_handle
+        .join()
+        .expect("failed to wait for receive thread");
+
+    // the connection is closed once, so the handshake is reported once
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].service, Some("http".into()));
+    closes
+}
+
+#[test]
+fn rst_teardown_test() {
+    setup::run_test(|dev1_ps, dev2_ps| {
+        let closes = teardown_scan(dev1_ps, dev2_ps, Teardown::Rst);
+        assert_eq!(closes, vec![(80, "rst")]);
+    });
+}
+
+#[test]
+fn fin_teardown_test() {
+    setup::run_test(|dev1_ps, dev2_ps| {
+        let closes = teardown_scan(dev1_ps, dev2_ps, Teardown::Fin);
+        assert_eq!(closes, vec![(80, "fin"), (80, "ack")]);
+    });
+}
@@ -0,0 +1,252 @@
+mod setup;
+
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use etherparse::{ip_number, PacketBuilder, SlicedPacket, TransportSlice};
+
+use afpacket::sync::RawPacketStream;
+use rscan::packet::build_response_ip_header;
+use rscan::{icmp, PortState, ScanResult, Scanner, Target};
+
+const DST_IP: [u8; 4] = [192, 168, 69, 2];
+
+const MAX_PACKET_SIZE: usize = 1500;
+const ETHERNET_HEADER_LEN: usize = 14;
+
+const PORT_UNREACHABLE: u8 = 3;
+const ADMIN_PROHIBITED: u8 = 13;
+
+const ECHO_REQUEST: u8 = 8;
+const ECHO_REPLY: u8 = 0;
+
+// Answer udp probes with port unreachable and tcp probes with administratively prohibited,
+// quoting the ip header and the first 8 bytes of the transport header
+fn unreachable_responder(mut ps: RawPacketStream, shutdown: Arc<AtomicBool>) {
+    let mut rx_pkt = [0; MAX_PACKET_SIZE];
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let len = ps.read(&mut rx_pkt).expect("failed to read pkt");
+        let sliced = match SlicedPacket::from_ethernet(&rx_pkt[..len]) {
+            Ok(sliced) => sliced,
+            Err(e) => {
+                log::error!("Err {:?}", e);
+                continue;
+            }
+        };
+        let code = match &sliced.transport {
+            Some(TransportSlice::Udp(_)) => PORT_UNREACHABLE,
+            Some(TransportSlice::Tcp(_)) => ADMIN_PROHIBITED,
+            _ => continue,
+        };
+        let quoted_len = (len - ETHERNET_HEADER_LEN).min(20 + 8);
+        let quoted = &rx_pkt[ETHERNET_HEADER_LEN..ETHERNET_HEADER_LEN + quoted_len];
+
+        let builder = PacketBuilder::ethernet2([0; 6], [0; 6]);
+        let builder = build_response_ip_header(&sliced, builder)
+            .expect("failed to build ip header")
+            .icmpv4_raw(3, code, [0; 4]);
+        let mut tx_pkt = Vec::with_capacity(builder.size(quoted.len()));
+        builder
+            .write(&mut tx_pkt, quoted)
+            .expect("failed to write pkt");
+        ps.write_all(&tx_pkt).expect("failed to write pkt");
+    }
+}
+
+// Answer echo requests with an echo reply carrying the same identifier, sequence number and payload
+fn echo_responder(mut ps: RawPacketStream, shutdown: Arc<AtomicBool>) {
+    let mut rx_pkt = [0; MAX_PACKET_SIZE];
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let len = ps.read(&mut rx_pkt).expect("failed to read pkt");
+        let sliced = match SlicedPacket::from_ethernet(&rx_pkt[..len]) {
+            Ok(sliced) => sliced,
+            Err(e) => {
+                log::error!("Err {:?}", e);
+                continue;
+            }
+        };
+        let (id_seq, payload) = match &sliced.transport {
+            Some(TransportSlice::Icmpv4(icmp)) if icmp.type_u8() == ECHO_REQUEST => {
+                (icmp.bytes5to8(), icmp.payload())
+            }
+            _ => continue,
+        };
+        let id = u16::from_be_bytes([id_seq[0], id_seq[1]]);
+        let seq = u16::from_be_bytes([id_seq[2], id_seq[3]]);
+
+        let builder = PacketBuilder::ethernet2([0; 6], [0; 6]);
+        let builder = build_response_ip_header(&sliced, builder)
+            .expect("failed to build ip header")
+            .icmpv4_echo_reply(id, seq);
+        let mut tx_pkt = Vec::with_capacity(builder.size(payload.len()));
+        builder
+            .write(&mut tx_pkt, payload)
+            .expect("failed to write pkt");
+        ps.write_all(&tx_pkt).expect("failed to write pkt");
+    }
+}
+
+#[test]
+fn icmp_unreachable_test() {
+    fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
+        let scan_config = setup::scan_config();
+
+        let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let test_receiver_shutdown = shutdown.clone();
+        let test_receiver_handle = thread::Builder::new()
+            .name("icmp test".into())
+            .spawn(move || {
+                unreachable_responder(dev2_ps, test_receiver_shutdown);
+            })
+            .expect("failed to start unreachable responder thread");
+
+        thread::sleep(Duration::from_secs(1));
+
+        let targets: Vec<Target> = (1..50)
+            .flat_map(|port| {
+                vec![
+                    Target {
+                        ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+                        port,
+                        ip_number: u8::from(ip_number::TCP),
+                        data: None,
+                        hostname: None,
+                    },
+                    Target {
+                        ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+                        port,
+                        ip_number: u8::from(ip_number::UDP),
+                        data: None,
+                        hostname: None,
+                    },
+                ]
+            })
+            .collect();
+
+        for target in targets.iter() {
+            scanner.scan_target(target).expect("failed to scan target");
+        }
+
+        let mut scan_results: Vec<ScanResult> = vec![];
+        let rx_timeout = Duration::from_secs(5);
+        let start = Instant::now();
+        while scan_results.len() < targets.len() && start.elapsed() < rx_timeout {
+            if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+                if scan_result.ip == IpAddr::V4(Ipv4Addr::from(DST_IP)) {
+                    scan_results.push(scan_result);
+                }
+            }
+        }
+
+        scanner.shutdown().expect("failed to shut down scanner");
+
+        shutdown.swap(true, Ordering::Relaxed);
+
+        test_receiver_handle
+            .join()
+            .expect("failed to wait for receive thread");
+
+        assert_eq!(targets.len(), scan_results.len());
+        for result in scan_results {
+            if result.transport_protocol == u8::from(ip_number::UDP) {
+                assert_eq!(result.port_state, Some(PortState::Closed));
+            } else {
+                assert_eq!(result.port_state, Some(PortState::Filtered));
+            }
+        }
+    }
+
+    setup::run_test(test_fn);
+}
+
+#[test]
+fn unreachable_code_test() {
+    // only port unreachable comes from the target itself, protocol unreachable is filtered
+    // like host unreachable, network prohibited, host prohibited and administratively prohibited
+    assert_eq!(
+        icmp::icmpv4_unreachable_port_state(PORT_UNREACHABLE),
+        PortState::Closed
+    );
+    for code in [0, 1, 2, 9, 10, ADMIN_PROHIBITED].iter() {
+        assert_eq!(
+            icmp::icmpv4_unreachable_port_state(*code),
+            PortState::Filtered,
+            "code {}",
+            code
+        );
+    }
+
+    assert_eq!(icmp::icmpv6_unreachable_port_state(4), PortState::Closed);
+    for code in [0, 1, 3, 5, 6].iter() {
+        assert_eq!(
+            icmp::icmpv6_unreachable_port_state(*code),
+            PortState::Filtered,
+            "code {}",
+            code
+        );
+    }
+}
+
+#[test]
+fn icmp_echo_test() {
+    fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
+        let scan_config = setup::scan_config();
+
+        let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let test_receiver_shutdown = shutdown.clone();
+        let test_receiver_handle = thread::Builder::new()
+            .name("echo test".into())
+            .spawn(move || {
+                echo_responder(dev2_ps, test_receiver_shutdown);
+            })
+            .expect("failed to start echo responder thread");
+
+        thread::sleep(Duration::from_secs(1));
+
+        let target = Target {
+            ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+            port: 0,
+            ip_number: u8::from(ip_number::ICMP),
+            data: None,
+            hostname: None,
+        };
+        scanner.scan_target(&target).expect("failed to scan target");
+
+        let mut scan_results: Vec<ScanResult> = vec![];
+        let rx_timeout = Duration::from_secs(5);
+        let start = Instant::now();
+        while scan_results.is_empty() && start.elapsed() < rx_timeout {
+            if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+                if scan_result.ip == IpAddr::V4(Ipv4Addr::from(DST_IP)) {
+                    scan_results.push(scan_result);
+                }
+            }
+        }
+
+        scanner.shutdown().expect("failed to shut down scanner");
+
+        shutdown.swap(true, Ordering::Relaxed);
+
+        test_receiver_handle
+            .join()
+            .expect("failed to wait for receive thread");
+
+        assert_eq!(1, scan_results.len());
+        assert_eq!(scan_results[0].icmp_type, Some(ECHO_REPLY));
+        assert!(scan_results[0].rtt.is_some());
+    }
+
+    setup::run_test(test_fn);
+}
@@ -0,0 +1,108 @@
+use rscan::handshake::handshakes_for_port;
+use rscan::nmap::parse_service_probes;
+
+const SERVICE_PROBES: &str = r#"
+# a trimmed down nmap-service-probes
+Exclude T:9100-9107
+
+Probe TCP NULL q||
+totalwaitms 6000
+match ftp m/^220 ProFTPD (\d\S+) Server/ p/ProFTPD/ v/$1/ cpe:/a:proftpd:proftpd:$1/
+match ssh m/^SSH-([\d.]+)-OpenSSH_([\w._-]+)\r?\n/i p/OpenSSH/ v/$2/ i/protocol $1/ cpe:/a:openbsd:openssh:$2/ cpe:/o:linux:linux_kernel/a
+softmatch ssh m/^SSH-/
+
+Probe TCP GetRequest q|GET / HTTP/1.0\r\n\r\n|
+rarity 1
+ports 80-85,8000,8080
+sslports 443
+match http m|^HTTP/1\.[01] \d\d\d .*\r\nServer: nginx/([\d.]+)|s p/nginx/ v/$1/
+match http m|^HTTP/1\.[01] (?=\d)| p/lookahead/
+softmatch http m|^HTTP/1\.[01] \d\d\d|
+
+Probe TCP HTTPOptions q|OPTIONS / HTTP/1.0\r\n\r\n|
+rarity 4
+ports 80-85
+fallback GetRequest
+
+Probe UDP DNSStatusRequest q|\0\0\x10\0\0\0\0\0\0\0\0\0|
+rarity 1
+match dns m|^\0\0\x90|
+"#;
+
+#[test]
+fn service_probes_test() {
+    let handshakes = parse_service_probes(SERVICE_PROBES).expect("failed to parse probes");
+    // udp probes are skipped
+    let names: Vec<&str> = handshakes.iter().map(|h| h.service.as_str()).collect();
+    // the NULL probe sends nothing, the banner wait takes its place
+    assert_eq!(names, vec!["GetRequest", "HTTPOptions"]);
+
+    // the matches of the NULL probe apply to every probe
+    let get = &handshakes[0];
+    let (service, fields) = get
+        .find(b"SSH-2.0-OpenSSH_8.4p1\r\n")
+        .expect("no match for ssh");
+    assert_eq!(service, "ssh");
+    assert_eq!(fields["product"], "OpenSSH");
+    assert_eq!(fields["version"], "8.4p1");
+    assert_eq!(fields["info"], "protocol 2.0");
+    assert_eq!(
+        fields["cpe"],
+        "cpe:/a:openbsd:openssh:8.4p1 cpe:/o:linux:linux_kernel"
+    );
+    // the i flag makes the match case insensitive
+    assert!(get.find(b"ssh-2.0-openssh_8.4p1\n").is_some());
+    // the softmatch catches what the matches don't
+    let (service, fields) = get.find(b"SSH-2.0-dropbear\r\n").unwrap();
+    assert_eq!(service, "ssh");
+    assert!(fields.is_empty());
+    let (service, _) = get.find(b"220 ProFTPD 1.3.5 Server ready\r\n").unwrap();
+    assert_eq!(service, "ftp");
+
+    assert_eq!(get.request, b"GET / HTTP/1.0\r\n\r\n");
+    assert_eq!(get.priority, -1);
+    assert!(get.matches_port(82) && get.matches_port(8080) && !get.matches_port(443));
+    // the s flag lets . match the newlines between the headers
+    let response = b"HTTP/1.1 200 OK\r\nDate: today\r\nServer: nginx/1.18.0\r\n\r\n";
+    let (service, fields) = get.find(response).unwrap();
+    assert_eq!(service, "http");
+    assert_eq!(fields["version"], "1.18.0");
+    // the match with a lookahead is skipped, the softmatch still matches
+    let (service, fields) = get.find(b"HTTP/1.0 404 Not Found\r\n").unwrap();
+    assert_eq!(service, "http");
+    assert!(fields.is_empty());
+    // HTTPOptions has no matches of its own, it falls back to GetRequest
+    let options = &handshakes[1];
+    let (service, fields) = options.find(response).unwrap();
+    assert_eq!(service, "http");
+    assert_eq!(fields["product"], "nginx");
+
+    // common probes go first, the port hints before that
+    let order: Vec<&str> = handshakes_for_port(&handshakes, 80)
+        .iter()
+        .map(|h| h.service.as_str())
+        .collect();
+    assert_eq!(order, vec!["GetRequest", "HTTPOptions"]);
+    let order: Vec<&str> = handshakes_for_port(&handshakes, 22)
+        .iter()
+        .map(|h| h.service.as_str())
+        .collect();
+    assert_eq!(order, vec!["GetRequest", "HTTPOptions"]);
+}
+
+#[test]
+fn service_probes_error_test() {
+    for (probes, line) in &[
+        ("match ftp m/^220/\n", 1),
+        ("Probe TCP NULL q||\nports 80-\n", 2),
+        ("Probe TCP NULL q||\n\nmatch ftp m/^220\n", 3),
+        ("Probe TCP NULL q||\nmatch ftp m/^220/ x/what/\n", 2),
+        ("Probe SCTP NULL q||\n", 1),
+        ("Probe TCP Bad q|\\xZZ|\n", 1),
+        ("Probe TCP NULL q||\nrarity high\n", 2),
+        ("Probe TCP NULL q||\nwaitms 10\n", 2),
+    ] {
+        let err = parse_service_probes(probes).unwrap_err();
+        assert_eq!(err.line, *line, "{}: {}", probes, err);
+    }
+}
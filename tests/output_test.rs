@@ -0,0 +1,97 @@
+use std::fs;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rscan::output::{open_input, OutputWriter, Rotation};
+
+fn temp_dir() -> PathBuf {
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+    let dir = std::env::temp_dir().join(format!("rscan_output_{}", rand_string));
+    fs::create_dir_all(&dir).expect("failed to create temp dir");
+    dir
+}
+
+fn read_lines(path: &Path) -> Vec<String> {
+    open_input(path.to_str().unwrap())
+        .expect("failed to open output")
+        .lines()
+        .map(|line| line.expect("failed to read line"))
+        .collect()
+}
+
+#[test]
+fn compressed_output_test() {
+    let dir = temp_dir();
+    for name in &["results.json.gz", "results.json.zst", "results.json"] {
+        let path = dir.join(name);
+        let mut writer = OutputWriter::create(path.to_str().unwrap(), Rotation::default())
+            .expect("failed to create output");
+        for i in 0..100 {
+            writer
+                .write_line(format!("{{\"line\": {}}}", i).as_bytes())
+                .expect("failed to write line");
+        }
+        writer.finish().expect("failed to finish output");
+
+        let lines = read_lines(&path);
+        assert_eq!(100, lines.len());
+        assert_eq!("{\"line\": 99}", lines[99]);
+    }
+    fs::remove_dir_all(dir).expect("failed to remove temp dir");
+}
+
+#[test]
+fn rotated_output_test() {
+    let dir = temp_dir();
+    let path = dir.join("results.json.gz");
+    let rotation = Rotation {
+        max_bytes: Some(100),
+        max_age: None,
+    };
+    let mut writer =
+        OutputWriter::create(path.to_str().unwrap(), rotation).expect("failed to create output");
+    // 10 bytes per line including the newline, so every file holds 10 lines
+    for i in 0..25 {
+        writer
+            .write_line(format!("line {:04}", i).as_bytes())
+            .expect("failed to write line");
+    }
+    drop(writer);
+
+    let counts: Vec<usize> = (0..3)
+        .map(|i| read_lines(&dir.join(format!("results.{}.json.gz", i))).len())
+        .collect();
+    assert_eq!(vec![10, 10, 5], counts);
+    assert!(!path.exists());
+    fs::remove_dir_all(dir).expect("failed to remove temp dir");
+}
+
+#[test]
+fn rotated_output_keeps_existing_files_test() {
+    let dir = temp_dir();
+    let path = dir.join("results.json");
+    // the first file of an earlier run
+    fs::write(dir.join("results.0.json"), "earlier\n").expect("failed to write file");
+    let rotation = Rotation {
+        max_bytes: Some(100),
+        max_age: None,
+    };
+    let mut writer =
+        OutputWriter::create(path.to_str().unwrap(), rotation).expect("failed to create output");
+    for i in 0..15 {
+        writer
+            .write_line(format!("line {:04}", i).as_bytes())
+            .expect("failed to write line");
+    }
+    writer.finish().expect("failed to finish output");
+
+    assert_eq!(vec!["earlier"], read_lines(&dir.join("results.0.json")));
+    assert_eq!(10, read_lines(&dir.join("results.1.json")).len());
+    assert_eq!(5, read_lines(&dir.join("results.2.json")).len());
+    fs::remove_dir_all(dir).expect("failed to remove temp dir");
+}
@@ -0,0 +1,211 @@
+mod setup;
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::unbounded;
+use etherparse::{ip_number, SlicedPacket, TransportSlice};
+
+use rscan::packet::build_tcp_response;
+use rscan::packet_io::{Loopback, PacketIo, PcapReader, PcapWriter};
+use rscan::ratelimit::RateLimit;
+use rscan::send::{start_tx, Retransmit};
+use rscan::{ScanConfig, Scanner, Target, TcpFlags};
+
+const DST_IP: [u8; 4] = [192, 168, 69, 2];
+
+const MAX_PACKET_SIZE: usize = 1500;
+
+#[test]
+fn loopback_test() {
+    let (mut a, mut b) = Loopback::pair();
+    a.send(b"frame").expect("failed to send frame");
+
+    let mut frame = [0; MAX_PACKET_SIZE];
+    let len = b.recv(&mut frame).expect("failed to receive frame");
+    assert_eq!(len.map(|len| &frame[..len]), Some(&b"frame"[..]));
+
+    // nothing was sent the other way
+    a.set_recv_timeout(Duration::from_millis(10))
+        .expect("failed to set timeout");
+    assert_eq!(a.recv(&mut frame).expect("failed to receive frame"), None);
+
+    drop(b);
+    assert!(a.send(b"frame").is_err());
+}
+
+#[test]
+fn pcap_test() {
+    let frames: Vec<Vec<u8>> = vec![vec![1; 60], vec![2; 1400], vec![]];
+    let mut writer = PcapWriter::new(vec![]).expect("failed to write header");
+    for frame in frames.iter() {
+        writer.write_frame(frame).expect("failed to write frame");
+    }
+    let capture = writer.into_inner();
+    assert_eq!(capture.len(), 24 + 3 * 16 + 60 + 1400);
+
+    let mut reader = PcapReader::new(&capture[..]).expect("failed to read header");
+    let mut frame = [0; MAX_PACKET_SIZE];
+    for expected in frames.iter() {
+        let len = reader.read_frame(&mut frame).expect("failed to read frame");
+        assert_eq!(len.map(|len| &frame[..len]), Some(&expected[..]));
+    }
+    assert_eq!(
+        reader.read_frame(&mut frame).expect("failed at the end"),
+        None
+    );
+
+    // frames longer than the buffer are cut short, and the next frame is read whole
+    let mut reader = PcapReader::new(&capture[..]).expect("failed to read header");
+    let mut short = [0; 100];
+    assert_eq!(reader.read_frame(&mut short).expect("failed"), Some(60));
+    assert_eq!(reader.read_frame(&mut short).expect("failed"), Some(100));
+    assert_eq!(reader.read_frame(&mut short).expect("failed"), Some(0));
+
+    assert!(PcapReader::new(&b"not a pcap file, just some bytes"[..]).is_err());
+}
+
+#[test]
+fn pcap_big_endian_test() {
+    let mut capture = vec![];
+    for field in [0xa1b2_c3d4u32, 0x0002_0004, 0, 0, 65535, 1].iter() {
+        capture.extend_from_slice(&field.to_be_bytes());
+    }
+    for field in [0u32, 0, 3, 3].iter() {
+        capture.extend_from_slice(&field.to_be_bytes());
+    }
+    capture.extend_from_slice(b"abc");
+
+    let mut reader = PcapReader::new(&capture[..]).expect("failed to read header");
+    let mut frame = [0; MAX_PACKET_SIZE];
+    assert_eq!(reader.read_frame(&mut frame).expect("failed"), Some(3));
+    assert_eq!(&frame[..3], b"abc");
+}
+
+fn synacker<P: PacketIo>(mut io: P, shutdown: Arc<AtomicBool>) {
+    let mut rx_pkt = [0; MAX_PACKET_SIZE];
+    let mut tx_pkt = [0; MAX_PACKET_SIZE];
+    while !shutdown.load(Ordering::Relaxed) {
+        let len = match io.recv(&mut rx_pkt) {
+            Ok(Some(len)) => len,
+            Ok(None) => continue,
+            // the scanner shut down and dropped its end of the link
+            Err(_) => break,
+        };
+        let sliced = match SlicedPacket::from_ethernet(&rx_pkt[..len]) {
+            Ok(sliced) => sliced,
+            Err(e) => {
+                log::error!("Err {:?}", e);
+                continue;
+            }
+        };
+        match &sliced.transport {
+            Some(TransportSlice::Tcp(tcp)) if tcp.syn() && !tcp.ack() => {}
+            _ => continue,
+        }
+        if let Some(len) = build_tcp_response(&sliced, &[], &mut tx_pkt) {
+            if io.send(&tx_pkt[..len]).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+// The whole scan runs over an in-memory link, no root or network interface needed
+#[test]
+fn loopback_scan_test() {
+    let (scanner_io, target_io) = Loopback::pair();
+    let scan_config = ScanConfig {
+        handshakes_file: None,
+        ..setup::scan_config()
+    };
+    let scanner = Scanner::new(scanner_io, scan_config).expect("failed to start scanner");
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let synacker_shutdown = shutdown.clone();
+    let synacker_handle = thread::Builder::new()
+        .name("synacker test".into())
+        .spawn(move || synacker(target_io, synacker_shutdown))
+        .expect("failed to start synacker thread");
+
+    let ports: Vec<u16> = (1..=100).collect();
+    for port in ports.iter() {
+        scanner
+            .scan_target(&Target {
+                ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+                port: *port,
+                ip_number: u8::from(ip_number::TCP),
+                data: None,
+                hostname: None,
+            })
+            .expect("failed to scan target");
+    }
+
+    let mut scan_results = vec![];
+    let start = Instant::now();
+    while scan_results.len() < ports.len() && start.elapsed() < Duration::from_secs(5) {
+        if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+            scan_results.push(scan_result);
+        }
+    }
+
+    scanner.shutdown().expect("failed to shut down scanner");
+    shutdown.swap(true, Ordering::Relaxed);
+    synacker_handle
+        .join()
+        .expect("failed to wait for synacker thread");
+
+    assert_eq!(scan_results.len(), ports.len());
+    assert!(scan_results
+        .iter()
+        .all(|r| r.tcp_flags == Some(TcpFlags::Synack)));
+}
+
+// A scanner dropped without shutting down closes the channels of the tx thread, which then
+// sends what it was given and stops instead of spinning
+#[test]
+fn tx_disconnect_test() {
+    let (tx_io, mut target_io) = Loopback::pair();
+    let (pkt_sender, pkts) = unbounded();
+    let (probe_sender, probes) = unbounded();
+    let (_answered_sender, answered) = unbounded();
+    let (_rate_limit_sender, rate_limit_updates) = unbounded();
+    let tx_handle = thread::Builder::new()
+        .name("tx test".into())
+        .spawn(move || {
+            start_tx(
+                tx_io,
+                pkts,
+                probes,
+                answered,
+                Retransmit::default(),
+                RateLimit::default(),
+                rate_limit_updates,
+                Arc::new(AtomicBool::new(false)),
+            )
+        })
+        .expect("failed to start tx thread");
+
+    pkt_sender.send(vec![1; 60]).expect("failed to send pkt");
+    thread::sleep(Duration::from_millis(100));
+    drop(pkt_sender);
+    drop(probe_sender);
+
+    let start = Instant::now();
+    while !tx_handle.is_finished() && start.elapsed() < Duration::from_secs(2) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(tx_handle.is_finished(), "tx thread still running");
+    tx_handle
+        .join()
+        .expect("tx thread panicked")
+        .expect("tx thread failed");
+
+    let mut frame = [0; MAX_PACKET_SIZE];
+    assert_eq!(
+        target_io.recv(&mut frame).expect("failed to recv"),
+        Some(60)
+    );
+}
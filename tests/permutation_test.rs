@@ -0,0 +1,67 @@
+use std::collections::HashSet;
+
+use rscan::permutation::Permutation;
+use rscan::targets::TargetSpec;
+
+#[test]
+fn permutation_covers_every_index_once_test() {
+    for &size in &[0u64, 1, 2, 255, 256, 1000, 70_000] {
+        let permutation = Permutation::new(u128::from(size), 42).unwrap();
+        let mut indices: Vec<u64> = permutation.iter().collect();
+        assert_eq!(indices.len() as u64, size);
+        indices.sort_unstable();
+        assert!(indices.iter().copied().eq(0..size), "size {}", size);
+    }
+}
+
+#[test]
+fn permutation_seed_test() {
+    let order = |seed| {
+        Permutation::new(1000, seed)
+            .unwrap()
+            .iter()
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(order(1), order(1));
+    assert_ne!(order(1), order(2));
+    assert_ne!(order(1), (0..1000).collect::<Vec<_>>());
+    assert!(Permutation::new(1u128 << 64, 1).is_err());
+}
+
+#[test]
+fn permutation_shard_test() {
+    let permutation = Permutation::new(5000, 7).unwrap();
+    let mut seen = HashSet::new();
+    for shard in 0..3 {
+        for index in permutation.shard(shard, 3) {
+            assert!(seen.insert(index), "index {} in more than one shard", index);
+        }
+    }
+    assert_eq!(seen.len(), 5000);
+}
+
+#[test]
+fn permutation_resume_test() {
+    let permutation = Permutation::new(5000, 7).unwrap();
+    let full: Vec<u64> = permutation.shard(1, 2).collect();
+
+    let mut order = permutation.shard(1, 2);
+    let first: Vec<u64> = order.by_ref().take(100).collect();
+    let position = order.position();
+    let rest: Vec<u64> = permutation.shard(1, 2).resume_at(position).collect();
+    assert_eq!([first, rest].concat(), full);
+}
+
+#[test]
+fn permuted_targets_test() {
+    let spec: TargetSpec = "10.0.0.0/24:80,443".parse().unwrap();
+    let permutation = Permutation::new(spec.len(), 3).unwrap();
+    let targets: Vec<_> = spec.permuted(permutation.iter()).collect();
+    assert_eq!(targets.len(), 512);
+
+    let permuted: HashSet<_> = targets.iter().map(|t| (t.ip, t.port)).collect();
+    let ordered: HashSet<_> = spec.iter().map(|t| (t.ip, t.port)).collect();
+    assert_eq!(permuted, ordered);
+    // consecutive targets are not all in the same part of the subnet
+    assert_ne!(targets[0].ip, targets[1].ip);
+}
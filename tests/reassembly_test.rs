@@ -0,0 +1,47 @@
+use rscan::reassembly::Stream;
+
+#[test]
+fn in_order_test() {
+    let mut stream = Stream::new(1000, 1024);
+    assert!(stream.insert(1000, b"HTTP/1.1 200 OK\r\n", false));
+    assert!(!stream.pushed());
+    assert!(stream.insert(1017, b"\r\n", true));
+    assert!(stream.pushed());
+    assert_eq!(stream.data(), b"HTTP/1.1 200 OK\r\n\r\n");
+    assert_eq!(stream.next_seq(), 1019);
+
+    // a retransmission adds nothing
+    assert!(!stream.insert(1000, b"HTTP/1.1 200 OK\r\n", false));
+    // a retransmission overlapping new data adds only the new part
+    assert!(stream.insert(1017, b"\r\nbody", false));
+    assert_eq!(stream.data(), b"HTTP/1.1 200 OK\r\n\r\nbody");
+}
+
+#[test]
+fn out_of_order_test() {
+    let mut stream = Stream::new(u32::MAX - 2, 1024);
+    // the end arrives first, past the wrap around of the sequence numbers
+    assert!(!stream.insert(5, b"ijk", true));
+    assert!(!stream.insert(2, b"fgh", false));
+    assert!(stream.data().is_empty());
+    assert!(!stream.pushed());
+
+    assert!(stream.insert(u32::MAX - 2, b"abcde", false));
+    assert_eq!(stream.data(), b"abcdefghijk");
+    assert_eq!(stream.next_seq(), 8);
+    assert!(stream.pushed());
+
+    assert_eq!(stream.take(), b"abcdefghijk");
+}
+
+#[test]
+fn max_capture_test() {
+    let mut stream = Stream::new(0, 8);
+    assert!(!stream.insert(6, b"ghijkl", false));
+    assert!(stream.insert(0, b"abcdef", false));
+    assert_eq!(stream.data(), b"abcdefgh");
+    assert!(stream.is_full());
+    assert_eq!(stream.window(), 0);
+    assert!(!stream.insert(8, b"mn", true));
+    assert_eq!(stream.data(), b"abcdefgh");
+}
@@ -1,4 +1,10 @@
 use afpacket::sync::RawPacketStream;
+use rscan::packet_io::Backend;
+use rscan::ratelimit::RateLimit;
+use rscan::recv::{DEFAULT_HOST_TIMEOUT, DEFAULT_MAX_CAPTURE, DEFAULT_MAX_HOSTS};
+use rscan::send::Retransmit;
+use rscan::{ScanConfig, Teardown};
+use std::net::Ipv4Addr;
 use std::sync::Once;
 
 static INIT: Once = Once::new();
@@ -11,6 +17,7 @@ fn setup_logging() {
 
 mod veth_setup;
 
+#[allow(dead_code)]
 pub fn run_test<F>(test: F)
 where
     F: Fn(RawPacketStream, RawPacketStream) + Send + 'static,
@@ -28,3 +35,41 @@ where
 
     veth_setup::run_with_dev(inner);
 }
+
+// Like run_test, for tests which open the veth pair themselves
+#[allow(dead_code)]
+pub fn run_test_with_devs<F>(test: F)
+where
+    F: FnOnce(String, String) + Send + 'static,
+{
+    setup_logging();
+    veth_setup::run_with_dev(test);
+}
+
+// What the tests scan with unless they say otherwise: from 192.168.69.1 and the source ports
+// 10000-10999, with the handshakes of the repo and without the default blocklist
+#[allow(dead_code)]
+pub fn scan_config() -> ScanConfig {
+    ScanConfig {
+        src_mac: [0, 0, 0, 0, 0, 0],
+        dst_mac: [0, 0, 0, 0, 0, 0],
+        src_ipv4: Some(Ipv4Addr::new(192, 168, 69, 1)),
+        src_ipv6: None,
+        src_ports: 10000..=10999,
+        handshakes_file: Some("handshakes.yaml".into()),
+        service_probes_file: None,
+        rate_limit: RateLimit::default(),
+        secret: rand::random(),
+        blocklist_files: vec![],
+        allowlist_files: vec![],
+        default_blocklist: false,
+        log_blocked: false,
+        host_timeout: DEFAULT_HOST_TIMEOUT,
+        max_hosts: DEFAULT_MAX_HOSTS,
+        banner_wait: None,
+        max_capture: DEFAULT_MAX_CAPTURE,
+        teardown: Teardown::Rst,
+        retransmit: Retransmit::default(),
+        backend: Backend::default(),
+    }
+}
@@ -1,19 +1,23 @@
 mod setup;
 
+use std::collections::HashMap;
 use std::io::prelude::*;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::num::NonZeroU64;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use etherparse::{ip_number, SlicedPacket};
+use etherparse::{ip_number, PacketBuilder, SlicedPacket, TransportSlice};
 
 use afpacket::sync::RawPacketStream;
-use rscan::packet::build_tcp_response;
-use rscan::{ScanConfig, Scanner, Target, TcpFlags};
+use rscan::packet::{build_response_ip_header, build_tcp_response};
+use rscan::ratelimit::RateLimit;
+use rscan::recv::{DEFAULT_HOST_TIMEOUT, DEFAULT_MAX_HOSTS};
+use rscan::send::Retransmit;
+use rscan::{ScanConfig, ScanError, Scanner, Target, TcpFlags};
 
-const SRC_IP: [u8; 4] = [192, 168, 69, 1];
 const DST_IP: [u8; 4] = [192, 168, 69, 2];
 
 const MAX_PACKET_SIZE: usize = 1500;
@@ -38,19 +42,82 @@ fn synacker(mut ps: RawPacketStream, shutdown: Arc<AtomicBool>) {
     }
 }
 
+// Ports the lossy synacker never answers
+const SILENT_PORTS: std::ops::Range<u16> = 100..105;
+
+// Drop the first SYN to every port and answer the others, except on SILENT_PORTS where nothing is
+// answered. Returns the number of SYNs seen by port.
+fn lossy_synacker(mut ps: RawPacketStream, shutdown: Arc<AtomicBool>) -> HashMap<u16, usize> {
+    let mut rx_pkt = [0; MAX_PACKET_SIZE];
+    let mut tx_pkt = [0; MAX_PACKET_SIZE];
+    let mut syns = HashMap::new();
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let len = ps.read(&mut rx_pkt).expect("failed to read pkt");
+        let sliced = match SlicedPacket::from_ethernet(&rx_pkt[..len]) {
+            Ok(sliced) => sliced,
+            Err(e) => {
+                log::error!("Err {:?}", e);
+                continue;
+            }
+        };
+        let tcp = match &sliced.transport {
+            Some(TransportSlice::Tcp(tcp)) if tcp.syn() && !tcp.ack() => tcp,
+            _ => continue,
+        };
+        let count = syns.entry(tcp.destination_port()).or_insert(0);
+        *count += 1;
+        if *count == 1 || SILENT_PORTS.contains(&tcp.destination_port()) {
+            continue;
+        }
+        if let Some(len) = build_tcp_response(&sliced, &[], &mut tx_pkt) {
+            ps.write_all(&tx_pkt[..len]).expect("failed to write pkt");
+        }
+    }
+    syns
+}
+
+// Answer every SYN with a SYN-ACK that does not acknowledge the SYN's sequence number
+fn spoofer(mut ps: RawPacketStream, shutdown: Arc<AtomicBool>) {
+    let mut rx_pkt = [0; MAX_PACKET_SIZE];
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let len = ps.read(&mut rx_pkt).expect("failed to read pkt");
+        let sliced = match SlicedPacket::from_ethernet(&rx_pkt[..len]) {
+            Ok(sliced) => sliced,
+            Err(e) => {
+                log::error!("Err {:?}", e);
+                continue;
+            }
+        };
+        let tcp = match &sliced.transport {
+            Some(TransportSlice::Tcp(tcp)) if tcp.syn() && !tcp.ack() => tcp,
+            _ => continue,
+        };
+        let builder = PacketBuilder::ethernet2([0; 6], [0; 6]);
+        let builder = build_response_ip_header(&sliced, builder)
+            .expect("failed to build ip header")
+            .tcp(tcp.destination_port(), tcp.source_port(), 0, 65535)
+            .syn()
+            .ack(tcp.sequence_number().wrapping_add(2));
+        let mut tx_pkt = Vec::with_capacity(builder.size(0));
+        builder
+            .write(&mut tx_pkt, &[])
+            .expect("failed to write pkt");
+        ps.write_all(&tx_pkt).expect("failed to write pkt");
+    }
+}
+
 #[test]
 fn syn_test() {
     fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
-        let scan_config = ScanConfig {
-            src_mac: [0, 0, 0, 0, 0, 0],
-            dst_mac: [0, 0, 0, 0, 0, 0],
-            src_ipv4: Some(Ipv4Addr::from(SRC_IP)),
-            src_ipv6: None,
-            src_port: 10000,
-            handshakes_file: "handshakes.yaml".into(),
-        };
+        let scan_config = setup::scan_config();
 
-        let scanner = Scanner::new(dev1_ps, scan_config);
+        let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
         let shutdown = Arc::new(AtomicBool::new(false));
         let test_receiver_shutdown = shutdown.clone();
         let test_receiver_handle = thread::Builder::new()
@@ -64,18 +131,18 @@ fn syn_test() {
 
         let max_port = 65000;
         let targets: Vec<Target> = (1..max_port)
-            .into_iter()
             .map(|port| Target {
                 ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
                 port,
                 ip_number: u8::from(ip_number::TCP),
                 data: None,
+                hostname: None,
             })
             .collect();
 
         for target in targets.iter() {
             thread::sleep(Duration::from_micros(1));
-            scanner.scan_target(&target);
+            scanner.scan_target(target).expect("failed to scan target");
         }
 
         let mut scan_results = vec![];
@@ -85,10 +152,10 @@ fn syn_test() {
         while scan_results.len() < targets.len() && start.elapsed() < rx_timeout {
             match scanner.result_receiver.try_recv() {
                 Ok(scan_result) => {
-                    if scan_result.ip == IpAddr::V4(Ipv4Addr::from(DST_IP)) {
-                        if scan_result.tcp_flags == Some(TcpFlags::Synack) {
-                            scan_results.push(scan_result);
-                        }
+                    if scan_result.ip == IpAddr::V4(Ipv4Addr::from(DST_IP))
+                        && scan_result.tcp_flags == Some(TcpFlags::Synack)
+                    {
+                        scan_results.push(scan_result);
                     }
                 }
                 Err(_e) => {
@@ -97,11 +164,11 @@ fn syn_test() {
             }
         }
 
-        scanner.shutdown();
+        scanner.shutdown().expect("failed to shut down scanner");
 
         shutdown.swap(true, Ordering::Relaxed);
 
-        let _ = test_receiver_handle
+        test_receiver_handle
             .join()
             .expect("failed to wait for receive thread");
 
@@ -112,3 +179,343 @@ fn syn_test() {
 
     setup::run_test(test_fn);
 }
+
+#[test]
+fn spoofed_synack_test() {
+    fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
+        let scan_config = setup::scan_config();
+
+        let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let test_receiver_shutdown = shutdown.clone();
+        let test_receiver_handle = thread::Builder::new()
+            .name("spoofer test".into())
+            .spawn(move || {
+                spoofer(dev2_ps, test_receiver_shutdown);
+            })
+            .expect("failed to start spoofer thread");
+
+        thread::sleep(Duration::from_secs(1));
+
+        let targets: Vec<Target> = (1..100)
+            .map(|port| Target {
+                ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+                port,
+                ip_number: u8::from(ip_number::TCP),
+                data: None,
+                hostname: None,
+            })
+            .collect();
+
+        for target in targets.iter() {
+            scanner.scan_target(target).expect("failed to scan target");
+        }
+
+        let mut scan_results = vec![];
+        let rx_timeout = Duration::from_secs(2);
+        let start = Instant::now();
+        while start.elapsed() < rx_timeout {
+            if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+                scan_results.push(scan_result);
+            }
+        }
+
+        let validation_failed = scanner.stats().validation_failed();
+        scanner.shutdown().expect("failed to shut down scanner");
+
+        shutdown.swap(true, Ordering::Relaxed);
+
+        test_receiver_handle
+            .join()
+            .expect("failed to wait for receive thread");
+
+        assert!(scan_results.is_empty());
+        assert_eq!(targets.len() as u64, validation_failed);
+    }
+
+    setup::run_test(test_fn);
+}
+
+fn expiry_scan_config(host_timeout: Duration, max_hosts: usize) -> ScanConfig {
+    ScanConfig {
+        host_timeout,
+        max_hosts,
+        ..setup::scan_config()
+    }
+}
+
+fn tcp_target(port: u16) -> Target {
+    Target {
+        ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+        port,
+        ip_number: u8::from(ip_number::TCP),
+        data: None,
+        hostname: None,
+    }
+}
+
+#[test]
+fn host_expiry_test() {
+    fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
+        let scanner = Scanner::new(dev1_ps, expiry_scan_config(Duration::from_secs(1), 1000))
+            .expect("failed to start scanner");
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let test_receiver_shutdown = shutdown.clone();
+        let test_receiver_handle = thread::Builder::new()
+            .name("synacker test".into())
+            .spawn(move || {
+                synacker(dev2_ps, test_receiver_shutdown);
+            })
+            .expect("failed to start synacker thread");
+
+        thread::sleep(Duration::from_secs(1));
+
+        // the synacker never answers the handshake, so the connection to port 80 must expire.
+        // keep the rx thread busy with other targets in the meantime
+        scanner
+            .scan_target(&tcp_target(80))
+            .expect("failed to scan target");
+        let mut scan_results = vec![];
+        let start = Instant::now();
+        let mut port = 1000;
+        while start.elapsed() < Duration::from_secs(4) {
+            scanner
+                .scan_target(&tcp_target(port))
+                .expect("failed to scan target");
+            port += 1;
+            thread::sleep(Duration::from_millis(100));
+            while let Ok(scan_result) = scanner.result_receiver.try_recv() {
+                scan_results.push(scan_result);
+            }
+        }
+
+        let hosts_expired = scanner.stats().hosts_expired();
+        scanner.shutdown().expect("failed to shut down scanner");
+        shutdown.swap(true, Ordering::Relaxed);
+        test_receiver_handle
+            .join()
+            .expect("failed to wait for receive thread");
+
+        assert!(scan_results
+            .iter()
+            .any(|r| r.port == 80 && r.tcp_flags == Some(TcpFlags::Synack) && !r.timed_out));
+        assert!(scan_results.iter().any(|r| r.port == 80 && r.timed_out));
+        assert!(hosts_expired >= 1);
+    }
+
+    setup::run_test(test_fn);
+}
+
+#[test]
+fn host_eviction_test() {
+    fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
+        let max_hosts = 10;
+        let scanner = Scanner::new(dev1_ps, expiry_scan_config(DEFAULT_HOST_TIMEOUT, max_hosts))
+            .expect("failed to start scanner");
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let test_receiver_shutdown = shutdown.clone();
+        let test_receiver_handle = thread::Builder::new()
+            .name("synacker test".into())
+            .spawn(move || {
+                synacker(dev2_ps, test_receiver_shutdown);
+            })
+            .expect("failed to start synacker thread");
+
+        thread::sleep(Duration::from_secs(1));
+
+        let num_targets = 50;
+        for port in 1..=num_targets {
+            scanner
+                .scan_target(&tcp_target(port))
+                .expect("failed to scan target");
+        }
+
+        let mut timed_out = 0;
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(2) {
+            if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+                if scan_result.timed_out {
+                    timed_out += 1;
+                }
+            }
+        }
+
+        let hosts_evicted = scanner.stats().hosts_evicted();
+        scanner.shutdown().expect("failed to shut down scanner");
+        shutdown.swap(true, Ordering::Relaxed);
+        test_receiver_handle
+            .join()
+            .expect("failed to wait for receive thread");
+
+        assert_eq!(hosts_evicted, u64::from(num_targets) - max_hosts as u64);
+        assert_eq!(timed_out, hosts_evicted);
+    }
+
+    setup::run_test(test_fn);
+}
+
+#[test]
+fn retransmit_test() {
+    fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
+        let scan_config = ScanConfig {
+            handshakes_file: None,
+            retransmit: Retransmit {
+                probes: 3,
+                delay: Duration::from_millis(200),
+                backoff: true,
+            },
+            ..expiry_scan_config(DEFAULT_HOST_TIMEOUT, DEFAULT_MAX_HOSTS)
+        };
+        let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let test_receiver_shutdown = shutdown.clone();
+        let test_receiver_handle = thread::Builder::new()
+            .name("lossy synacker test".into())
+            .spawn(move || lossy_synacker(dev2_ps, test_receiver_shutdown))
+            .expect("failed to start lossy synacker thread");
+
+        thread::sleep(Duration::from_secs(1));
+
+        let ports: Vec<u16> = (1..20).collect();
+        for port in ports.iter().copied().chain(SILENT_PORTS) {
+            scanner
+                .scan_target(&tcp_target(port))
+                .expect("failed to scan target");
+        }
+
+        // the probes go out after 0, 200 and 600ms, unless the target answered
+        let mut scan_results = vec![];
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(2) {
+            if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+                scan_results.push(scan_result);
+            }
+        }
+
+        scanner.shutdown().expect("failed to shut down scanner");
+        shutdown.swap(true, Ordering::Relaxed);
+        let syns = test_receiver_handle
+            .join()
+            .expect("failed to wait for receive thread");
+
+        for port in ports.iter() {
+            // the second SYN was answered, so the third was not sent
+            assert_eq!(syns.get(port), Some(&2), "port {}", port);
+            let results = scan_results.iter().filter(|r| r.port == *port).count();
+            assert_eq!(results, 1, "port {}", port);
+        }
+        // unanswered SYNs are sent as often as configured
+        for port in SILENT_PORTS {
+            assert_eq!(syns.get(&port), Some(&3), "port {}", port);
+        }
+        assert!(scan_results
+            .iter()
+            .all(|r| r.tcp_flags == Some(TcpFlags::Synack) && ports.contains(&r.port)));
+    }
+
+    setup::run_test(test_fn);
+}
+
+#[test]
+fn rate_limit_test() {
+    fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
+        let scan_config = ScanConfig {
+            rate_limit: RateLimit {
+                packets_per_second: NonZeroU64::new(10),
+                bits_per_second: None,
+                burst: 1,
+            },
+            ..setup::scan_config()
+        };
+        let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let test_receiver_shutdown = shutdown.clone();
+        let test_receiver_handle = thread::Builder::new()
+            .name("synacker test".into())
+            .spawn(move || {
+                synacker(dev2_ps, test_receiver_shutdown);
+            })
+            .expect("failed to start synacker thread");
+
+        thread::sleep(Duration::from_secs(1));
+
+        let ports = 1..=100;
+        for port in ports.clone() {
+            scanner
+                .scan_target(&tcp_target(port))
+                .expect("failed to scan target");
+        }
+
+        let mut limited = 0;
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+                if scan_result.tcp_flags == Some(TcpFlags::Synack) {
+                    limited += 1;
+                }
+            }
+        }
+
+        // lifting the limit lets the rest of the targets go at once
+        scanner
+            .set_rate_limit(RateLimit::default())
+            .expect("failed to set rate limit");
+        let mut unlimited = 0;
+        let start = Instant::now();
+        while limited + unlimited < ports.len() && start.elapsed() < Duration::from_secs(2) {
+            if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+                if scan_result.tcp_flags == Some(TcpFlags::Synack) {
+                    unlimited += 1;
+                }
+            }
+        }
+
+        scanner.shutdown().expect("failed to shut down scanner");
+        shutdown.swap(true, Ordering::Relaxed);
+        test_receiver_handle
+            .join()
+            .expect("failed to wait for receive thread");
+
+        // about ten SYNs went out in the first second
+        assert!((5..=15).contains(&limited), "{}", limited);
+        assert_eq!(limited + unlimited, ports.len());
+    }
+
+    setup::run_test(test_fn);
+}
+
+#[test]
+fn scan_error_test() {
+    fn test_fn(dev1_ps: RawPacketStream, _dev2_ps: RawPacketStream) {
+        let scan_config = ScanConfig {
+            handshakes_file: Some("does-not-exist.yaml".into()),
+            ..expiry_scan_config(DEFAULT_HOST_TIMEOUT, DEFAULT_MAX_HOSTS)
+        };
+        match Scanner::new(dev1_ps.clone(), scan_config) {
+            Err(ScanError::Handshakes(_)) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("started a scanner without handshakes"),
+        }
+
+        let scanner = Scanner::new(
+            dev1_ps,
+            expiry_scan_config(DEFAULT_HOST_TIMEOUT, DEFAULT_MAX_HOSTS),
+        )
+        .expect("failed to start scanner");
+        // there is no source Ipv6 address to probe an Ipv6 target from
+        let target = Target {
+            ip: IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ..tcp_target(80)
+        };
+        assert!(matches!(
+            scanner.scan_target(&target),
+            Err(ScanError::MissingIpv6)
+        ));
+        // a bad target does not stop the scanner threads
+        let errors = scanner.error_receiver.clone();
+        scanner.shutdown().expect("failed to shut down scanner");
+        assert!(errors.try_recv().is_err());
+    }
+
+    setup::run_test(test_fn);
+}
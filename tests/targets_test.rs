@@ -0,0 +1,94 @@
+use std::net::IpAddr;
+
+use etherparse::ip_number;
+use rscan::targets::{IpPrefix, TargetSpec};
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn target_spec_expansion_test() {
+    let spec: TargetSpec = "10.0.0.0/30,192.168.1.7:80,443,8000-8002"
+        .parse()
+        .expect("failed to parse spec");
+    assert_eq!(spec.len(), 25);
+
+    let targets: Vec<_> = spec.iter().collect();
+    assert_eq!(targets.len(), 25);
+    assert_eq!((targets[0].ip, targets[0].port), (ip("10.0.0.0"), 80));
+    assert_eq!((targets[1].ip, targets[1].port), (ip("10.0.0.0"), 443));
+    assert_eq!((targets[4].ip, targets[4].port), (ip("10.0.0.0"), 8002));
+    assert_eq!((targets[5].ip, targets[5].port), (ip("10.0.0.1"), 80));
+    assert_eq!(
+        (targets[24].ip, targets[24].port),
+        (ip("192.168.1.7"), 8002)
+    );
+    assert!(targets
+        .iter()
+        .all(|t| t.ip_number == u8::from(ip_number::TCP) && t.data.is_none()));
+
+    // random access agrees with iteration
+    for (i, target) in targets.iter().enumerate() {
+        let indexed = spec.get(i as u128).unwrap();
+        assert_eq!((indexed.ip, indexed.port), (target.ip, target.port));
+    }
+    assert!(spec.get(25).is_none());
+}
+
+#[test]
+fn target_spec_ipv6_test() {
+    let spec: TargetSpec = "[2001:db8::1]:53".parse().unwrap();
+    let targets: Vec<_> = spec.iter().collect();
+    assert_eq!(targets.len(), 1);
+    assert_eq!((targets[0].ip, targets[0].port), (ip("2001:db8::1"), 53));
+
+    let spec: TargetSpec = "2001:db8::/126,2001:db8:1::/127:22".parse().unwrap();
+    let ips: Vec<_> = spec.iter().map(|t| t.ip).collect();
+    assert_eq!(
+        ips,
+        vec![
+            ip("2001:db8::"),
+            ip("2001:db8::1"),
+            ip("2001:db8::2"),
+            ip("2001:db8::3"),
+            ip("2001:db8:1::"),
+            ip("2001:db8:1::1"),
+        ]
+    );
+
+    // without ports every address is probed once with port 0
+    let spec: TargetSpec = "::1".parse().unwrap();
+    let targets: Vec<_> = spec
+        .with_ip_number(u8::from(ip_number::ICMP))
+        .iter()
+        .collect();
+    assert_eq!((targets[0].ip, targets[0].port), (ip("::1"), 0));
+}
+
+#[test]
+fn target_spec_lazy_test() {
+    // far too many targets to hold in memory, so they must be produced lazily
+    let spec: TargetSpec = "2001:db8::/64:1-65535".parse().unwrap();
+    assert_eq!(spec.len(), (1u128 << 64) * 65535);
+    let target = spec.iter().nth(65535).unwrap();
+    assert_eq!((target.ip, target.port), (ip("2001:db8::1"), 1));
+    let last = spec.get(spec.len() - 1).unwrap();
+    assert_eq!(
+        (last.ip, last.port),
+        (ip("2001:db8::ffff:ffff:ffff:ffff"), 65535)
+    );
+}
+
+#[test]
+fn target_spec_error_test() {
+    assert!("10.0.0.0/33:80".parse::<TargetSpec>().is_err());
+    assert!("10.0.0.300:80".parse::<TargetSpec>().is_err());
+    assert!("10.0.0.0/8:80-79".parse::<TargetSpec>().is_err());
+    assert!("10.0.0.0/8:http".parse::<TargetSpec>().is_err());
+
+    let prefix: IpPrefix = "10.1.2.3/8".parse().unwrap();
+    assert_eq!(prefix.to_string(), "10.0.0.0/8");
+    assert!(prefix.contains(ip("10.255.0.1")));
+    assert!(!prefix.contains(ip("11.0.0.1")));
+}
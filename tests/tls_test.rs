@@ -0,0 +1,320 @@
+mod setup;
+
+use std::fs;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use etherparse::{ip_number, PacketBuilder, SlicedPacket, TransportSlice};
+
+use afpacket::sync::RawPacketStream;
+use rscan::packet::{build_response_ip_header, build_tcp_response};
+use rscan::tls::{client_hello, parse_server_flight, TlsConfig, TlsError, TlsVersion};
+use rscan::{ScanResult, Scanner, Target, TcpFlags};
+
+const DST_IP: [u8; 4] = [192, 168, 69, 2];
+
+const MAX_PACKET_SIZE: usize = 1500;
+
+fn read_cert() -> Vec<u8> {
+    fs::read("test_data/tls-cert.der").expect("failed to read certificate")
+}
+
+fn u16_bytes(value: usize) -> [u8; 2] {
+    (value as u16).to_be_bytes()
+}
+
+fn u24_bytes(value: usize) -> [u8; 3] {
+    let bytes = (value as u32).to_be_bytes();
+    [bytes[1], bytes[2], bytes[3]]
+}
+
+fn handshake_message(message_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = vec![message_type];
+    message.extend(u24_bytes(body.len()));
+    message.extend(body);
+    message
+}
+
+fn record(content_type: u8, fragment: &[u8]) -> Vec<u8> {
+    let mut record = vec![content_type, 3, 3];
+    record.extend(u16_bytes(fragment.len()));
+    record.extend(fragment);
+    record
+}
+
+fn server_hello(version: u16, suite: u16, alpn: Option<&str>) -> Vec<u8> {
+    let mut body = vec![3, 3];
+    body.extend([7; 32]);
+    body.push(0);
+    body.extend(suite.to_be_bytes());
+    body.push(0);
+    let mut extensions = vec![];
+    if version == 0x0304 {
+        extensions.extend([0, 43, 0, 2, 3, 4]);
+    }
+    if let Some(alpn) = alpn {
+        extensions.extend([0, 16]);
+        extensions.extend(u16_bytes(alpn.len() + 3));
+        extensions.extend(u16_bytes(alpn.len() + 1));
+        extensions.push(alpn.len() as u8);
+        extensions.extend(alpn.as_bytes());
+    }
+    body.extend(u16_bytes(extensions.len()));
+    body.extend(extensions);
+    handshake_message(2, &body)
+}
+
+// ServerHello, Certificate and ServerHelloDone of TLS 1.2, with the certificate message in a
+// record of its own
+fn server_flight(alpn: Option<&str>) -> Vec<u8> {
+    let cert = read_cert();
+    let mut certificates = vec![];
+    certificates.extend(u24_bytes(cert.len() + 3));
+    certificates.extend(u24_bytes(cert.len()));
+    certificates.extend(&cert);
+
+    let mut flight = record(22, &server_hello(0x0303, 0xc02f, alpn));
+    let mut handshake = handshake_message(11, &certificates);
+    handshake.extend(handshake_message(14, &[]));
+    flight.extend(record(22, &handshake));
+    flight
+}
+
+#[test]
+fn client_hello_test() {
+    let conf = TlsConfig {
+        alpn: vec!["h2".into(), "http/1.1".into()],
+        ..Default::default()
+    };
+    let hello = client_hello(&conf, Some("example.com"));
+    assert_eq!(&hello[..3], &[22, 3, 1]);
+    assert_eq!(
+        usize::from(u16::from_be_bytes([hello[3], hello[4]])),
+        hello.len() - 5
+    );
+    // ClientHello, version 1.2
+    assert_eq!(hello[5], 1);
+    assert_eq!(&hello[9..11], &[3, 3]);
+    let contains = |hello: &[u8], needle: &[u8]| hello.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(&hello, b"\x00\x0bexample.com"));
+    assert!(contains(&hello, b"\x02h2\x08http/1.1"));
+    // no supported_versions extension below 1.3
+    assert!(!contains(&hello, &[0, 43, 0, 5, 4, 3, 4, 3, 3]));
+
+    // addresses are not sent as SNI
+    let hello = client_hello(&conf, Some("192.0.2.1"));
+    assert!(!contains(&hello, b"192.0.2.1"));
+
+    let conf = TlsConfig {
+        max_version: TlsVersion::Tls13,
+        cipher_suites: vec![0x1301, 0xc02f],
+        ..Default::default()
+    };
+    let hello = client_hello(&conf, None);
+    assert!(contains(&hello, &[0, 4, 0x13, 0x01, 0xc0, 0x2f]));
+    assert!(contains(&hello, &[0, 43, 0, 5, 4, 3, 4, 3, 3]));
+}
+
+#[test]
+fn server_flight_test() {
+    let flight = server_flight(Some("h2"));
+
+    // split in the middle of the certificate
+    let partial = parse_server_flight(&flight[..200]).unwrap();
+    assert!(!partial.complete);
+    assert_eq!(partial.info.version, Some("TLSv1.2".into()));
+
+    let parsed = parse_server_flight(&flight).unwrap();
+    assert!(parsed.complete);
+    let info = parsed.info;
+    assert_eq!(
+        info.cipher_suite,
+        Some("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".into())
+    );
+    assert_eq!(info.alpn, Some("h2".into()));
+    assert_eq!(info.certificates.len(), 1);
+    let cert = &info.certificates[0];
+    assert_eq!(cert.der, read_cert());
+    assert_eq!(cert.subject, Some("CN=example.com, O=rscan test".into()));
+    assert_eq!(cert.issuer, cert.subject);
+    assert_eq!(
+        cert.sans,
+        vec!["example.com", "www.example.com", "192.0.2.1"]
+    );
+    assert!(cert.not_before.is_some() && cert.not_after.is_some());
+
+    // everything after the ServerHello of 1.3 is encrypted
+    let mut flight = record(22, &server_hello(0x0304, 0x1301, None));
+    flight.extend(record(20, &[1]));
+    let parsed = parse_server_flight(&flight).unwrap();
+    assert!(parsed.complete);
+    assert_eq!(parsed.info.version, Some("TLSv1.3".into()));
+    assert_eq!(
+        parsed.info.cipher_suite,
+        Some("TLS_AES_128_GCM_SHA256".into())
+    );
+    assert!(parsed.info.certificates.is_empty());
+
+    // handshake_failure
+    let parsed = parse_server_flight(&record(21, &[2, 40])).unwrap();
+    assert!(parsed.complete);
+    assert_eq!(parsed.info.alert, Some(40));
+
+    assert_eq!(
+        parse_server_flight(b"HTTP/1.1 400 Bad Request\r\n"),
+        Err(TlsError::NotTls)
+    );
+}
+
+// Answers a ClientHello with the flight, split over two segments
+fn tls_responder(mut ps: RawPacketStream, flight: Vec<u8>, shutdown: Arc<AtomicBool>) {
+    let mut rx_pkt = [0; MAX_PACKET_SIZE];
+    let mut tx_pkt = [0; MAX_PACKET_SIZE];
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let len = ps.read(&mut rx_pkt).expect("failed to read pkt");
+        let sliced = match SlicedPacket::from_ethernet(&rx_pkt[..len]) {
+            Ok(sliced) => sliced,
+            Err(e) => {
+                log::error!("Err {:?}", e);
+                continue;
+            }
+        };
+        // skip our own packets, which come from the scanned ports
+        let tcp = match &sliced.transport {
+            Some(TransportSlice::Tcp(tcp)) if tcp.source_port() >= 10000 => tcp,
+            _ => continue,
+        };
+        if tcp.syn() {
+            if let Some(len) = build_tcp_response(&sliced, &[], &mut tx_pkt) {
+                ps.write_all(&tx_pkt[..len]).expect("failed to write pkt");
+            }
+            continue;
+        }
+        if !sliced.payload.starts_with(&[22, 3]) {
+            continue;
+        }
+        let ack = tcp
+            .sequence_number()
+            .wrapping_add(sliced.payload.len() as u32);
+        let mut seq = tcp.acknowledgment_number();
+        for segment in flight.chunks(flight.len() / 2 + 1) {
+            let builder = PacketBuilder::ethernet2([0; 6], [0; 6]);
+            let builder = build_response_ip_header(&sliced, builder)
+                .expect("failed to build ip header")
+                .tcp(tcp.destination_port(), tcp.source_port(), seq, 65535)
+                .ack(ack);
+            let mut tx_pkt = Vec::with_capacity(builder.size(segment.len()));
+            builder
+                .write(&mut tx_pkt, segment)
+                .expect("failed to write pkt");
+            ps.write_all(&tx_pkt).expect("failed to write pkt");
+            seq = seq.wrapping_add(segment.len() as u32);
+        }
+    }
+}
+
+// Scan the ports with a tls responder sending the flight, and return the handshake result of
+// every port
+fn tls_scan(
+    dev1_ps: RawPacketStream,
+    dev2_ps: RawPacketStream,
+    flight: Vec<u8>,
+    ports: &[u16],
+) -> Vec<ScanResult> {
+    let scan_config = setup::scan_config();
+
+    let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let test_receiver_shutdown = shutdown.clone();
+    let test_receiver_handle = thread::Builder::new()
+        .name("tls test".into())
+        .spawn(move || {
+            tls_responder(dev2_ps, flight, test_receiver_shutdown);
+        })
+        .expect("failed to start tls responder thread");
+
+    thread::sleep(Duration::from_secs(1));
+
+    for port in ports.iter() {
+        scanner
+            .scan_target(&Target {
+                ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+                port: *port,
+                ip_number: u8::from(ip_number::TCP),
+                data: None,
+                hostname: Some("example.com".into()),
+            })
+            .expect("failed to scan target");
+    }
+
+    let mut results = vec![];
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(3) {
+        if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+            if scan_result.tcp_flags != Some(TcpFlags::Synack) {
+                results.push(scan_result);
+            }
+        }
+    }
+
+    scanner.shutdown().expect("failed to shut down scanner");
+    shutdown.swap(true, Ordering::Relaxed);
+    test_receiver_handle
+        .join()
+        .expect("failed to wait for receive thread");
+
+    for port in ports.iter() {
+        let port_results = results.iter().filter(|r| r.port == *port).count();
+        assert_eq!(port_results, 1, "port {}", port);
+    }
+    results
+}
+
+#[test]
+fn tls_handshake_test() {
+    fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
+        let flight = server_flight(Some("h2"));
+        let results = tls_scan(dev1_ps, dev2_ps, flight.clone(), &[443, 8443]);
+        for result in results.iter() {
+            assert_eq!(result.tcp_flags, Some(TcpFlags::Ack));
+            assert_eq!(result.service, Some("tls".into()));
+            assert_eq!(result.probes, vec!["tls"]);
+            // the two segments were put back together
+            assert_eq!(result.data, flight);
+            let tls = result.tls.as_ref().expect("no tls info");
+            assert_eq!(tls.version, Some("TLSv1.2".into()));
+            assert_eq!(tls.alpn, Some("h2".into()));
+            assert_eq!(tls.certificates[0].sans[0], "example.com");
+            assert_eq!(tls.error, None);
+        }
+    }
+
+    setup::run_test(test_fn);
+}
+
+#[test]
+fn tls_malformed_test() {
+    // a ServerHello too short for its fields ends the scan of the port as tls, no other
+    // handshake is tried
+    fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
+        let flight = record(22, &handshake_message(2, &[3, 3]));
+        let results = tls_scan(dev1_ps, dev2_ps, flight.clone(), &[443]);
+        let result = &results[0];
+        assert_eq!(result.tcp_flags, Some(TcpFlags::Ack));
+        assert!(!result.timed_out);
+        assert_eq!(result.service, Some("tls".into()));
+        assert_eq!(result.probes, vec!["tls"]);
+        assert_eq!(result.data, flight);
+        let tls = result.tls.as_ref().expect("no tls info");
+        assert_eq!(tls.error, Some("Malformed ServerHello".into()));
+    }
+
+    setup::run_test(test_fn);
+}
@@ -0,0 +1,125 @@
+mod setup;
+
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use etherparse::{ip_number, PacketBuilder, SlicedPacket, TransportSlice};
+
+use afpacket::sync::RawPacketStream;
+use rscan::packet::build_response_ip_header;
+use rscan::udp::default_probe;
+use rscan::{ScanResult, Scanner, Target};
+
+const DST_IP: [u8; 4] = [192, 168, 69, 2];
+
+const MAX_PACKET_SIZE: usize = 1500;
+
+// Send every UDP datagram back to where it came from
+fn udp_echo(mut ps: RawPacketStream, shutdown: Arc<AtomicBool>) {
+    let mut rx_pkt = [0; MAX_PACKET_SIZE];
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let len = ps.read(&mut rx_pkt).expect("failed to read pkt");
+        let sliced = match SlicedPacket::from_ethernet(&rx_pkt[..len]) {
+            Ok(sliced) => sliced,
+            Err(e) => {
+                log::error!("Err {:?}", e);
+                continue;
+            }
+        };
+        let udp = match &sliced.transport {
+            Some(TransportSlice::Udp(udp)) => udp,
+            _ => continue,
+        };
+        let builder = PacketBuilder::ethernet2([0; 6], [0; 6]);
+        let builder = build_response_ip_header(&sliced, builder)
+            .expect("failed to build ip header")
+            .udp(udp.destination_port(), udp.source_port());
+        let mut tx_pkt = Vec::with_capacity(builder.size(sliced.payload.len()));
+        builder
+            .write(&mut tx_pkt, sliced.payload)
+            .expect("failed to write pkt");
+        ps.write_all(&tx_pkt).expect("failed to write pkt");
+    }
+}
+
+#[test]
+fn udp_test() {
+    fn test_fn(dev1_ps: RawPacketStream, dev2_ps: RawPacketStream) {
+        let scan_config = setup::scan_config();
+
+        let scanner = Scanner::new(dev1_ps, scan_config).expect("failed to start scanner");
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let test_receiver_shutdown = shutdown.clone();
+        let test_receiver_handle = thread::Builder::new()
+            .name("udp test".into())
+            .spawn(move || {
+                udp_echo(dev2_ps, test_receiver_shutdown);
+            })
+            .expect("failed to start udp echo thread");
+
+        thread::sleep(Duration::from_secs(1));
+
+        let ports = [53, 123, 161, 1900, 11211, 4444];
+        let targets: Vec<Target> = ports
+            .iter()
+            .map(|&port| Target {
+                ip: IpAddr::V4(Ipv4Addr::from(DST_IP)),
+                port,
+                ip_number: u8::from(ip_number::UDP),
+                data: if port == 4444 {
+                    Some(b"custom payload".to_vec())
+                } else {
+                    None
+                },
+                hostname: None,
+            })
+            .collect();
+
+        for target in targets.iter() {
+            scanner.scan_target(target).expect("failed to scan target");
+        }
+
+        let mut scan_results: Vec<ScanResult> = vec![];
+        let rx_timeout = Duration::from_secs(5);
+        let start = Instant::now();
+        while scan_results.len() < targets.len() && start.elapsed() < rx_timeout {
+            if let Ok(scan_result) = scanner.result_receiver.try_recv() {
+                if scan_result.ip == IpAddr::V4(Ipv4Addr::from(DST_IP)) {
+                    scan_results.push(scan_result);
+                }
+            }
+        }
+
+        scanner.shutdown().expect("failed to shut down scanner");
+
+        shutdown.swap(true, Ordering::Relaxed);
+
+        test_receiver_handle
+            .join()
+            .expect("failed to wait for receive thread");
+
+        assert_eq!(targets.len(), scan_results.len());
+        for result in scan_results {
+            assert_eq!(result.transport_protocol, u8::from(ip_number::UDP));
+            match default_probe(result.port) {
+                Some(probe) => {
+                    assert_eq!(result.service.as_deref(), Some(probe.service));
+                    assert_eq!(result.data, probe.payload);
+                }
+                None => {
+                    assert_eq!(result.service, None);
+                    assert_eq!(result.data, b"custom payload");
+                }
+            }
+        }
+    }
+
+    setup::run_test(test_fn);
+}